@@ -0,0 +1,330 @@
+// There is no sound output yet. Only the parts of the apu that the cpu can observe are
+// emulated: the length counters (through $4015), the frame counter and its IRQ, and the
+// DMC's sample fetches and IRQ. The cpu timing tests need them: instr_timing times each
+// instruction against the length counter, and 04-dummy_reads_apu looks for dummy reads of
+// $4015 through the frame IRQ flag.
+// See https://wiki.nesdev.com/w/index.php/APU_Frame_Counter
+// and https://wiki.nesdev.com/w/index.php/APU_DMC
+
+use region::Region;
+
+static LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// Frame counter steps 2, 4 and 5, which clock the length counters, in cpu cycles since the
+// sequence was reset. The quarter frame steps only clock envelopes, which are not emulated.
+// The Dendy has the NTSC apu.
+const NTSC_STEPS: (u32, u32, u32) = (14913, 29829, 37281);
+const PAL_STEPS: (u32, u32, u32) = (16627, 33253, 41565);
+
+struct LengthCounter {
+    enabled: bool,
+    halt: bool,
+    count: u8,
+}
+
+impl LengthCounter {
+    fn new() -> LengthCounter {
+        LengthCounter {
+            enabled: false,
+            halt: false,
+            count: 0,
+        }
+    }
+
+    fn load(&mut self, val: u8) {
+        if self.enabled {
+            self.count = LENGTH_TABLE[(val >> 3) as usize];
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.count = 0;
+        }
+    }
+
+    fn clock(&mut self) {
+        if !self.halt && self.count > 0 {
+            self.count -= 1;
+        }
+    }
+}
+
+// Cpu cycles between DMC output bits
+static NTSC_DMC_RATES: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+static PAL_DMC_RATES: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+struct Dmc {
+    irq_enabled: bool,
+    looping: bool,
+    rate: u16,
+    sample_addr: u16,
+    sample_len: u16,
+
+    // Memory reader
+    addr: u16,
+    bytes_remaining: u16,
+    buffer: Option<u8>,
+
+    // Output unit. Only its timing is emulated, since that decides when the buffer is emptied.
+    timer: u16,
+    bits_remaining: u8,
+
+    irq: bool,
+}
+
+impl Dmc {
+    fn new(rates: &'static [u16; 16]) -> Dmc {
+        Dmc {
+            irq_enabled: false,
+            looping: false,
+            rate: rates[0],
+            sample_addr: 0xC000,
+            sample_len: 1,
+
+            addr: 0xC000,
+            bytes_remaining: 0,
+            buffer: None,
+
+            timer: rates[0],
+            bits_remaining: 8,
+
+            irq: false,
+        }
+    }
+
+    fn restart(&mut self) {
+        self.addr = self.sample_addr;
+        self.bytes_remaining = self.sample_len;
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn fill(&mut self, val: u8) {
+        self.buffer = Some(val);
+        self.addr = if self.addr == 0xFFFF { 0x8000 } else { self.addr + 1 };
+        self.bytes_remaining -= 1;
+
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    fn clock(&mut self) {
+        self.timer -= 1;
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.rate;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            // The next output cycle starts by emptying the buffer into the shift register
+            self.bits_remaining = 8;
+            self.buffer = None;
+        }
+    }
+}
+
+pub struct Apu {
+    pulse1: LengthCounter,
+    pulse2: LengthCounter,
+    triangle: LengthCounter,
+    noise: LengthCounter,
+    dmc: Dmc,
+    dmc_rates: &'static [u16; 16],
+    steps: (u32, u32, u32),
+
+    five_step: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+
+    frame_cycle: u32,
+    odd_cycle: bool,
+    // Writes to $4017 take effect after a delay of 3 or 4 cycles
+    reset_delay: u8,
+}
+
+impl Apu {
+    pub fn new(region: Region) -> Apu {
+        let (dmc_rates, steps) = match region {
+            Region::Pal => (&PAL_DMC_RATES, PAL_STEPS),
+            Region::Ntsc | Region::Dendy => (&NTSC_DMC_RATES, NTSC_STEPS),
+        };
+
+        Apu {
+            pulse1: LengthCounter::new(),
+            pulse2: LengthCounter::new(),
+            triangle: LengthCounter::new(),
+            noise: LengthCounter::new(),
+            dmc: Dmc::new(dmc_rates),
+            dmc_rates: dmc_rates,
+            steps: steps,
+
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: false,
+
+            frame_cycle: 0,
+            odd_cycle: false,
+            reset_delay: 0,
+        }
+    }
+
+    pub fn read_status(&mut self) -> u8 {
+        let val = ((self.dmc.irq as u8)<<7)
+            + ((self.frame_irq as u8)<<6)
+            + (((self.dmc.bytes_remaining > 0) as u8)<<4)
+            + (((self.noise.count > 0) as u8)<<3)
+            + (((self.triangle.count > 0) as u8)<<2)
+            + (((self.pulse2.count > 0) as u8)<<1)
+            + ((self.pulse1.count > 0) as u8);
+
+        self.frame_irq = false;
+        val
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x4000 => self.pulse1.halt = val&0b00100000 > 0,
+            0x4003 => self.pulse1.load(val),
+            0x4004 => self.pulse2.halt = val&0b00100000 > 0,
+            0x4007 => self.pulse2.load(val),
+            0x4008 => self.triangle.halt = val&0b10000000 > 0,
+            0x400B => self.triangle.load(val),
+            0x400C => self.noise.halt = val&0b00100000 > 0,
+            0x400F => self.noise.load(val),
+            0x4010 => {
+                self.dmc.irq_enabled = val&0b10000000 > 0;
+                self.dmc.looping = val&0b01000000 > 0;
+                self.dmc.rate = self.dmc_rates[(val&0b00001111) as usize];
+                if !self.dmc.irq_enabled {
+                    self.dmc.irq = false;
+                }
+            },
+            0x4012 => self.dmc.sample_addr = 0xC000 + (val as u16)*64,
+            0x4013 => self.dmc.sample_len = (val as u16)*16 + 1,
+            0x4015 => {
+                self.pulse1.set_enabled(val&0b00000001 > 0);
+                self.pulse2.set_enabled(val&0b00000010 > 0);
+                self.triangle.set_enabled(val&0b00000100 > 0);
+                self.noise.set_enabled(val&0b00001000 > 0);
+                self.dmc.set_enabled(val&0b00010000 > 0);
+            },
+            0x4017 => {
+                self.five_step = val&0b10000000 > 0;
+                self.irq_inhibit = val&0b01000000 > 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+                self.reset_delay = if self.odd_cycle { 4 } else { 3 };
+            },
+            _ => () // Sound generation is not emulated
+        }
+    }
+
+    // Reset silences every channel, and restarts the frame counter as if $4017 was written
+    // again with its last value
+    pub fn reset(&mut self) {
+        self.write(0x4015, 0);
+        let last = ((self.five_step as u8)<<7) + ((self.irq_inhibit as u8)<<6);
+        self.write(0x4017, last);
+    }
+
+    pub fn frame_irq(&self) -> bool {
+        self.frame_irq
+    }
+
+    pub fn dmc_irq(&self) -> bool {
+        self.dmc.irq
+    }
+
+    // The address of the next sample byte, once the DMC needs it
+    pub fn dmc_request(&self) -> Option<u16> {
+        if self.dmc.buffer.is_none() && self.dmc.bytes_remaining > 0 {
+            Some(self.dmc.addr)
+        } else {
+            None
+        }
+    }
+
+    pub fn dmc_fill(&mut self, val: u8) {
+        self.dmc.fill(val);
+    }
+
+    pub fn tick(&mut self) {
+        self.odd_cycle = !self.odd_cycle;
+        self.dmc.clock();
+
+        if self.reset_delay > 0 {
+            self.reset_delay -= 1;
+            if self.reset_delay == 0 {
+                self.frame_cycle = 0;
+                if self.five_step {
+                    self.clock_length_counters();
+                }
+                return;
+            }
+        }
+
+        self.frame_cycle += 1;
+
+        let (step_2, step_4, step_5) = self.steps;
+        if self.five_step {
+            match self.frame_cycle {
+                c if c == step_2 || c == step_5 => self.clock_length_counters(),
+                c if c > step_5 => self.frame_cycle = 0,
+                _ => ()
+            }
+        } else {
+            // The IRQ flag is set for three cycles in a row, and the last one is also the
+            // first cycle of the next sequence
+            match self.frame_cycle {
+                c if c == step_2 => self.clock_length_counters(),
+                c if c == step_4 - 1 => self.set_frame_irq(),
+                c if c == step_4 => {
+                    self.set_frame_irq();
+                    self.clock_length_counters();
+                },
+                c if c > step_4 => {
+                    self.set_frame_irq();
+                    self.frame_cycle = 0;
+                },
+                _ => ()
+            }
+        }
+    }
+
+    fn set_frame_irq(&mut self) {
+        if !self.irq_inhibit {
+            self.frame_irq = true;
+        }
+    }
+
+    fn clock_length_counters(&mut self) {
+        self.pulse1.clock();
+        self.pulse2.clock();
+        self.triangle.clock();
+        self.noise.clock();
+    }
+}
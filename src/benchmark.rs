@@ -0,0 +1,45 @@
+extern crate nes_emulator;
+
+use std::env;
+use std::process;
+use std::time::Instant;
+use nes_emulator::ines::*;
+use nes_emulator::nes::*;
+use nes_emulator::region::Region;
+
+// Usage: benchmark [rom] [frames]
+// Runs the rom without a window or hacks as fast as possible. The default is a homebrew game
+// from the repo, so that numbers can be compared between checkouts; smb.nes is not included.
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let path = args.get(1).map_or("tests/c_playground/SPACY.nes", |s| s);
+    let frames: u32 = args.get(2).map_or(3600, |s| s.parse().expect("Invalid frame count"));
+
+    let (flags, prg, chr) = match load_file(path) {
+        Ok(rom) => rom,
+        Err(e) => {
+            println!("Could not load {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+    let mut nes = match Nes::new(prg, chr, flags.mapper, flags.prg_ram_size, flags.mirroring) {
+        Ok(nes) => nes,
+        Err(e) => {
+            println!("Could not start {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+    nes.set_region(flags.region.unwrap_or(Region::Ntsc));
+
+    let start = Instant::now();
+    for _ in 0..frames {
+        if let Err(e) = nes.tick() {
+            println!("{}", e);
+        }
+    }
+    let elapsed = start.elapsed();
+    let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+
+    println!("{} frames in {:.2}s, {:.0} frames per second ({:.1}x real time)", frames,
+             seconds, frames as f64 / seconds, frames as f64 / seconds / 60.);
+}
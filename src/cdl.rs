@@ -0,0 +1,169 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use cpu::*;
+use nes::*;
+use memory::Mapper;
+use disasm::{disassemble, Mode};
+
+// Code/Data Logger, in the .cdl format of FCEUX. There is one byte of flags for each byte of
+// PRG rom, followed by one for each byte of CHR rom.
+// See http://www.fceux.com/web/help/fceux.html?CodeDataLogger.html
+
+// PRG flags. Bits 2 and 3 hold the cpu bank ($8000, $A000, $C000 or $E000) the byte was
+// mapped into when it was last accessed.
+pub const CODE: u8 = 0x01;
+pub const DATA: u8 = 0x02;
+pub const INDIRECT_CODE: u8 = 0x10;
+pub const INDIRECT_DATA: u8 = 0x20;
+pub const PCM: u8 = 0x40;
+
+// CHR flags
+pub const RENDERED: u8 = 0x01;
+pub const READ: u8 = 0x02;
+
+pub struct Cdl {
+    pub prg: Vec<u8>,
+    pub chr: Vec<u8>,
+}
+
+impl Cdl {
+    pub fn new(prg_size: usize, chr_size: usize) -> Cdl {
+        Cdl {
+            prg: vec![0; prg_size],
+            chr: vec![0; chr_size],
+        }
+    }
+
+    pub fn load(filename: &str, prg_size: usize, chr_size: usize) -> io::Result<Cdl> {
+        let mut data = vec![];
+        File::open(filename)?.read_to_end(&mut data)?;
+        if data.len() != prg_size + chr_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("{} is not a log for this rom", filename)));
+        }
+
+        let chr = data.split_off(prg_size);
+        Ok(Cdl {
+            prg: data,
+            chr: chr,
+        })
+    }
+
+    // Starts from the log in filename if there is one, so that sessions add up
+    pub fn load_or_new(filename: &str, prg_size: usize, chr_size: usize) -> Cdl {
+        match Cdl::load(filename, prg_size, chr_size) {
+            Ok(cdl) => cdl,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Cdl::new(prg_size, chr_size),
+            Err(e) => panic!("Could not load {}: {}", filename, e)
+        }
+    }
+
+    pub fn merge(&mut self, other: &Cdl) {
+        for (a, b) in self.prg.iter_mut().zip(other.prg.iter()) {
+            *a |= *b;
+        }
+        for (a, b) in self.chr.iter_mut().zip(other.chr.iter()) {
+            *a |= *b;
+        }
+    }
+
+    pub fn save(&self, filename: &str) -> io::Result<()> {
+        let mut file = File::create(filename)?;
+        file.write_all(&self.prg)?;
+        file.write_all(&self.chr)
+    }
+
+    pub fn log_prg(&mut self, mapper: &Box<Mapper>, addr: u16, flags: u8) {
+        if let Some(offset) = mapper.prg_rom_offset(addr) {
+            let bank = (((addr>>13)&0b11) as u8)<<2;
+            self.prg[offset] = (self.prg[offset] & !0b1100) | flags | bank;
+        }
+    }
+}
+
+fn is_store(mnemonic: &str) -> bool {
+    ["STA", "STX", "STY", "SAX", "SHY", "SHX", "AHX", "TAS"].contains(&mnemonic)
+}
+
+// Called before every instruction while logging, and returns its opcode. FCEUX only logs what
+// the instruction is meant to access, so dummy reads are not logged.
+pub fn log_instruction(cpu: &Cpu, mem: &mut Chipset) -> u8 {
+    let instruction = disassemble(mem, cpu.pc);
+    let target = instruction.effective_addr(cpu, mem);
+
+    let mut data = vec![];
+    match (instruction.mode, target) {
+        (Mode::IndirectX, Some(addr)) | (Mode::IndirectY, Some(addr)) => {
+            data.push((addr, DATA | INDIRECT_DATA));
+        },
+        (Mode::Indirect, _) => {
+            // The pointer of JMP ($xxxx), which wraps within its page
+            let ptr = instruction.bytes[1] as u16 + ((instruction.bytes[2] as u16)<<8);
+            data.push((ptr, DATA));
+            data.push(((ptr&0xFF00) | (ptr.wrapping_add(1)&0x00FF), DATA));
+        },
+        (_, Some(addr)) => data.push((addr, DATA)),
+        _ => ()
+    }
+    if is_store(instruction.mnemonic) {
+        data.clear();
+    }
+
+    if let Some(ref mut cdl) = mem.cdl {
+        for i in 0..instruction.len() {
+            cdl.log_prg(&mem.mapper, cpu.pc.wrapping_add(i), CODE);
+        }
+        for &(addr, flags) in data.iter() {
+            cdl.log_prg(&mem.mapper, addr, flags);
+        }
+    }
+    instruction.bytes[0]
+}
+
+// Called after the instruction op has run
+pub fn log_jump(op: u8, cpu: &Cpu, mem: &mut Chipset) {
+    let nmi_vector = mem.peek(0xFFFA) as u16 + ((mem.peek(0xFFFB) as u16)<<8);
+    if let Some(ref mut cdl) = mem.cdl {
+        if op == 0x6C {
+            cdl.log_prg(&mem.mapper, cpu.pc, INDIRECT_CODE);
+        }
+
+        if cpu.interrupted || op == 0x00 {
+            let vector = if cpu.pc == nmi_vector { 0xFFFA } else { 0xFFFE };
+            cdl.log_prg(&mem.mapper, vector, DATA);
+            cdl.log_prg(&mem.mapper, vector + 1, DATA);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nes::*;
+    use super::*;
+
+    #[test]
+    fn logs_code_data_and_indirect_jumps() {
+        let mut program = vec![0; 0x23];
+        program[..6].copy_from_slice(&[
+            0xAD, 0x10, 0x80, // LDA $8010
+            0x6C, 0x12, 0x80, // JMP ($8012)
+        ]);
+        program[0x12] = 0x20; // -> $8020
+        program[0x13] = 0x80;
+        program[0x20..0x23].copy_from_slice(&[0x4C, 0x20, 0x80]); // JMP $8020
+
+        let mut nes = nes_with_program(&program);
+        nes.chipset.start_cdl(Cdl::new(16*1024, 8*1024));
+        for _ in 0..3 {
+            nes.step().unwrap();
+        }
+
+        let prg = &nes.chipset.cdl.as_ref().unwrap().prg;
+        assert_eq!(&prg[0..6], &[CODE; 6]);
+        assert_eq!(prg[0x10], DATA);
+        assert_eq!(&prg[0x12..0x14], &[DATA; 2]);
+        assert_eq!(&prg[0x20..0x23], &[CODE | INDIRECT_CODE, CODE, CODE]);
+        assert_eq!(prg.iter().filter(|&&flags| flags != 0).count(), 12);
+    }
+}
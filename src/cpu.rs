@@ -1,5 +1,4 @@
-use nes::Chipset;
-use phf::Map;
+use disasm::Mode;
 use std::fmt;
 
 enum AddressModeResult {
@@ -24,218 +23,443 @@ impl fmt::Debug for AddressModeResult {
 }
 
 impl AddressModeResult {
-    fn read(&self, cpu: &Cpu, mem: &mut Chipset) -> u8 {
+    fn read<B: Bus>(&self, cpu: &mut Cpu, mem: &mut B) -> u8 {
         match *self {
             Val(val) => val,
-            Addr(addr) => mem.read(addr),
+            Addr(addr) => read(cpu, mem, addr),
             Accumulator => cpu.a,
             X => cpu.x,
             Y => cpu.y,
         }
     }
 
-    fn write(&self, cpu: &mut Cpu, mem: &mut Chipset, val: u8) {
+    fn write<B: Bus>(&self, cpu: &mut Cpu, mem: &mut B, val: u8) {
         match *self {
-            Addr(addr) => mem.write(addr, val),
+            Addr(addr) => write(cpu, mem, addr, val),
             Accumulator => cpu.a = val,
             X => cpu.x = val,
             Y => cpu.y = val,
             _ => panic!("Attempt to write to a read-only AddressModeResult: {:?}", self)
         }
     }
+
+    // Read-modify-write instructions write the unmodified value back before the result
+    fn modify<B: Bus>(&self, cpu: &mut Cpu, mem: &mut B, old: u8, val: u8) {
+        if let Addr(addr) = *self {
+            write(cpu, mem, addr, old);
+        }
+        self.write(cpu, mem, val);
+    }
+}
+
+// Sources that can hold the (level triggered) IRQ line low. The line stays asserted until
+// every source that raised it has been acknowledged.
+pub const IRQ_MAPPER: u8 = 0b00000001;
+pub const IRQ_APU_FRAME: u8 = 0b00000010;
+pub const IRQ_DMC: u8 = 0b00000100;
+
+// Everything the cpu is connected to. Chipset is the NES memory map, and RamBus is plain
+// memory for running the cpu on its own.
+pub trait Bus {
+    fn read(&mut self, addr: u16) -> u8;
+
+    fn write(&mut self, addr: u16, val: u8);
+
+    // Reads without side effects, for debugging output
+    fn peek(&mut self, addr: u16) -> u8;
+
+    // Called by the cpu once per cycle, before it accesses the bus
+    fn tick(&mut self, _cpu: &mut Cpu) {
+    }
+
+    // A DMA transfer that is waiting to halt the cpu. The cpu can only be halted when it is
+    // about to read, so this is checked before every read.
+    fn dma(&mut self) -> Option<Dma> {
+        None
+    }
+
+    // The byte fetched by Dma::Dmc
+    fn dmc_sample(&mut self, _val: u8) {
+    }
+
+    // The ppu's scanline and dot, for traces
+    fn ppu_position(&self) -> (u32, u32) {
+        (0, 0)
+    }
+}
+
+// See https://wiki.nesdev.com/w/index.php/DMA
+pub enum Dma {
+    // Copies a page of memory to $2004
+    Oam(u8),
+    // Fetches the next DMC sample byte from an address
+    Dmc(u16),
+}
+
+// The operations, which execute runs with the addressing mode from the table
+#[derive(Clone, Copy)]
+enum Op {
+    Adc,
+    Ahx,
+    Alr,
+    Anc,
+    And,
+    Arr,
+    Asl,
+    Axs,
+    Bcc,
+    Bcs,
+    Beq,
+    Bit,
+    Bmi,
+    Bne,
+    Bpl,
+    Brk,
+    Bvc,
+    Bvs,
+    Clc,
+    Cld,
+    Cli,
+    Clv,
+    Cmp,
+    Cpx,
+    Cpy,
+    Dcp,
+    Dec,
+    Dex,
+    Dey,
+    Eor,
+    Inc,
+    Inx,
+    Iny,
+    Isb,
+    Jmp,
+    Jsr,
+    Kil,
+    Las,
+    Lax,
+    Lda,
+    Ldx,
+    Ldy,
+    Lsr,
+    Lxa,
+    Nop,
+    Ora,
+    Pha,
+    Php,
+    Pla,
+    Plp,
+    Rla,
+    Rol,
+    Ror,
+    Rra,
+    Rti,
+    Rts,
+    Sax,
+    Sbc,
+    Sec,
+    Sed,
+    Sei,
+    Shx,
+    Shy,
+    Slo,
+    Sre,
+    Sta,
+    Stx,
+    Sty,
+    Tas,
+    Tax,
+    Tay,
+    Tsx,
+    Txa,
+    Txs,
+    Tya,
+    Xaa,
 }
 
-type AddressMode = fn(&mut Cpu, &mut Chipset, bool) -> AddressModeResult;
-type ALUOperation = fn(&mut Cpu, &mut Chipset, AddressMode) -> ();
-
-const OPCODES: Map<u8, (ALUOperation, AddressMode)> = phf_map!{
-    // ADC
-    0x69u8 => (adc, immediate),
-    0x65u8 => (adc, zero_page),
-    0x75u8 => (adc, zero_page_x),
-    0x6Du8 => (adc, absolute),
-    0x7Du8 => (adc, absolute_x),
-    0x79u8 => (adc, absolute_y),
-    0x61u8 => (adc, indirect_x),
-    0x71u8 => (adc, indirect_y),
-
-    // SBC
-    0xE9u8 => (sbc, immediate),
-    0xE5u8 => (sbc, zero_page),
-    0xF5u8 => (sbc, zero_page_x),
-    0xEDu8 => (sbc, absolute),
-    0xFDu8 => (sbc, absolute_x),
-    0xF9u8 => (sbc, absolute_y),
-    0xE1u8 => (sbc, indirect_x),
-    0xF1u8 => (sbc, indirect_y),
-
-    //AND
-    0x29u8 => (and, immediate),
-    0x25u8 => (and, zero_page),
-    0x35u8 => (and, zero_page_x),
-    0x2Du8 => (and, absolute),
-    0x3Du8 => (and, absolute_x),
-    0x39u8 => (and, absolute_y),
-    0x21u8 => (and, indirect_x),
-    0x31u8 => (and, indirect_y),
-
-    //ORA
-    0x09u8 => (ora, immediate),
-    0x05u8 => (ora, zero_page),
-    0x15u8 => (ora, zero_page_x),
-    0x0Du8 => (ora, absolute),
-    0x1Du8 => (ora, absolute_x),
-    0x19u8 => (ora, absolute_y),
-    0x01u8 => (ora, indirect_x),
-    0x11u8 => (ora, indirect_y),
-
-    //EOR (XOR)
-    0x49u8 => (eor, immediate),
-    0x45u8 => (eor, zero_page),
-    0x55u8 => (eor, zero_page_x),
-    0x4Du8 => (eor, absolute),
-    0x5Du8 => (eor, absolute_x),
-    0x59u8 => (eor, absolute_y),
-    0x41u8 => (eor, indirect_x),
-    0x51u8 => (eor, indirect_y),
-
-    //ASL
-    0x0Au8 => (asl, implied_a),
-    0x06u8 => (asl, zero_page),
-    0x16u8 => (asl, zero_page_x),
-    0x0Eu8 => (asl, absolute),
-    0x1Eu8 => (asl, absolute_x),
-
-    //LSR
-    0x4Au8 => (lsr, implied_a),
-    0x46u8 => (lsr, zero_page),
-    0x56u8 => (lsr, zero_page_x),
-    0x4Eu8 => (lsr, absolute),
-    0x5Eu8 => (lsr, absolute_x),
-
-    //ROL
-    0x2Au8 => (rol, implied_a),
-    0x26u8 => (rol, zero_page),
-    0x36u8 => (rol, zero_page_x),
-    0x2Eu8 => (rol, absolute),
-    0x3Eu8 => (rol, absolute_x),
-
-    //ROR
-    0x6Au8 => (ror, implied_a),
-    0x66u8 => (ror, zero_page),
-    0x76u8 => (ror, zero_page_x),
-    0x6Eu8 => (ror, absolute),
-    0x7Eu8 => (ror, absolute_x),
-
-    //BIT
-    0x24u8 => (bit, zero_page),
-    0x2Cu8 => (bit, absolute),
-
-    // CMP
-    0xC9u8 => (cmp, immediate),
-    0xC5u8 => (cmp, zero_page),
-    0xD5u8 => (cmp, zero_page_x),
-    0xCDu8 => (cmp, absolute),
-    0xDDu8 => (cmp, absolute_x),
-    0xD9u8 => (cmp, absolute_y),
-    0xC1u8 => (cmp, indirect_x),
-    0xD1u8 => (cmp, indirect_y),
-
-    // CPX
-    0xE0u8 => (cpx, immediate),
-    0xE4u8 => (cpx, zero_page),
-    0xECu8 => (cpx, absolute),
-
-    // CPY
-    0xC0u8 => (cpy, immediate),
-    0xC4u8 => (cpy, zero_page),
-    0xCCu8 => (cpy, absolute),
-
-    // INC
-    0xE6u8 => (inc, zero_page),
-    0xF6u8 => (inc, zero_page_x),
-    0xEEu8 => (inc, absolute),
-    0xFEu8 => (inc, absolute_x),
-
-    // DEC
-    0xC6u8 => (dec, zero_page),
-    0xD6u8 => (dec, zero_page_x),
-    0xCEu8 => (dec, absolute),
-    0xDEu8 => (dec, absolute_x),
-
-    // INX
-    0xE8u8 => (inc, implied_x),
-    // INY
-    0xC8u8 => (inc, implied_y),
-    // DEX
-    0xCAu8 => (dec, implied_x),
-    // DEY
-    0x88u8 => (dec, implied_y),
-
-    // Branches
-    0x10u8 => (bpl, relative),
-    0x30u8 => (bmi, relative),
-    0x50u8 => (bvc, relative),
-    0x70u8 => (bvs, relative),
-    0x90u8 => (bcc, relative),
-    0xB0u8 => (bcs, relative),
-    0xD0u8 => (bne, relative),
-    0xF0u8 => (beq, relative),
-
-    // LDA
-    0xA9u8 => (lda, immediate),
-    0xA5u8 => (lda, zero_page),
-    0xB5u8 => (lda, zero_page_x),
-    0xADu8 => (lda, absolute),
-    0xBDu8 => (lda, absolute_x),
-    0xB9u8 => (lda, absolute_y),
-    0xA1u8 => (lda, indirect_x),
-    0xB1u8 => (lda, indirect_y),
-
-    //LDX
-    0xA2u8 => (ldx, immediate),
-    0xA6u8 => (ldx, zero_page),
-    0xB6u8 => (ldx, zero_page_y),
-    0xAEu8 => (ldx, absolute),
-    0xBEu8 => (ldx, absolute_y),
-
-    //LDY
-    0xA0u8 => (ldy, immediate),
-    0xA4u8 => (ldy, zero_page),
-    0xB4u8 => (ldy, zero_page_x),
-    0xACu8 => (ldy, absolute),
-    0xBCu8 => (ldy, absolute_x),
-
-    //STA
-    0x85u8 => (sta, zero_page),
-    0x95u8 => (sta, zero_page_x),
-    0x8Du8 => (sta, absolute),
-    0x9Du8 => (sta, absolute_x),
-    0x99u8 => (sta, absolute_y),
-    0x81u8 => (sta, indirect_x),
-    0x91u8 => (sta, indirect_y),
-
-    //STX
-    0x86u8 => (stx, zero_page),
-    0x96u8 => (stx, zero_page_y),
-    0x8Eu8 => (stx, absolute),
-
-    //STY
-    0x84u8 => (sty, zero_page),
-    0x94u8 => (sty, zero_page_x),
-    0x8Cu8 => (sty, absolute),
-
-    //JSR
-    0x20u8 => (jsr, absolute),
-};
+// Indexed by opcode. Unofficial opcodes are included, see
+// http://wiki.nesdev.com/w/index.php/CPU_unofficial_opcodes
+static OPCODES: [(&'static str, Op, Mode); 256] = [
+    ("BRK", Op::Brk, Mode::Implied),        // 00
+    ("ORA", Op::Ora, Mode::IndirectX),      // 01
+    ("KIL", Op::Kil, Mode::Implied),        // 02
+    ("SLO", Op::Slo, Mode::IndirectX),      // 03
+    ("NOP", Op::Nop, Mode::ZeroPage),       // 04
+    ("ORA", Op::Ora, Mode::ZeroPage),       // 05
+    ("ASL", Op::Asl, Mode::ZeroPage),       // 06
+    ("SLO", Op::Slo, Mode::ZeroPage),       // 07
+    ("PHP", Op::Php, Mode::Implied),        // 08
+    ("ORA", Op::Ora, Mode::Immediate),      // 09
+    ("ASL", Op::Asl, Mode::Accumulator),    // 0A
+    ("ANC", Op::Anc, Mode::Immediate),      // 0B
+    ("NOP", Op::Nop, Mode::Absolute),       // 0C
+    ("ORA", Op::Ora, Mode::Absolute),       // 0D
+    ("ASL", Op::Asl, Mode::Absolute),       // 0E
+    ("SLO", Op::Slo, Mode::Absolute),       // 0F
+
+    ("BPL", Op::Bpl, Mode::Relative),       // 10
+    ("ORA", Op::Ora, Mode::IndirectY),      // 11
+    ("KIL", Op::Kil, Mode::Implied),        // 12
+    ("SLO", Op::Slo, Mode::IndirectY),      // 13
+    ("NOP", Op::Nop, Mode::ZeroPageX),      // 14
+    ("ORA", Op::Ora, Mode::ZeroPageX),      // 15
+    ("ASL", Op::Asl, Mode::ZeroPageX),      // 16
+    ("SLO", Op::Slo, Mode::ZeroPageX),      // 17
+    ("CLC", Op::Clc, Mode::Implied),        // 18
+    ("ORA", Op::Ora, Mode::AbsoluteY),      // 19
+    ("NOP", Op::Nop, Mode::Implied),        // 1A
+    ("SLO", Op::Slo, Mode::AbsoluteY),      // 1B
+    ("NOP", Op::Nop, Mode::AbsoluteX),      // 1C
+    ("ORA", Op::Ora, Mode::AbsoluteX),      // 1D
+    ("ASL", Op::Asl, Mode::AbsoluteX),      // 1E
+    ("SLO", Op::Slo, Mode::AbsoluteX),      // 1F
+
+    ("JSR", Op::Jsr, Mode::Absolute),       // 20
+    ("AND", Op::And, Mode::IndirectX),      // 21
+    ("KIL", Op::Kil, Mode::Implied),        // 22
+    ("RLA", Op::Rla, Mode::IndirectX),      // 23
+    ("BIT", Op::Bit, Mode::ZeroPage),       // 24
+    ("AND", Op::And, Mode::ZeroPage),       // 25
+    ("ROL", Op::Rol, Mode::ZeroPage),       // 26
+    ("RLA", Op::Rla, Mode::ZeroPage),       // 27
+    ("PLP", Op::Plp, Mode::Implied),        // 28
+    ("AND", Op::And, Mode::Immediate),      // 29
+    ("ROL", Op::Rol, Mode::Accumulator),    // 2A
+    ("ANC", Op::Anc, Mode::Immediate),      // 2B
+    ("BIT", Op::Bit, Mode::Absolute),       // 2C
+    ("AND", Op::And, Mode::Absolute),       // 2D
+    ("ROL", Op::Rol, Mode::Absolute),       // 2E
+    ("RLA", Op::Rla, Mode::Absolute),       // 2F
+
+    ("BMI", Op::Bmi, Mode::Relative),       // 30
+    ("AND", Op::And, Mode::IndirectY),      // 31
+    ("KIL", Op::Kil, Mode::Implied),        // 32
+    ("RLA", Op::Rla, Mode::IndirectY),      // 33
+    ("NOP", Op::Nop, Mode::ZeroPageX),      // 34
+    ("AND", Op::And, Mode::ZeroPageX),      // 35
+    ("ROL", Op::Rol, Mode::ZeroPageX),      // 36
+    ("RLA", Op::Rla, Mode::ZeroPageX),      // 37
+    ("SEC", Op::Sec, Mode::Implied),        // 38
+    ("AND", Op::And, Mode::AbsoluteY),      // 39
+    ("NOP", Op::Nop, Mode::Implied),        // 3A
+    ("RLA", Op::Rla, Mode::AbsoluteY),      // 3B
+    ("NOP", Op::Nop, Mode::AbsoluteX),      // 3C
+    ("AND", Op::And, Mode::AbsoluteX),      // 3D
+    ("ROL", Op::Rol, Mode::AbsoluteX),      // 3E
+    ("RLA", Op::Rla, Mode::AbsoluteX),      // 3F
+
+    ("RTI", Op::Rti, Mode::Implied),        // 40
+    ("EOR", Op::Eor, Mode::IndirectX),      // 41
+    ("KIL", Op::Kil, Mode::Implied),        // 42
+    ("SRE", Op::Sre, Mode::IndirectX),      // 43
+    ("NOP", Op::Nop, Mode::ZeroPage),       // 44
+    ("EOR", Op::Eor, Mode::ZeroPage),       // 45
+    ("LSR", Op::Lsr, Mode::ZeroPage),       // 46
+    ("SRE", Op::Sre, Mode::ZeroPage),       // 47
+    ("PHA", Op::Pha, Mode::Implied),        // 48
+    ("EOR", Op::Eor, Mode::Immediate),      // 49
+    ("LSR", Op::Lsr, Mode::Accumulator),    // 4A
+    ("ALR", Op::Alr, Mode::Immediate),      // 4B
+    ("JMP", Op::Jmp, Mode::Absolute),       // 4C
+    ("EOR", Op::Eor, Mode::Absolute),       // 4D
+    ("LSR", Op::Lsr, Mode::Absolute),       // 4E
+    ("SRE", Op::Sre, Mode::Absolute),       // 4F
+
+    ("BVC", Op::Bvc, Mode::Relative),       // 50
+    ("EOR", Op::Eor, Mode::IndirectY),      // 51
+    ("KIL", Op::Kil, Mode::Implied),        // 52
+    ("SRE", Op::Sre, Mode::IndirectY),      // 53
+    ("NOP", Op::Nop, Mode::ZeroPageX),      // 54
+    ("EOR", Op::Eor, Mode::ZeroPageX),      // 55
+    ("LSR", Op::Lsr, Mode::ZeroPageX),      // 56
+    ("SRE", Op::Sre, Mode::ZeroPageX),      // 57
+    ("CLI", Op::Cli, Mode::Implied),        // 58
+    ("EOR", Op::Eor, Mode::AbsoluteY),      // 59
+    ("NOP", Op::Nop, Mode::Implied),        // 5A
+    ("SRE", Op::Sre, Mode::AbsoluteY),      // 5B
+    ("NOP", Op::Nop, Mode::AbsoluteX),      // 5C
+    ("EOR", Op::Eor, Mode::AbsoluteX),      // 5D
+    ("LSR", Op::Lsr, Mode::AbsoluteX),      // 5E
+    ("SRE", Op::Sre, Mode::AbsoluteX),      // 5F
+
+    ("RTS", Op::Rts, Mode::Implied),        // 60
+    ("ADC", Op::Adc, Mode::IndirectX),      // 61
+    ("KIL", Op::Kil, Mode::Implied),        // 62
+    ("RRA", Op::Rra, Mode::IndirectX),      // 63
+    ("NOP", Op::Nop, Mode::ZeroPage),       // 64
+    ("ADC", Op::Adc, Mode::ZeroPage),       // 65
+    ("ROR", Op::Ror, Mode::ZeroPage),       // 66
+    ("RRA", Op::Rra, Mode::ZeroPage),       // 67
+    ("PLA", Op::Pla, Mode::Implied),        // 68
+    ("ADC", Op::Adc, Mode::Immediate),      // 69
+    ("ROR", Op::Ror, Mode::Accumulator),    // 6A
+    ("ARR", Op::Arr, Mode::Immediate),      // 6B
+    ("JMP", Op::Jmp, Mode::Indirect),       // 6C
+    ("ADC", Op::Adc, Mode::Absolute),       // 6D
+    ("ROR", Op::Ror, Mode::Absolute),       // 6E
+    ("RRA", Op::Rra, Mode::Absolute),       // 6F
+
+    ("BVS", Op::Bvs, Mode::Relative),       // 70
+    ("ADC", Op::Adc, Mode::IndirectY),      // 71
+    ("KIL", Op::Kil, Mode::Implied),        // 72
+    ("RRA", Op::Rra, Mode::IndirectY),      // 73
+    ("NOP", Op::Nop, Mode::ZeroPageX),      // 74
+    ("ADC", Op::Adc, Mode::ZeroPageX),      // 75
+    ("ROR", Op::Ror, Mode::ZeroPageX),      // 76
+    ("RRA", Op::Rra, Mode::ZeroPageX),      // 77
+    ("SEI", Op::Sei, Mode::Implied),        // 78
+    ("ADC", Op::Adc, Mode::AbsoluteY),      // 79
+    ("NOP", Op::Nop, Mode::Implied),        // 7A
+    ("RRA", Op::Rra, Mode::AbsoluteY),      // 7B
+    ("NOP", Op::Nop, Mode::AbsoluteX),      // 7C
+    ("ADC", Op::Adc, Mode::AbsoluteX),      // 7D
+    ("ROR", Op::Ror, Mode::AbsoluteX),      // 7E
+    ("RRA", Op::Rra, Mode::AbsoluteX),      // 7F
+
+    ("NOP", Op::Nop, Mode::Immediate),      // 80
+    ("STA", Op::Sta, Mode::IndirectX),      // 81
+    ("NOP", Op::Nop, Mode::Immediate),      // 82
+    ("SAX", Op::Sax, Mode::IndirectX),      // 83
+    ("STY", Op::Sty, Mode::ZeroPage),       // 84
+    ("STA", Op::Sta, Mode::ZeroPage),       // 85
+    ("STX", Op::Stx, Mode::ZeroPage),       // 86
+    ("SAX", Op::Sax, Mode::ZeroPage),       // 87
+    ("DEY", Op::Dey, Mode::Implied),        // 88
+    ("NOP", Op::Nop, Mode::Immediate),      // 89
+    ("TXA", Op::Txa, Mode::Implied),        // 8A
+    ("XAA", Op::Xaa, Mode::Immediate),      // 8B
+    ("STY", Op::Sty, Mode::Absolute),       // 8C
+    ("STA", Op::Sta, Mode::Absolute),       // 8D
+    ("STX", Op::Stx, Mode::Absolute),       // 8E
+    ("SAX", Op::Sax, Mode::Absolute),       // 8F
+
+    ("BCC", Op::Bcc, Mode::Relative),       // 90
+    ("STA", Op::Sta, Mode::IndirectY),      // 91
+    ("KIL", Op::Kil, Mode::Implied),        // 92
+    ("AHX", Op::Ahx, Mode::IndirectY),      // 93
+    ("STY", Op::Sty, Mode::ZeroPageX),      // 94
+    ("STA", Op::Sta, Mode::ZeroPageX),      // 95
+    ("STX", Op::Stx, Mode::ZeroPageY),      // 96
+    ("SAX", Op::Sax, Mode::ZeroPageY),      // 97
+    ("TYA", Op::Tya, Mode::Implied),        // 98
+    ("STA", Op::Sta, Mode::AbsoluteY),      // 99
+    ("TXS", Op::Txs, Mode::Implied),        // 9A
+    ("TAS", Op::Tas, Mode::AbsoluteY),      // 9B
+    ("SHY", Op::Shy, Mode::AbsoluteX),      // 9C
+    ("STA", Op::Sta, Mode::AbsoluteX),      // 9D
+    ("SHX", Op::Shx, Mode::AbsoluteY),      // 9E
+    ("AHX", Op::Ahx, Mode::AbsoluteY),      // 9F
+
+    ("LDY", Op::Ldy, Mode::Immediate),      // A0
+    ("LDA", Op::Lda, Mode::IndirectX),      // A1
+    ("LDX", Op::Ldx, Mode::Immediate),      // A2
+    ("LAX", Op::Lax, Mode::IndirectX),      // A3
+    ("LDY", Op::Ldy, Mode::ZeroPage),       // A4
+    ("LDA", Op::Lda, Mode::ZeroPage),       // A5
+    ("LDX", Op::Ldx, Mode::ZeroPage),       // A6
+    ("LAX", Op::Lax, Mode::ZeroPage),       // A7
+    ("TAY", Op::Tay, Mode::Implied),        // A8
+    ("LDA", Op::Lda, Mode::Immediate),      // A9
+    ("TAX", Op::Tax, Mode::Implied),        // AA
+    ("LXA", Op::Lxa, Mode::Immediate),      // AB
+    ("LDY", Op::Ldy, Mode::Absolute),       // AC
+    ("LDA", Op::Lda, Mode::Absolute),       // AD
+    ("LDX", Op::Ldx, Mode::Absolute),       // AE
+    ("LAX", Op::Lax, Mode::Absolute),       // AF
+
+    ("BCS", Op::Bcs, Mode::Relative),       // B0
+    ("LDA", Op::Lda, Mode::IndirectY),      // B1
+    ("KIL", Op::Kil, Mode::Implied),        // B2
+    ("LAX", Op::Lax, Mode::IndirectY),      // B3
+    ("LDY", Op::Ldy, Mode::ZeroPageX),      // B4
+    ("LDA", Op::Lda, Mode::ZeroPageX),      // B5
+    ("LDX", Op::Ldx, Mode::ZeroPageY),      // B6
+    ("LAX", Op::Lax, Mode::ZeroPageY),      // B7
+    ("CLV", Op::Clv, Mode::Implied),        // B8
+    ("LDA", Op::Lda, Mode::AbsoluteY),      // B9
+    ("TSX", Op::Tsx, Mode::Implied),        // BA
+    ("LAS", Op::Las, Mode::AbsoluteY),      // BB
+    ("LDY", Op::Ldy, Mode::AbsoluteX),      // BC
+    ("LDA", Op::Lda, Mode::AbsoluteX),      // BD
+    ("LDX", Op::Ldx, Mode::AbsoluteY),      // BE
+    ("LAX", Op::Lax, Mode::AbsoluteY),      // BF
+
+    ("CPY", Op::Cpy, Mode::Immediate),      // C0
+    ("CMP", Op::Cmp, Mode::IndirectX),      // C1
+    ("NOP", Op::Nop, Mode::Immediate),      // C2
+    ("DCP", Op::Dcp, Mode::IndirectX),      // C3
+    ("CPY", Op::Cpy, Mode::ZeroPage),       // C4
+    ("CMP", Op::Cmp, Mode::ZeroPage),       // C5
+    ("DEC", Op::Dec, Mode::ZeroPage),       // C6
+    ("DCP", Op::Dcp, Mode::ZeroPage),       // C7
+    ("INY", Op::Iny, Mode::Implied),        // C8
+    ("CMP", Op::Cmp, Mode::Immediate),      // C9
+    ("DEX", Op::Dex, Mode::Implied),        // CA
+    ("AXS", Op::Axs, Mode::Immediate),      // CB
+    ("CPY", Op::Cpy, Mode::Absolute),       // CC
+    ("CMP", Op::Cmp, Mode::Absolute),       // CD
+    ("DEC", Op::Dec, Mode::Absolute),       // CE
+    ("DCP", Op::Dcp, Mode::Absolute),       // CF
+
+    ("BNE", Op::Bne, Mode::Relative),       // D0
+    ("CMP", Op::Cmp, Mode::IndirectY),      // D1
+    ("KIL", Op::Kil, Mode::Implied),        // D2
+    ("DCP", Op::Dcp, Mode::IndirectY),      // D3
+    ("NOP", Op::Nop, Mode::ZeroPageX),      // D4
+    ("CMP", Op::Cmp, Mode::ZeroPageX),      // D5
+    ("DEC", Op::Dec, Mode::ZeroPageX),      // D6
+    ("DCP", Op::Dcp, Mode::ZeroPageX),      // D7
+    ("CLD", Op::Cld, Mode::Implied),        // D8
+    ("CMP", Op::Cmp, Mode::AbsoluteY),      // D9
+    ("NOP", Op::Nop, Mode::Implied),        // DA
+    ("DCP", Op::Dcp, Mode::AbsoluteY),      // DB
+    ("NOP", Op::Nop, Mode::AbsoluteX),      // DC
+    ("CMP", Op::Cmp, Mode::AbsoluteX),      // DD
+    ("DEC", Op::Dec, Mode::AbsoluteX),      // DE
+    ("DCP", Op::Dcp, Mode::AbsoluteX),      // DF
+
+    ("CPX", Op::Cpx, Mode::Immediate),      // E0
+    ("SBC", Op::Sbc, Mode::IndirectX),      // E1
+    ("NOP", Op::Nop, Mode::Immediate),      // E2
+    ("ISB", Op::Isb, Mode::IndirectX),      // E3
+    ("CPX", Op::Cpx, Mode::ZeroPage),       // E4
+    ("SBC", Op::Sbc, Mode::ZeroPage),       // E5
+    ("INC", Op::Inc, Mode::ZeroPage),       // E6
+    ("ISB", Op::Isb, Mode::ZeroPage),       // E7
+    ("INX", Op::Inx, Mode::Implied),        // E8
+    ("SBC", Op::Sbc, Mode::Immediate),      // E9
+    ("NOP", Op::Nop, Mode::Implied),        // EA
+    ("SBC", Op::Sbc, Mode::Immediate),      // EB
+    ("CPX", Op::Cpx, Mode::Absolute),       // EC
+    ("SBC", Op::Sbc, Mode::Absolute),       // ED
+    ("INC", Op::Inc, Mode::Absolute),       // EE
+    ("ISB", Op::Isb, Mode::Absolute),       // EF
+
+    ("BEQ", Op::Beq, Mode::Relative),       // F0
+    ("SBC", Op::Sbc, Mode::IndirectY),      // F1
+    ("KIL", Op::Kil, Mode::Implied),        // F2
+    ("ISB", Op::Isb, Mode::IndirectY),      // F3
+    ("NOP", Op::Nop, Mode::ZeroPageX),      // F4
+    ("SBC", Op::Sbc, Mode::ZeroPageX),      // F5
+    ("INC", Op::Inc, Mode::ZeroPageX),      // F6
+    ("ISB", Op::Isb, Mode::ZeroPageX),      // F7
+    ("SED", Op::Sed, Mode::Implied),        // F8
+    ("SBC", Op::Sbc, Mode::AbsoluteY),      // F9
+    ("NOP", Op::Nop, Mode::Implied),        // FA
+    ("ISB", Op::Isb, Mode::AbsoluteY),      // FB
+    ("NOP", Op::Nop, Mode::AbsoluteX),      // FC
+    ("SBC", Op::Sbc, Mode::AbsoluteX),      // FD
+    ("INC", Op::Inc, Mode::AbsoluteX),      // FE
+    ("ISB", Op::Isb, Mode::AbsoluteX),      // FF
+];
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Cpu {
-    a: u8,
-    x: u8,
-    y: u8,
-    s: u8,
-    pc: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub s: u8,
+    pub pc: u16,
     negative: bool,
     overflow: bool,
     interrupt: bool,
@@ -244,107 +468,290 @@ pub struct Cpu {
     carry: bool,
     decimal: bool,
 
-    pub count: u32,
-    pub debug: bool,
+    // Cycles since power on
+    pub total_count: u64,
     nmi_waiting: bool,
+    irq_sources: u8,
+    interrupt_poll: bool,
+    // Whether the last tick ended by entering an interrupt handler
+    pub interrupted: bool,
 }
 
-fn immediate(cpu: &mut Cpu, mem: &mut Chipset, _: bool) -> AddressModeResult {
-    cpu.pc += 1;
-    cpu.count += 2;
-    Val(mem.read(cpu.pc-1))
+// Every bus access takes exactly one cpu cycle. The rest of the system is advanced before the
+// access, so that reads and writes see the same state they would on hardware.
+fn read<B: Bus>(cpu: &mut Cpu, mem: &mut B, addr: u16) -> u8 {
+    if let Some(dma) = mem.dma() {
+        run_dma(cpu, mem, dma, addr);
+    }
+
+    cycle(cpu, mem);
+    mem.read(addr)
 }
 
-fn zero_page(cpu: &mut Cpu, mem: &mut Chipset, _: bool) -> AddressModeResult {
-    let arg = mem.read(cpu.pc);
-    cpu.pc += 1;
-    cpu.count += 3;
-    Addr(arg as u16)
+fn write<B: Bus>(cpu: &mut Cpu, mem: &mut B, addr: u16, val: u8) {
+    cycle(cpu, mem);
+    mem.write(addr, val);
 }
 
-fn zero_page_x(cpu: &mut Cpu, mem: &mut Chipset, _: bool) -> AddressModeResult {
-    let arg = mem.read(cpu.pc);
-    cpu.pc += 1;
-    cpu.count += 4;
-    Addr((arg as u16 + cpu.x as u16) % 256)
+fn cycle<B: Bus>(cpu: &mut Cpu, mem: &mut B) {
+    // Interrupts are polled at the end of the second to last cycle of an instruction, so
+    // the value from the start of the last cycle is the one that counts
+    cpu.interrupt_poll = cpu.nmi_waiting || (cpu.irq_sources != 0 && !cpu.irq_disable);
+    cpu.total_count += 1;
+    mem.tick(cpu);
 }
 
-fn zero_page_y(cpu: &mut Cpu, mem: &mut Chipset, _: bool) -> AddressModeResult {
-    let arg = mem.read(cpu.pc);
-    cpu.pc += 1;
-    cpu.count += 4;
-    Addr((arg as u16 + cpu.y as u16) % 256)
+// While the cpu is halted it keeps repeating the read it was about to do, which can have side
+// effects when it is a register
+fn halted<B: Bus>(cpu: &mut Cpu, mem: &mut B, addr: u16) {
+    cycle(cpu, mem);
+    mem.read(addr);
 }
 
-fn absolute(cpu: &mut Cpu, mem: &mut Chipset, _: bool) -> AddressModeResult {
-    cpu.pc += 2;
-    cpu.count += 4;
-    Addr(mem.read16(cpu.pc-2))
+// DMA reads happen on even cycles and writes on odd ones, so transfers may wait an extra
+// cycle to line up
+fn align<B: Bus>(cpu: &mut Cpu, mem: &mut B, addr: u16) {
+    if cpu.total_count % 2 == 1 {
+        halted(cpu, mem, addr);
+    }
 }
 
-fn absolute_x(cpu: &mut Cpu, mem: &mut Chipset, page_matters: bool) -> AddressModeResult {
-    let arg = mem.read16(cpu.pc);
-    cpu.pc += 2;
-    cpu.count += 4;
+fn dmc_fetch<B: Bus>(cpu: &mut Cpu, mem: &mut B, sample_addr: u16) {
+    cycle(cpu, mem);
+    let val = mem.read(sample_addr);
+    mem.dmc_sample(val);
+}
 
-    let addr = arg.wrapping_add(cpu.x as u16);
+// Runs a transfer in place of a read of addr. OAM DMA takes 513 or 514 cycles, and a DMC
+// fetch 3 or 4, or only 2 when it happens in the middle of OAM DMA.
+fn run_dma<B: Bus>(cpu: &mut Cpu, mem: &mut B, dma: Dma, addr: u16) {
+    halted(cpu, mem, addr);
+
+    match dma {
+        Dma::Oam(page) => {
+            align(cpu, mem, addr);
+            for i in 0..256 {
+                let src = ((page as u16)<<8) + i;
+                if let Some(Dma::Dmc(sample_addr)) = mem.dma() {
+                    dmc_fetch(cpu, mem, sample_addr);
+                    halted(cpu, mem, addr);
+                }
+
+                cycle(cpu, mem);
+                let val = mem.read(src);
+                cycle(cpu, mem);
+                mem.write(0x2004, val);
+            }
+        },
+        Dma::Dmc(sample_addr) => {
+            halted(cpu, mem, addr);
+            align(cpu, mem, addr);
+            dmc_fetch(cpu, mem, sample_addr);
+        }
+    }
+}
+
+fn fetch<B: Bus>(cpu: &mut Cpu, mem: &mut B) -> u8 {
+    let pc = cpu.pc;
+    cpu.pc = pc.wrapping_add(1);
+    read(cpu, mem, pc)
+}
+
+fn fetch16<B: Bus>(cpu: &mut Cpu, mem: &mut B) -> u16 {
+    let lo = fetch(cpu, mem);
+    let hi = fetch(cpu, mem);
+    lo as u16 + ((hi as u16)<<8)
+}
 
-    if !page_matters || addr/256u16 != arg / 256u16 {
-        cpu.count += 1;
+// Matching on both enums lets the compiler turn each into a jump table, and keeps every bus
+// access a direct call into B
+fn execute<B: Bus>(cpu: &mut Cpu, mem: &mut B, op: Op, mode: Mode) {
+    match op {
+        Op::Adc => adc(cpu, mem, mode),
+        Op::Ahx => ahx(cpu, mem, mode),
+        Op::Alr => alr(cpu, mem, mode),
+        Op::Anc => anc(cpu, mem, mode),
+        Op::And => and(cpu, mem, mode),
+        Op::Arr => arr(cpu, mem, mode),
+        Op::Asl => asl(cpu, mem, mode),
+        Op::Axs => axs(cpu, mem, mode),
+        Op::Bcc => bcc(cpu, mem, mode),
+        Op::Bcs => bcs(cpu, mem, mode),
+        Op::Beq => beq(cpu, mem, mode),
+        Op::Bit => bit(cpu, mem, mode),
+        Op::Bmi => bmi(cpu, mem, mode),
+        Op::Bne => bne(cpu, mem, mode),
+        Op::Bpl => bpl(cpu, mem, mode),
+        Op::Brk => brk(cpu, mem, mode),
+        Op::Bvc => bvc(cpu, mem, mode),
+        Op::Bvs => bvs(cpu, mem, mode),
+        Op::Clc => clc(cpu, mem, mode),
+        Op::Cld => cld(cpu, mem, mode),
+        Op::Cli => cli(cpu, mem, mode),
+        Op::Clv => clv(cpu, mem, mode),
+        Op::Cmp => cmp(cpu, mem, mode),
+        Op::Cpx => cpx(cpu, mem, mode),
+        Op::Cpy => cpy(cpu, mem, mode),
+        Op::Dcp => dcp(cpu, mem, mode),
+        Op::Dec => dec(cpu, mem, mode),
+        Op::Dex => dex(cpu, mem, mode),
+        Op::Dey => dey(cpu, mem, mode),
+        Op::Eor => eor(cpu, mem, mode),
+        Op::Inc => inc(cpu, mem, mode),
+        Op::Inx => inx(cpu, mem, mode),
+        Op::Iny => iny(cpu, mem, mode),
+        Op::Isb => isb(cpu, mem, mode),
+        Op::Jmp => jmp(cpu, mem, mode),
+        Op::Jsr => jsr(cpu, mem, mode),
+        Op::Kil => kil(cpu, mem, mode),
+        Op::Las => las(cpu, mem, mode),
+        Op::Lax => lax(cpu, mem, mode),
+        Op::Lda => lda(cpu, mem, mode),
+        Op::Ldx => ldx(cpu, mem, mode),
+        Op::Ldy => ldy(cpu, mem, mode),
+        Op::Lsr => lsr(cpu, mem, mode),
+        Op::Lxa => lxa(cpu, mem, mode),
+        Op::Nop => nop(cpu, mem, mode),
+        Op::Ora => ora(cpu, mem, mode),
+        Op::Pha => pha(cpu, mem, mode),
+        Op::Php => php(cpu, mem, mode),
+        Op::Pla => pla(cpu, mem, mode),
+        Op::Plp => plp(cpu, mem, mode),
+        Op::Rla => rla(cpu, mem, mode),
+        Op::Rol => rol(cpu, mem, mode),
+        Op::Ror => ror(cpu, mem, mode),
+        Op::Rra => rra(cpu, mem, mode),
+        Op::Rti => rti(cpu, mem, mode),
+        Op::Rts => rts(cpu, mem, mode),
+        Op::Sax => sax(cpu, mem, mode),
+        Op::Sbc => sbc(cpu, mem, mode),
+        Op::Sec => sec(cpu, mem, mode),
+        Op::Sed => sed(cpu, mem, mode),
+        Op::Sei => sei(cpu, mem, mode),
+        Op::Shx => shx(cpu, mem, mode),
+        Op::Shy => shy(cpu, mem, mode),
+        Op::Slo => slo(cpu, mem, mode),
+        Op::Sre => sre(cpu, mem, mode),
+        Op::Sta => sta(cpu, mem, mode),
+        Op::Stx => stx(cpu, mem, mode),
+        Op::Sty => sty(cpu, mem, mode),
+        Op::Tas => tas(cpu, mem, mode),
+        Op::Tax => tax(cpu, mem, mode),
+        Op::Tay => tay(cpu, mem, mode),
+        Op::Tsx => tsx(cpu, mem, mode),
+        Op::Txa => txa(cpu, mem, mode),
+        Op::Txs => txs(cpu, mem, mode),
+        Op::Tya => tya(cpu, mem, mode),
+        Op::Xaa => xaa(cpu, mem, mode),
     }
-    Addr(addr)
 }
 
-fn absolute_y(cpu: &mut Cpu, mem: &mut Chipset, page_matters: bool) -> AddressModeResult {
-    let arg = mem.read16(cpu.pc);
-    cpu.pc += 2;
-    cpu.count += 4;
+fn address<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode, page_matters: bool) -> AddressModeResult {
+    match mode {
+        Mode::Implied => implied(cpu, mem, page_matters),
+        Mode::Accumulator => implied_a(cpu, mem, page_matters),
+        Mode::Immediate => immediate(cpu, mem, page_matters),
+        Mode::ZeroPage => zero_page(cpu, mem, page_matters),
+        Mode::ZeroPageX => zero_page_x(cpu, mem, page_matters),
+        Mode::ZeroPageY => zero_page_y(cpu, mem, page_matters),
+        Mode::Absolute => absolute(cpu, mem, page_matters),
+        Mode::AbsoluteX => absolute_x(cpu, mem, page_matters),
+        Mode::AbsoluteY => absolute_y(cpu, mem, page_matters),
+        Mode::Indirect => indirect(cpu, mem, page_matters),
+        Mode::IndirectX => indirect_x(cpu, mem, page_matters),
+        Mode::IndirectY => indirect_y(cpu, mem, page_matters),
+        Mode::Relative => relative(cpu, mem, page_matters),
+    }
+}
 
-    let addr = arg.wrapping_add(cpu.y as u16);
+fn immediate<B: Bus>(cpu: &mut Cpu, mem: &mut B, _: bool) -> AddressModeResult {
+    Val(fetch(cpu, mem))
+}
+
+fn zero_page<B: Bus>(cpu: &mut Cpu, mem: &mut B, _: bool) -> AddressModeResult {
+    Addr(fetch(cpu, mem) as u16)
+}
+
+fn zero_page_x<B: Bus>(cpu: &mut Cpu, mem: &mut B, _: bool) -> AddressModeResult {
+    let arg = fetch(cpu, mem);
+    read(cpu, mem, arg as u16); // Dummy read while the index is added
+    Addr(arg.wrapping_add(cpu.x) as u16)
+}
 
-    if !page_matters || addr/256u16 != arg /256u16 {
-        cpu.count += 1;
+fn zero_page_y<B: Bus>(cpu: &mut Cpu, mem: &mut B, _: bool) -> AddressModeResult {
+    let arg = fetch(cpu, mem);
+    read(cpu, mem, arg as u16); // Dummy read while the index is added
+    Addr(arg.wrapping_add(cpu.y) as u16)
+}
+
+fn absolute<B: Bus>(cpu: &mut Cpu, mem: &mut B, _: bool) -> AddressModeResult {
+    Addr(fetch16(cpu, mem))
+}
+
+// The low byte of the address is added first, so when the index crosses a page (or the
+// instruction writes) the cpu reads from the wrong page before fixing the high byte
+fn indexed<B: Bus>(cpu: &mut Cpu, mem: &mut B, base: u16, index: u8, page_matters: bool) -> AddressModeResult {
+    let addr = base.wrapping_add(index as u16);
+
+    if !page_matters || addr/256u16 != base/256u16 {
+        read(cpu, mem, (base&0xFF00) | (addr&0x00FF));
     }
     Addr(addr)
 }
 
-fn indirect_x(cpu: &mut Cpu, mem: &mut Chipset, _: bool) -> AddressModeResult {
-    let arg = mem.read(cpu.pc);
-    cpu.pc += 1;
-    cpu.count += 6;
-    Addr(mem.read((arg as u16 + cpu.x as u16) % 256) as u16
-        + (mem.read((arg as u16 + cpu.x as u16 + 1) % 256) as u16)*256)
+fn absolute_x<B: Bus>(cpu: &mut Cpu, mem: &mut B, page_matters: bool) -> AddressModeResult {
+    let arg = fetch16(cpu, mem);
+    let x = cpu.x;
+    indexed(cpu, mem, arg, x, page_matters)
 }
 
-fn indirect_y(cpu: &mut Cpu, mem: &mut Chipset, page_matters: bool) -> AddressModeResult {
-    let arg = mem.read(cpu.pc);
-    cpu.pc += 1;
-    cpu.count += 5;
+fn absolute_y<B: Bus>(cpu: &mut Cpu, mem: &mut B, page_matters: bool) -> AddressModeResult {
+    let arg = fetch16(cpu, mem);
+    let y = cpu.y;
+    indexed(cpu, mem, arg, y, page_matters)
+}
 
-    let base = mem.read(arg as u16 % 256) as u16 + (mem.read((arg as u16 + 1) % 256) as u16)*256;
+fn indirect_x<B: Bus>(cpu: &mut Cpu, mem: &mut B, _: bool) -> AddressModeResult {
+    let arg = fetch(cpu, mem);
+    read(cpu, mem, arg as u16); // Dummy read while the index is added
+    let ptr = arg.wrapping_add(cpu.x);
+    let lo = read(cpu, mem, ptr as u16);
+    let hi = read(cpu, mem, ptr.wrapping_add(1) as u16);
+    Addr(lo as u16 + (hi as u16)*256)
+}
 
-    if !page_matters || (base + cpu.y as u16)/256u16 != base/256u16 {
-        cpu.count += 1;
-    }
+fn indirect_y<B: Bus>(cpu: &mut Cpu, mem: &mut B, page_matters: bool) -> AddressModeResult {
+    let arg = fetch(cpu, mem);
+    let lo = read(cpu, mem, arg as u16);
+    let hi = read(cpu, mem, arg.wrapping_add(1) as u16);
 
-    Addr(base + cpu.y as u16)
+    let base = lo as u16 + (hi as u16)*256;
+    let y = cpu.y;
+    indexed(cpu, mem, base, y, page_matters)
 }
 
-fn implied_a(_: &mut Cpu, _: &mut Chipset, _: bool) -> AddressModeResult {
-    Accumulator
+// Single byte instructions still read the byte after the opcode, and throw it away
+fn implied<B: Bus>(cpu: &mut Cpu, mem: &mut B, _: bool) -> AddressModeResult {
+    let pc = cpu.pc;
+    Val(read(cpu, mem, pc))
 }
 
-fn implied_x(_: &mut Cpu, _: &mut Chipset, _: bool) -> AddressModeResult {
-    X
+fn implied_a<B: Bus>(cpu: &mut Cpu, mem: &mut B, _: bool) -> AddressModeResult {
+    implied(cpu, mem, false);
+    Accumulator
 }
 
-fn implied_y(_: &mut Cpu, _: &mut Chipset, _: bool) -> AddressModeResult {
-    Y
+// Only used by JMP. The high byte is read from the same page, even if the pointer is at the
+// end of it.
+fn indirect<B: Bus>(cpu: &mut Cpu, mem: &mut B, _: bool) -> AddressModeResult {
+    let addr = fetch16(cpu, mem);
+    let lo = read(cpu, mem, addr);
+    let hi = read(cpu, mem, (addr&0xFF00) + (addr.wrapping_add(1)&0x00FF));
+    Addr(lo as u16 + ((hi as u16)<<8))
 }
 
-fn relative(cpu: &mut Cpu, mem: &mut Chipset, page_matters: bool) -> AddressModeResult {
-    let arg = mem.read(cpu.pc);
-    cpu.pc = cpu.pc + 1;
+fn relative<B: Bus>(cpu: &mut Cpu, mem: &mut B, _: bool) -> AddressModeResult {
+    let arg = fetch(cpu, mem);
 
     let rel_addr = if arg <= 127 {
         cpu.pc.wrapping_add(arg as u16)
@@ -352,15 +759,11 @@ fn relative(cpu: &mut Cpu, mem: &mut Chipset, page_matters: bool) -> AddressMode
         cpu.pc.wrapping_sub((!arg + 1) as u16)
     };
 
-    if !page_matters || cpu.pc/256u16 != rel_addr/256u16 {
-        cpu.count = cpu.count + 1;
-    }
-    cpu.count = cpu.count + 1;
     Addr(rel_addr)
 }
 
-fn adc(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
-    let val = mode(cpu, mem, true).read(cpu, mem);
+fn adc<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let val = address(cpu, mem, mode, true).read(cpu, mem);
     add_with_carry(cpu, val);
 }
 
@@ -376,96 +779,92 @@ fn add_with_carry(cpu: &mut Cpu, val: u8) {
     cpu.negative = cpu.a&0b10000000 > 0;
 }
 
-fn sbc(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
-    let val = mode(cpu, mem, true).read(cpu, mem);
+fn sbc<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let val = address(cpu, mem, mode, true).read(cpu, mem);
     // Flip the bits for 2s compliment, but rely on carry to add 1
     add_with_carry(cpu, !val);
 }
 
-fn and(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
-    let val = mode(cpu, mem, true).read(cpu, mem);
+fn and<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let val = address(cpu, mem, mode, true).read(cpu, mem);
     cpu.a = cpu.a&val;
     cpu.zero = cpu.a == 0;
     cpu.negative = cpu.a&0b10000000 > 0;
 }
 
-fn ora(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
-    let val = mode(cpu, mem, true).read(cpu, mem);
+fn ora<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let val = address(cpu, mem, mode, true).read(cpu, mem);
     cpu.a = cpu.a|val;
     cpu.zero = cpu.a == 0;
     cpu.negative = cpu.a&0b10000000 > 0;
 }
 
-fn eor(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
-    let val = mode(cpu, mem, true).read(cpu, mem);
+fn eor<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let val = address(cpu, mem, mode, true).read(cpu, mem);
     cpu.a = cpu.a^val;
     cpu.zero = cpu.a == 0;
     cpu.negative = cpu.a&0b10000000 > 0;
 }
 
-fn asl(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
-    let r = mode(cpu, mem, false);
+fn asl<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let r = address(cpu, mem, mode, false);
     let val = r.read(cpu, mem);
-    cpu.count = cpu.count + 2;
 
     cpu.carry = val&0b10000000 > 0;
     let result = val << 1;
-    r.write(cpu, mem, result);
+    r.modify(cpu, mem, val, result);
 
     cpu.zero = result == 0;
     cpu.negative = result&0b10000000 > 0;
 }
 
-fn lsr(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
-    let r = mode(cpu, mem, false);
+fn lsr<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let r = address(cpu, mem, mode, false);
     let val = r.read(cpu, mem);
-    cpu.count = cpu.count + 2;
 
     cpu.carry = val&0b00000001 > 0;
     let result = (val >> 1) & 0b011111111;
-    r.write(cpu, mem, result);
+    r.modify(cpu, mem, val, result);
 
     cpu.zero = result == 0;
     cpu.negative = result&0b10000000 > 0;
 }
 
-fn rol(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
-    let r = mode(cpu, mem, false);
+fn rol<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let r = address(cpu, mem, mode, false);
     let val = r.read(cpu, mem);
-    cpu.count = cpu.count + 2;
 
     let old_carry = if cpu.carry { 1 } else { 0 };
     cpu.carry = val&0b10000000 > 0;
     let result = (val << 1) | old_carry;
-    r.write(cpu, mem, result);
+    r.modify(cpu, mem, val, result);
 
     cpu.zero = result == 0;
     cpu.negative = result&0b10000000 > 0;
 }
 
-fn ror(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
-    let r = mode(cpu, mem, false);
+fn ror<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let r = address(cpu, mem, mode, false);
     let val = r.read(cpu, mem);
-    cpu.count = cpu.count + 2;
 
     let old_carry = if cpu.carry { 1 } else { 0 };
     cpu.carry = val&0b00000001 > 0;
     let result = (val >> 1) | old_carry<<7;
-    r.write(cpu, mem, result);
+    r.modify(cpu, mem, val, result);
 
     cpu.zero = result == 0;
     cpu.negative = result&0b10000000 > 0;
 }
 
-fn bit(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
-    let val = mode(cpu, mem, true).read(cpu, mem);
+fn bit<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let val = address(cpu, mem, mode, true).read(cpu, mem);
 
     cpu.zero = val & cpu.a == 0;
     cpu.negative = val & 0b10000000 > 0;
     cpu.overflow = val & 0b01000000 > 0;
 }
 
-fn cmp(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
+fn cmp<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
     let a = cpu.a;
     let overflow = cpu.overflow;
     cpu.carry = true;
@@ -474,7 +873,7 @@ fn cmp(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
     cpu.overflow = overflow;
 }
 
-fn cpx(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
+fn cpx<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
     let a = cpu.a;
     let overflow = cpu.overflow;
     cpu.carry = true;
@@ -484,7 +883,7 @@ fn cpx(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
     cpu.overflow = overflow;
 }
 
-fn cpy(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
+fn cpy<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
     let a = cpu.a;
     let overflow = cpu.overflow;
     cpu.carry = true;
@@ -494,258 +893,554 @@ fn cpy(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
     cpu.overflow = overflow;
 }
 
-fn inc(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
-    let r = mode(cpu, mem, false);
-    let val = r.read(cpu, mem);
-    cpu.count = cpu.count + 2;
+fn inc<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let r = address(cpu, mem, mode, false);
+    add_to(cpu, mem, r, 1);
+}
 
-    let result = ((val as u16).wrapping_add(1)&0xFF) as u8;
-    r.write(cpu, mem, result);
+fn dec<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let r = address(cpu, mem, mode, false);
+    add_to(cpu, mem, r, 0xFF);
+}
 
-    cpu.zero = result == 0;
-    cpu.negative = result&0b10000000 > 0;
+fn inx<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    address(cpu, mem, mode, false);
+    add_to(cpu, mem, X, 1);
 }
 
-fn dec(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
-    let r = mode(cpu, mem, false);
+fn iny<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    address(cpu, mem, mode, false);
+    add_to(cpu, mem, Y, 1);
+}
+
+fn dex<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    address(cpu, mem, mode, false);
+    add_to(cpu, mem, X, 0xFF);
+}
+
+fn dey<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    address(cpu, mem, mode, false);
+    add_to(cpu, mem, Y, 0xFF);
+}
+
+fn add_to<B: Bus>(cpu: &mut Cpu, mem: &mut B, r: AddressModeResult, n: u8) {
     let val = r.read(cpu, mem);
-    cpu.count = cpu.count + 2;
 
-    let result = ((val as u16).wrapping_sub(1)&0xFF) as u8;
-    r.write(cpu, mem, result);
+    let result = val.wrapping_add(n);
+    r.modify(cpu, mem, val, result);
 
     cpu.zero = result == 0;
     cpu.negative = result&0b10000000 > 0;
 }
 
-fn jump(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode, cond: bool) {
-    cpu.count += 2;
-    let count = cpu.count;
-    let val = match mode(cpu, mem, true) {
+fn jump<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode, cond: bool) {
+    let val = match address(cpu, mem, mode, true) {
         Addr(a) => a,
         _ => panic!("Jump instruction address mode must produce an address result!")
     };
 
     if !cond {
-        // We read memory to advance the pc, but do not want to advance the count
-        cpu.count = count;
         return;
     }
 
+    // Taken branches read the next opcode while adding the offset, and read from the
+    // wrong page if the high byte still needs to be fixed
+    let pc = cpu.pc;
+    read(cpu, mem, pc);
+    if pc/256u16 != val/256u16 {
+        read(cpu, mem, (pc&0xFF00) | (val&0x00FF));
+    }
+
     cpu.pc = val;
 }
 
-fn bpl(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
+fn bpl<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
     let cond = !cpu.negative;
     jump(cpu, mem, mode, cond);
 }
 
-fn bmi(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
+fn bmi<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
     let cond = cpu.negative;
     jump(cpu, mem, mode, cond);
 }
 
-fn bvc(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
+fn bvc<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
     let cond = !cpu.overflow;
     jump(cpu, mem, mode, cond);
 }
 
-fn bvs(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
+fn bvs<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
     let cond = cpu.overflow;
     jump(cpu, mem, mode, cond);
 }
 
-fn bcc(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
+fn bcc<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
     let cond = !cpu.carry;
     jump(cpu, mem, mode, cond);
 }
 
-fn bcs(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
+fn bcs<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
     let cond = cpu.carry;
     jump(cpu, mem, mode, cond);
 }
 
-fn bne(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
+fn bne<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
     let cond = !cpu.zero;
     jump(cpu, mem, mode, cond);
 }
 
-fn beq(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
+fn beq<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
     let cond = cpu.zero;
     jump(cpu, mem, mode, cond);
 }
 
-fn lda(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
-    let val = mode(cpu, mem, true).read(cpu, mem);
+fn lda<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let val = address(cpu, mem, mode, true).read(cpu, mem);
     cpu.a = val;
     cpu.zero = cpu.a == 0;
     cpu.negative = cpu.a&0b10000000 > 0;
 }
 
-fn ldx(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
-    let val = mode(cpu, mem, true).read(cpu, mem);
+fn ldx<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let val = address(cpu, mem, mode, true).read(cpu, mem);
     cpu.x = val;
     cpu.zero = cpu.x == 0;
     cpu.negative = cpu.x&0b10000000 > 0;
 }
 
-fn ldy(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
-    let val = mode(cpu, mem, true).read(cpu, mem);
+fn ldy<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let val = address(cpu, mem, mode, true).read(cpu, mem);
     cpu.y = val;
     cpu.zero = cpu.y == 0;
     cpu.negative = cpu.y&0b10000000 > 0;
 }
 
-fn manual(cpu: &mut Cpu, mem: &mut Chipset, op: u8) {
-    cpu.count += 2;
-    match op {
-        0x18 => cpu.carry = false, //CLC
-        0x38 => cpu.carry = true, //SEC
-        0x58 => cpu.irq_disable = false, //CLI
-        0x78 => cpu.irq_disable = true, //SEI
-        0xB8 => cpu.overflow = false, //CLV
-        0x1A => (), //NOP
-        0x3A => (), //NOP
-        0x5A => (), //NOP
-        0x7A => (), //NOP
-        0xDA => (), //NOP
-        0xEA => (), //NOP
-        0xFA => (), //NOP
-        0xAA => { //TAX
-            cpu.x = cpu.a;
-            cpu.zero = cpu.x == 0;
-            cpu.negative = cpu.x&0b10000000 > 0;
-        },
-        0x8A => { //TXA
-            cpu.a = cpu.x;
-            cpu.zero = cpu.a == 0;
-            cpu.negative = cpu.a&0b10000000 > 0;
-        },
-        0xA8 => { //TAY
-            cpu.y = cpu.a;
-            cpu.zero = cpu.y == 0;
-            cpu.negative = cpu.y&0b10000000 > 0;
-        },
-        0x98 => { //TYA
-            cpu.a = cpu.y;
-            cpu.zero = cpu.a == 0;
-            cpu.negative = cpu.a&0b10000000 > 0;
-        },
-        0x9A => cpu.s = cpu.x, //TXS
-        0xBA => { //TSX
-            cpu.x = cpu.s;
-            cpu.zero = cpu.x == 0;
-            cpu.negative = cpu.x&0b10000000 > 0;
-        },
-        0x48 => { //PHA
-            cpu.count += 1;
-            let a = cpu.a;
-            push(cpu, mem, a);
-        },
-        0x68 => { //PLA
-            cpu.count += 2;
-            cpu.a = pull(cpu, mem);
-            cpu.zero = cpu.a == 0;
-            cpu.negative = cpu.a&0b10000000 > 0;
-        },
-        0x08 => { //PHP
-            cpu.count += 1;
-            let interrupt = cpu.interrupt;
-            cpu.interrupt = true;
-            let p = cpu.get_p();
-            push(cpu, mem, p);
-            cpu.interrupt = interrupt;
-        },
-        0x28 => { //PLP
-            cpu.count += 2;
-            let p = pull(cpu, mem);
-            cpu.set_p(p);
-        },
-        0x00 => { //BRK
-            cpu.count += 2;
-            let pc = cpu.pc + 1;
-            push16(cpu, mem, pc);
-            manual(cpu, mem, 0x08); //PHP
-            cpu.pc = mem.read16(0xFFFE);
-            cpu.irq_disable = true;
-        },
-        0x40 => { //RTI
-            manual(cpu, mem, 0x28); //PLP
-            let pc = pull16(cpu, mem);
-            cpu.pc = pc;
-        },
-        0x60 => { //RTS
-            cpu.count += 4;
-            let pc = pull16(cpu, mem);
-            cpu.pc = pc+1;
-        },
-        0x4c => { //JMP (absolute)
-            cpu.count += 1;
-            let pc = mem.read16(cpu.pc);
-            cpu.pc = pc;
-        },
-        0x6c => { //JMP (indirect)
-            cpu.count += 3;
-            let addr = mem.read16(cpu.pc);
-            let pc = mem.read(addr) as u16
-                + ((mem.read((addr&0xFF00) + ((addr+1)&0x00FF)) as u16)<<8);
-            cpu.pc = pc;
-        },
-        0xF8 => cpu.decimal = true,
-        0xD8 => cpu.decimal = false,
-        _ => panic!("Not implemented yet! Op: {:X} at {:X}", op, cpu.pc.wrapping_sub(1))
-    }
+fn clc<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    address(cpu, mem, mode, false);
+    cpu.carry = false;
+}
+
+fn sec<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    address(cpu, mem, mode, false);
+    cpu.carry = true;
+}
+
+fn cli<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    address(cpu, mem, mode, false);
+    cpu.irq_disable = false;
+}
+
+fn sei<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    address(cpu, mem, mode, false);
+    cpu.irq_disable = true;
+}
+
+fn clv<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    address(cpu, mem, mode, false);
+    cpu.overflow = false;
+}
+
+fn cld<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    address(cpu, mem, mode, false);
+    cpu.decimal = false;
+}
+
+fn sed<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    address(cpu, mem, mode, false);
+    cpu.decimal = true;
+}
+
+fn tax<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    address(cpu, mem, mode, false);
+    cpu.x = cpu.a;
+    cpu.zero = cpu.x == 0;
+    cpu.negative = cpu.x&0b10000000 > 0;
+}
+
+fn txa<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    address(cpu, mem, mode, false);
+    cpu.a = cpu.x;
+    cpu.zero = cpu.a == 0;
+    cpu.negative = cpu.a&0b10000000 > 0;
+}
+
+fn tay<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    address(cpu, mem, mode, false);
+    cpu.y = cpu.a;
+    cpu.zero = cpu.y == 0;
+    cpu.negative = cpu.y&0b10000000 > 0;
+}
+
+fn tya<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    address(cpu, mem, mode, false);
+    cpu.a = cpu.y;
+    cpu.zero = cpu.a == 0;
+    cpu.negative = cpu.a&0b10000000 > 0;
+}
+
+fn txs<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    address(cpu, mem, mode, false);
+    cpu.s = cpu.x;
+}
+
+fn tsx<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    address(cpu, mem, mode, false);
+    cpu.x = cpu.s;
+    cpu.zero = cpu.x == 0;
+    cpu.negative = cpu.x&0b10000000 > 0;
+}
+
+fn pha<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    address(cpu, mem, mode, false);
+    let a = cpu.a;
+    push(cpu, mem, a);
+}
+
+fn pla<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    address(cpu, mem, mode, false);
+    stack_dummy_read(cpu, mem);
+    cpu.a = pull(cpu, mem);
+    cpu.zero = cpu.a == 0;
+    cpu.negative = cpu.a&0b10000000 > 0;
+}
+
+fn php<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    address(cpu, mem, mode, false);
+    let interrupt = cpu.interrupt;
+    cpu.interrupt = true;
+    let p = cpu.get_p();
+    push(cpu, mem, p);
+    cpu.interrupt = interrupt;
+}
+
+fn plp<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    address(cpu, mem, mode, false);
+    stack_dummy_read(cpu, mem);
+    let p = pull(cpu, mem);
+    cpu.set_p(p);
+}
+
+fn brk<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    address(cpu, mem, mode, false);
+    // The byte after BRK is skipped
+    cpu.pc = cpu.pc.wrapping_add(1);
+    interrupt(cpu, mem, true);
+}
+
+fn rti<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    address(cpu, mem, mode, false);
+    stack_dummy_read(cpu, mem);
+    let p = pull(cpu, mem);
+    cpu.set_p(p);
+    let pc = pull16(cpu, mem);
+    cpu.pc = pc;
+}
+
+fn rts<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    address(cpu, mem, mode, false);
+    stack_dummy_read(cpu, mem);
+    let pc = pull16(cpu, mem);
+    read(cpu, mem, pc); // Dummy read while the pc is incremented
+    cpu.pc = pc.wrapping_add(1);
+}
+
+fn jmp<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    cpu.pc = match address(cpu, mem, mode, false) {
+        Addr(a) => a,
+        _ => panic!("Jmp instruction address mode must produce an address result!")
+    };
+}
+
+fn kil<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    address(cpu, mem, mode, false);
+    // The cpu locks up, so we keep executing the same instruction
+    cpu.pc = cpu.pc.wrapping_sub(1);
 }
 
-fn sta(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
-    let m = mode(cpu, mem, false);
+// Mnemonic and addressing mode of an opcode, for the disassembler
+pub fn instruction_info(op: u8) -> (&'static str, Mode) {
+    let (name, _, mode) = OPCODES[op as usize];
+    (name, mode)
+}
+
+fn sta<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let m = address(cpu, mem, mode, false);
     let a = cpu.a;
     m.write(cpu, mem, a);
 }
 
-fn stx(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
-    let m = mode(cpu, mem, false);
+fn stx<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let m = address(cpu, mem, mode, false);
     let a = cpu.x;
     m.write(cpu, mem, a);
 }
 
-fn sty(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
-    let m = mode(cpu, mem, false);
+fn sty<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let m = address(cpu, mem, mode, false);
     let a = cpu.y;
     m.write(cpu, mem, a);
 }
 
-fn push(cpu: &mut Cpu, mem: &mut Chipset, val: u8) {
-    mem.write((0x01u16<<8) + cpu.s as u16, val);
+fn push<B: Bus>(cpu: &mut Cpu, mem: &mut B, val: u8) {
+    let addr = (0x01u16<<8) + cpu.s as u16;
+    write(cpu, mem, addr, val);
     cpu.s = ((cpu.s as u16).wrapping_sub(1)&0xFF) as u8;
 }
 
-fn push16(cpu: &mut Cpu, mem: &mut Chipset, val: u16) {
+fn push16<B: Bus>(cpu: &mut Cpu, mem: &mut B, val: u16) {
     push(cpu, mem, ((val&0xFF00)>>8) as u8);
     push(cpu, mem, (val&0x00FF) as u8);
 }
 
-fn pull(cpu: &mut Cpu, mem: &mut Chipset) -> u8 {
+fn pull<B: Bus>(cpu: &mut Cpu, mem: &mut B) -> u8 {
     cpu.s = ((cpu.s as u16 + 1)&0xFF) as u8;
-    mem.read((0x01u16<<8) + cpu.s as u16)
+    let addr = (0x01u16<<8) + cpu.s as u16;
+    read(cpu, mem, addr)
+}
+
+// Pulling takes an extra cycle to increment the stack pointer, during which the current
+// top of the stack is read
+fn stack_dummy_read<B: Bus>(cpu: &mut Cpu, mem: &mut B) {
+    let addr = (0x01u16<<8) + cpu.s as u16;
+    read(cpu, mem, addr);
 }
 
-fn pull16(cpu: &mut Cpu, mem: &mut Chipset) -> u16 {
+fn pull16<B: Bus>(cpu: &mut Cpu, mem: &mut B) -> u16 {
     let lo = pull(cpu, mem);
     let hi = pull(cpu, mem);
     lo as u16 + ((hi as u16)<<8)
 }
 
-fn jsr(cpu: &mut Cpu, mem: &mut Chipset, mode: AddressMode) {
-    cpu.count += 2;
-    let val = match mode(cpu, mem, false) {
-        Addr(a) => a,
-        _ => panic!("Jsr instruction address mode must produce an address result!")
+// Shared by BRK, IRQ and NMI. An NMI that arrives before the vector is fetched hijacks the
+// sequence, so BRK or IRQ will jump to the NMI handler instead (with B still as pushed).
+fn interrupt<B: Bus>(cpu: &mut Cpu, mem: &mut B, brk: bool) {
+    let pc = cpu.pc;
+    push16(cpu, mem, pc);
+
+    let interrupt = cpu.interrupt;
+    cpu.interrupt = brk;
+    let p = cpu.get_p();
+    push(cpu, mem, p);
+    cpu.interrupt = interrupt;
+    cpu.irq_disable = true;
+
+    let vector = if cpu.nmi_waiting {
+        cpu.nmi_waiting = false;
+        0xFFFA
+    } else {
+        0xFFFE
     };
+    let lo = read(cpu, mem, vector);
+    let hi = read(cpu, mem, vector + 1);
+    cpu.pc = lo as u16 + ((hi as u16)<<8);
+}
 
-    let pc = cpu.pc-1;
+// The high byte of the target is only read after the return address, which is the address
+// of that byte, has been pushed
+fn jsr<B: Bus>(cpu: &mut Cpu, mem: &mut B, _mode: Mode) {
+    let lo = fetch(cpu, mem);
+    stack_dummy_read(cpu, mem);
+    let pc = cpu.pc;
     push16(cpu, mem, pc);
-    cpu.pc = val;
+    let hi = fetch(cpu, mem);
+    cpu.pc = lo as u16 + ((hi as u16)<<8);
+}
+
+fn nop<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    // The operand is still read, even though nothing is done with it
+    address(cpu, mem, mode, true).read(cpu, mem);
+}
+
+fn lax<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let val = address(cpu, mem, mode, true).read(cpu, mem);
+    cpu.a = val;
+    cpu.x = val;
+    cpu.zero = val == 0;
+    cpu.negative = val&0b10000000 > 0;
+}
+
+fn sax<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let m = address(cpu, mem, mode, false);
+    let val = cpu.a & cpu.x;
+    m.write(cpu, mem, val);
+}
+
+fn dcp<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let r = address(cpu, mem, mode, false);
+    let val = r.read(cpu, mem);
+
+    let result = val.wrapping_sub(1);
+    r.modify(cpu, mem, val, result);
+
+    cpu.carry = cpu.a >= result;
+    cpu.zero = cpu.a == result;
+    cpu.negative = cpu.a.wrapping_sub(result)&0b10000000 > 0;
+}
+
+fn isb<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let r = address(cpu, mem, mode, false);
+    let val = r.read(cpu, mem);
+
+    let result = val.wrapping_add(1);
+    r.modify(cpu, mem, val, result);
+
+    add_with_carry(cpu, !result);
+}
+
+fn slo<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let r = address(cpu, mem, mode, false);
+    let val = r.read(cpu, mem);
+
+    cpu.carry = val&0b10000000 > 0;
+    let result = val << 1;
+    r.modify(cpu, mem, val, result);
+
+    cpu.a = cpu.a|result;
+    cpu.zero = cpu.a == 0;
+    cpu.negative = cpu.a&0b10000000 > 0;
+}
+
+fn rla<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let r = address(cpu, mem, mode, false);
+    let val = r.read(cpu, mem);
+
+    let old_carry = if cpu.carry { 1 } else { 0 };
+    cpu.carry = val&0b10000000 > 0;
+    let result = (val << 1) | old_carry;
+    r.modify(cpu, mem, val, result);
+
+    cpu.a = cpu.a&result;
+    cpu.zero = cpu.a == 0;
+    cpu.negative = cpu.a&0b10000000 > 0;
+}
+
+fn sre<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let r = address(cpu, mem, mode, false);
+    let val = r.read(cpu, mem);
+
+    cpu.carry = val&0b00000001 > 0;
+    let result = val >> 1;
+    r.modify(cpu, mem, val, result);
+
+    cpu.a = cpu.a^result;
+    cpu.zero = cpu.a == 0;
+    cpu.negative = cpu.a&0b10000000 > 0;
+}
+
+fn rra<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let r = address(cpu, mem, mode, false);
+    let val = r.read(cpu, mem);
+
+    let old_carry = if cpu.carry { 1 } else { 0 };
+    cpu.carry = val&0b00000001 > 0;
+    let result = (val >> 1) | old_carry<<7;
+    r.modify(cpu, mem, val, result);
+
+    // The carry out of the rotate is the carry into the add
+    add_with_carry(cpu, result);
+}
+
+fn anc<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    and(cpu, mem, mode);
+    cpu.carry = cpu.negative;
+}
+
+fn alr<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let val = address(cpu, mem, mode, true).read(cpu, mem);
+    let val = cpu.a&val;
+
+    cpu.carry = val&0b00000001 > 0;
+    cpu.a = val >> 1;
+    cpu.zero = cpu.a == 0;
+    cpu.negative = false;
+}
+
+fn arr<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let val = address(cpu, mem, mode, true).read(cpu, mem);
+    let old_carry = if cpu.carry { 1 } else { 0 };
+    cpu.a = ((cpu.a&val) >> 1) | old_carry<<7;
+
+    cpu.zero = cpu.a == 0;
+    cpu.negative = cpu.a&0b10000000 > 0;
+    cpu.carry = cpu.a&0b01000000 > 0;
+    cpu.overflow = ((cpu.a&0b01000000)>>6) ^ ((cpu.a&0b00100000)>>5) > 0;
+}
+
+fn axs<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let val = address(cpu, mem, mode, true).read(cpu, mem);
+    let ax = cpu.a&cpu.x;
+
+    cpu.carry = ax >= val;
+    cpu.x = ax.wrapping_sub(val);
+    cpu.zero = cpu.x == 0;
+    cpu.negative = cpu.x&0b10000000 > 0;
+}
+
+// LXA and XAA are unstable on real hardware. We use the behaviour where the "magic" constant
+// ORed into A is 0xFF, which is what most test roms expect
+fn lxa<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let val = address(cpu, mem, mode, true).read(cpu, mem);
+    cpu.a = val;
+    cpu.x = val;
+    cpu.zero = val == 0;
+    cpu.negative = val&0b10000000 > 0;
+}
+
+fn xaa<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let val = address(cpu, mem, mode, true).read(cpu, mem);
+    cpu.a = cpu.x&val;
+    cpu.zero = cpu.a == 0;
+    cpu.negative = cpu.a&0b10000000 > 0;
+}
+
+// Stores val & (high byte of the base address + 1). If the index crossed a page, the value
+// also replaces the high byte of the address that is written to.
+fn store_and_high<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode, index: u8, val: u8) {
+    let addr = match address(cpu, mem, mode, false) {
+        Addr(a) => a,
+        _ => panic!("Unofficial store address mode must produce an address result!")
+    };
+
+    let base = addr.wrapping_sub(index as u16);
+    let val = val & ((base>>8) as u8).wrapping_add(1);
+    let addr = if base&0xFF00 != addr&0xFF00 {
+        (addr&0x00FF) | ((val as u16)<<8)
+    } else {
+        addr
+    };
+
+    write(cpu, mem, addr, val);
+}
+
+fn shy<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let (index, val) = (cpu.x, cpu.y);
+    store_and_high(cpu, mem, mode, index, val);
+}
+
+fn shx<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let (index, val) = (cpu.y, cpu.x);
+    store_and_high(cpu, mem, mode, index, val);
+}
+
+fn ahx<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let (index, val) = (cpu.y, cpu.a&cpu.x);
+    store_and_high(cpu, mem, mode, index, val);
+}
+
+fn tas<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    cpu.s = cpu.a&cpu.x;
+    let (index, val) = (cpu.y, cpu.s);
+    store_and_high(cpu, mem, mode, index, val);
+}
+
+fn las<B: Bus>(cpu: &mut Cpu, mem: &mut B, mode: Mode) {
+    let val = address(cpu, mem, mode, true).read(cpu, mem);
+    let val = val&cpu.s;
+    cpu.a = val;
+    cpu.x = val;
+    cpu.s = val;
+    cpu.zero = val == 0;
+    cpu.negative = val&0b10000000 > 0;
 }
 
 impl Cpu {
@@ -762,9 +1457,11 @@ impl Cpu {
             irq_disable: false,
             carry: false,
             zero: false,
-            count: 0,
-            debug: false,
+            total_count: 0,
             nmi_waiting: false,
+            irq_sources: 0,
+            interrupt_poll: false,
+            interrupted: false,
             decimal: false,
         }
     }
@@ -789,48 +1486,59 @@ impl Cpu {
         self.carry          = val&0b00000001>0;
     }
 
-    pub fn tick(&mut self, mem: &mut Chipset) {
-        //println!("{:X}", self.pc);
-        let op = mem.read(self.pc);
-        self.pc = self.pc.wrapping_add(1);
-
-        if self.debug {
-            print!("{:04X}: {:0X}", self.pc - 1, op);
-            if self.pc <= 0xFFFE {
-                println!(" {:0X} {:0X}", mem.read(self.pc), mem.read(self.pc + 1));
-            } else {
-                println!();
-            }
-        }
+    pub fn tick<B: Bus>(&mut self, mem: &mut B) {
+        let op = fetch(self, mem);
+
+        let (_, op, mode) = OPCODES[op as usize];
+        execute(self, mem, op, mode);
 
-        match OPCODES.get(&op) {
-            Some(&(alu, mode)) => alu(self, mem, mode),
-            _ => manual(self, mem, op)
+        self.interrupted = self.interrupt_poll;
+        // CLI, SEI and PLP change the flag on their last cycle, after the poll, so the
+        // change only takes effect after the next instruction
+        if self.interrupt_poll {
+            let pc = self.pc;
+            read(self, mem, pc);
+            read(self, mem, pc);
+            interrupt(self, mem, false);
         }
+    }
 
-        if self.debug {
-            println!("State after: {:?}", self);
+    // The reset sequence is an interrupt with the writes turned into reads, so the stack
+    // pointer still goes down by three but nothing is pushed
+    pub fn reset<B: Bus>(&mut self, mem: &mut B) {
+        let pc = self.pc;
+        read(self, mem, pc);
+        read(self, mem, pc);
+        for _ in 0..3 {
+            let addr = (0x01u16<<8) + self.s as u16;
+            read(self, mem, addr);
+            self.s = self.s.wrapping_sub(1);
         }
 
-        if self.nmi_waiting {
-            self.nmi_waiting = false;
+        self.irq_disable = true;
+        self.nmi_waiting = false;
+        let lo = read(self, mem, 0xFFFC);
+        let hi = read(self, mem, 0xFFFD);
+        self.pc = lo as u16 + ((hi as u16)<<8);
+    }
 
-            self.count += 7;
-            let pc = self.pc;
-            push16(self, mem, pc);
+    pub fn nmi(&mut self) {
+        self.nmi_waiting = true;
+    }
 
-            let interrupt = self.interrupt;
-            self.interrupt = false;
-            let p = self.get_p();
-            push(self, mem, p);
-            self.interrupt = interrupt;
+    pub fn irq(&mut self, source: u8) {
+        self.irq_sources |= source;
+    }
 
-            self.pc = mem.read16(0xFFFA);
-            self.irq_disable = true;
-        }
+    pub fn acknowledge_irq(&mut self, source: u8) {
+        self.irq_sources &= !source;
     }
 
-    pub fn nmi(&mut self) {
-        self.nmi_waiting = true;
+    pub fn set_irq(&mut self, source: u8, asserted: bool) {
+        if asserted {
+            self.irq(source);
+        } else {
+            self.acknowledge_irq(source);
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,538 @@
+use std::io;
+use std::io::prelude::*;
+use std::process;
+use cpu::*;
+use nes::*;
+use disasm::disassemble;
+use trace::trace_line;
+use profiler;
+use profiler::Profiler;
+use cdl::Cdl;
+
+static HELP: &'static str = "\
+Numbers are in hex.
+  s [n]                step n instructions
+  n                    step over a JSR
+  o                    step out of the current subroutine
+  c                    continue until a breakpoint or watchpoint
+  f                    run to the next frame
+  i                    run to the next NMI
+  b <addr> [if <cond>] break when pc reaches addr, if cond is true
+                       cond is <a|x|y|s|p|pc|[addr]> <==|!=|<|>|<=|>=> <value>
+  w <addr>[-<end>] [r|w|rw]
+                       break after the range is read and/or written
+  l                    list breakpoints and watchpoints
+  bd <n>, wd <n>       delete breakpoint or watchpoint n
+  r [<reg> <value>]    show or set a register (a, x, y, s, p, pc)
+  m <addr> [len]       show memory
+  e <addr> <value>...  change ram, PRG ram or rom, skipping registers
+  u [addr] [n]         disassemble n instructions
+  p                    start the profiler, or stop it and write profile.txt and
+                       callgrind.out.nes
+  cs <file>            save the code/data log
+  cm <file>            merge a code/data log into this one
+  reset                press the reset button
+  power                turn the console off and on
+  v [palette]          show OAM and palette ram, and save the nametables, pattern
+                       tables (in palette 0-7), sprites and palette as PNGs
+  d                    leave the debugger and remove all breakpoints
+  q                    quit, writing the profile if the profiler is running";
+
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub read: bool,
+    pub write: bool,
+}
+
+impl Watchpoint {
+    pub fn matches(&self, addr: u16, write: bool) -> bool {
+        addr >= self.start && addr <= self.end && if write { self.write } else { self.read }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operand {
+    A,
+    X,
+    Y,
+    S,
+    P,
+    Pc,
+    Mem(u16),
+}
+
+struct Condition {
+    operand: Operand,
+    comparison: Comparison,
+    value: u16,
+}
+
+impl Condition {
+    fn eval(&self, cpu: &Cpu, mem: &mut Chipset) -> bool {
+        let val = match self.operand {
+            Operand::A => cpu.a as u16,
+            Operand::X => cpu.x as u16,
+            Operand::Y => cpu.y as u16,
+            Operand::S => cpu.s as u16,
+            Operand::P => cpu.get_p() as u16,
+            Operand::Pc => cpu.pc,
+            Operand::Mem(addr) => mem.peek(addr) as u16,
+        };
+
+        match self.comparison {
+            Comparison::Eq => val == self.value,
+            Comparison::Ne => val != self.value,
+            Comparison::Lt => val < self.value,
+            Comparison::Gt => val > self.value,
+            Comparison::Le => val <= self.value,
+            Comparison::Ge => val >= self.value,
+        }
+    }
+}
+
+struct Breakpoint {
+    addr: u16,
+    condition: Option<Condition>,
+    text: String,
+}
+
+enum RunMode {
+    Running,
+    Paused,
+    Step(u32),
+    // Return address and stack pointer of the JSR being stepped over
+    StepOver(u16, u8),
+    // Stack pointer when stepping out. The subroutine may have pushed more since it was called,
+    // so its return address is somewhere above it.
+    StepOut(u8),
+    Frame,
+    Nmi,
+}
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    run_mode: RunMode,
+    last_pc: u16,
+}
+
+fn parse_hex(s: &str) -> Option<u16> {
+    let s = s.trim_left_matches("0x").trim_left_matches('$');
+    u16::from_str_radix(s, 16).ok()
+}
+
+fn parse_operand(s: &str) -> Option<Operand> {
+    match s {
+        "a" => Some(Operand::A),
+        "x" => Some(Operand::X),
+        "y" => Some(Operand::Y),
+        "s" => Some(Operand::S),
+        "p" => Some(Operand::P),
+        "pc" => Some(Operand::Pc),
+        _ if s.starts_with('[') && s.ends_with(']') => {
+            parse_hex(&s[1..s.len()-1]).map(Operand::Mem)
+        },
+        _ => None
+    }
+}
+
+fn parse_condition(args: &[&str]) -> Option<Condition> {
+    if args.len() != 3 {
+        return None;
+    }
+
+    let comparison = match args[1] {
+        "==" => Comparison::Eq,
+        "!=" => Comparison::Ne,
+        "<" => Comparison::Lt,
+        ">" => Comparison::Gt,
+        "<=" => Comparison::Le,
+        ">=" => Comparison::Ge,
+        _ => return None
+    };
+
+    match (parse_operand(args[0]), parse_hex(args[2])) {
+        (Some(operand), Some(value)) => Some(Condition {
+            operand: operand,
+            comparison: comparison,
+            value: value,
+        }),
+        _ => None
+    }
+}
+
+fn get_line() -> Option<String> {
+    print!("> ");
+    io::stdout().flush().unwrap();
+
+    let mut input = String::new();
+    match io::stdin().read_line(&mut input) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(input)
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: vec![],
+            run_mode: RunMode::Running,
+            last_pc: 0,
+        }
+    }
+
+    // Break before the next instruction
+    pub fn pause(&mut self) {
+        self.run_mode = RunMode::Paused;
+    }
+
+    pub fn end_frame(&mut self) {
+        if let RunMode::Frame = self.run_mode {
+            self.run_mode = RunMode::Paused;
+        }
+    }
+
+    // Called before every instruction
+    pub fn should_break(&mut self, cpu: &Cpu, mem: &mut Chipset) -> bool {
+        let last_pc = self.last_pc;
+        self.last_pc = cpu.pc;
+
+        if let Some((addr, val, write)) = mem.watch_hit.take() {
+            println!("Watchpoint: {} ${:04X} = {:02X} by the instruction at {:04X}",
+                     if write { "write" } else { "read" }, addr, val, last_pc);
+            return true;
+        }
+
+        let stop = match self.run_mode {
+            RunMode::Running | RunMode::Frame => false,
+            RunMode::Paused => true,
+            RunMode::Step(n) => {
+                self.run_mode = RunMode::Step(n.saturating_sub(1));
+                n <= 1
+            },
+            RunMode::StepOver(ret, s) => cpu.pc == ret && cpu.s == s,
+            // Nested calls return from below s, and this one from s or above it, where the
+            // stack may have wrapped
+            RunMode::StepOut(s) => match mem.peek(last_pc) {
+                0x60 => cpu.s.wrapping_sub(2).wrapping_sub(s) < 0x80,
+                0x40 => cpu.s.wrapping_sub(3).wrapping_sub(s) < 0x80,
+                _ => false
+            },
+            RunMode::Nmi => cpu.pc == mem.peek(0xFFFA) as u16 + ((mem.peek(0xFFFB) as u16)<<8),
+        };
+        if stop {
+            return true;
+        }
+
+        for b in &self.breakpoints {
+            if b.addr == cpu.pc && b.condition.as_ref().map_or(true, |c| c.eval(cpu, mem)) {
+                println!("Breakpoint: {}", b.text);
+                return true;
+            }
+        }
+        false
+    }
+
+    // Reads commands from stdin until one of them resumes emulation. The instruction at pc
+    // runs next without checking the breakpoints again.
+    pub fn prompt(&mut self, cpu: &mut Cpu, mem: &mut Chipset, profiler: &mut Option<Profiler>) {
+        println!("{}", trace_line(cpu, mem));
+
+        loop {
+            let line = match get_line() {
+                Some(line) => line,
+                None => {
+                    // stdin was closed, so nobody is there to resume us later
+                    self.detach(mem);
+                    break;
+                }
+            };
+
+            let args: Vec<&str> = line.split_whitespace().collect();
+            if args.is_empty() {
+                continue;
+            }
+
+            if self.command(&args, cpu, mem, profiler) {
+                break;
+            }
+        }
+
+        mem.watch_hit = None;
+    }
+
+    fn detach(&mut self, mem: &mut Chipset) {
+        self.breakpoints.clear();
+        mem.watchpoints.clear();
+        self.run_mode = RunMode::Running;
+    }
+
+    // Returns true if emulation should resume
+    fn command(&mut self, args: &[&str], cpu: &mut Cpu, mem: &mut Chipset,
+               profiler: &mut Option<Profiler>) -> bool {
+        let arg = |i: usize| args.get(i).and_then(|s| parse_hex(s));
+
+        match args[0] {
+            "s" => {
+                self.run_mode = RunMode::Step(arg(1).unwrap_or(1) as u32);
+                return true;
+            },
+            "n" => {
+                self.run_mode = if mem.peek(cpu.pc) == 0x20 {
+                    RunMode::StepOver(cpu.pc.wrapping_add(3), cpu.s)
+                } else {
+                    RunMode::Step(1)
+                };
+                return true;
+            },
+            "o" => {
+                self.run_mode = RunMode::StepOut(cpu.s);
+                return true;
+            },
+            "c" => {
+                self.run_mode = RunMode::Running;
+                return true;
+            },
+            "f" => {
+                self.run_mode = RunMode::Frame;
+                return true;
+            },
+            "i" => {
+                self.run_mode = RunMode::Nmi;
+                return true;
+            },
+            "d" => {
+                self.detach(mem);
+                return true;
+            },
+            "q" => {
+                if let Some(ref p) = *profiler {
+                    p.write_reports(mem);
+                }
+                process::exit(0)
+            },
+            "p" => profiler::toggle(profiler, mem),
+            "reset" | "power" => {
+                if args[0] == "reset" {
+                    mem.reset(cpu);
+                } else {
+                    mem.power_cycle(cpu);
+                }
+                println!("{}", trace_line(cpu, mem));
+            },
+            "v" => {
+                print!("{}\n{}", mem.ppu.describe_sprites(), mem.ppu.describe_palette());
+                match mem.ppu.save_views(&mut mem.mapper, arg(1).unwrap_or(0) as u8) {
+                    Ok(_) => println!("Wrote nametables.png, patterns.png, sprites.png and palette.png"),
+                    Err(e) => println!("Could not write the ppu views: {}", e)
+                }
+            },
+            "cs" | "cm" if mem.cdl.is_none() => println!("The code/data logger is off"),
+            "cs" => match args.get(1) {
+                Some(filename) => match mem.save_cdl(filename) {
+                    Ok(_) => println!("Wrote {}", filename),
+                    Err(e) => println!("Could not write {}: {}", filename, e)
+                },
+                None => println!("Usage: cs <file>")
+            },
+            "cm" => match args.get(1) {
+                Some(filename) => {
+                    let size = mem.cdl.as_ref().map_or((0, 0), |c| (c.prg.len(), c.chr.len()));
+                    match Cdl::load(filename, size.0, size.1) {
+                        Ok(other) => mem.merge_cdl(&other),
+                        Err(e) => println!("Could not load {}: {}", filename, e)
+                    }
+                },
+                None => println!("Usage: cm <file>")
+            },
+            "b" => {
+                let condition = if args.get(2) == Some(&"if") {
+                    match parse_condition(&args[3..]) {
+                        Some(c) => Some(c),
+                        None => {
+                            println!("Invalid condition");
+                            return false;
+                        }
+                    }
+                } else {
+                    None
+                };
+
+                match arg(1) {
+                    Some(addr) => self.breakpoints.push(Breakpoint {
+                        addr: addr,
+                        condition: condition,
+                        text: args[1..].join(" "),
+                    }),
+                    None => println!("Usage: b <addr> [if <cond>]")
+                }
+            },
+            "w" => {
+                let range: Vec<&str> = args.get(1).map_or(vec![], |s| s.split('-').collect());
+                let start = range.get(0).and_then(|s| parse_hex(s));
+                let end = range.get(1).and_then(|s| parse_hex(s)).or(start);
+                let kind = args.get(2).cloned().unwrap_or("rw");
+
+                match (start, end) {
+                    (Some(start), Some(end)) => mem.watchpoints.push(Watchpoint {
+                        start: start,
+                        end: end,
+                        read: kind.contains('r'),
+                        write: kind.contains('w'),
+                    }),
+                    _ => println!("Usage: w <addr>[-<end>] [r|w|rw]")
+                }
+            },
+            "l" => {
+                for (i, b) in self.breakpoints.iter().enumerate() {
+                    println!("Breakpoint {}: {}", i, b.text);
+                }
+                for (i, w) in mem.watchpoints.iter().enumerate() {
+                    println!("Watchpoint {}: {:04X}-{:04X} {}{}", i, w.start, w.end,
+                             if w.read { "r" } else { "" }, if w.write { "w" } else { "" });
+                }
+            },
+            "bd" => match arg(1) {
+                Some(i) if (i as usize) < self.breakpoints.len() => {
+                    self.breakpoints.remove(i as usize);
+                },
+                _ => println!("No such breakpoint")
+            },
+            "wd" => match arg(1) {
+                Some(i) if (i as usize) < mem.watchpoints.len() => {
+                    mem.watchpoints.remove(i as usize);
+                },
+                _ => println!("No such watchpoint")
+            },
+            "r" => {
+                if let (Some(reg), Some(val)) = (args.get(1), arg(2)) {
+                    match *reg {
+                        "a" => cpu.a = val as u8,
+                        "x" => cpu.x = val as u8,
+                        "y" => cpu.y = val as u8,
+                        "s" => cpu.s = val as u8,
+                        "p" => cpu.set_p(val as u8),
+                        "pc" => cpu.pc = val,
+                        _ => println!("Unknown register {}", reg)
+                    }
+                }
+                println!("{}", trace_line(cpu, mem));
+            },
+            "m" => match arg(1) {
+                Some(start) => {
+                    let len = arg(2).unwrap_or(0x40) as u32;
+                    for row in 0..(len + 15)/16 {
+                        let addr = start.wrapping_add((row*16) as u16);
+                        let bytes: Vec<String> = (0..16)
+                            .map(|i| format!("{:02X}", mem.peek(addr.wrapping_add(i))))
+                            .collect();
+                        println!("{:04X}: {}", addr, bytes.join(" "));
+                    }
+                },
+                None => println!("Usage: m <addr> [len]")
+            },
+            "e" => match arg(1) {
+                Some(start) => {
+                    for i in 2..args.len() {
+                        let addr = start.wrapping_add((i - 2) as u16);
+                        match parse_hex(args[i]) {
+                            Some(val) => if !mem.poke(addr, val as u8) {
+                                println!("{:04X} is not memory", addr);
+                            },
+                            None => println!("Invalid value {}", args[i])
+                        }
+                    }
+                },
+                None => println!("Usage: e <addr> <value>...")
+            },
+            "u" => {
+                let mut addr = arg(1).unwrap_or(cpu.pc);
+                for _ in 0..arg(2).unwrap_or(0x10) {
+                    let instruction = disassemble(mem, addr);
+                    println!("{:04X}: {}", addr, instruction.text());
+                    addr = addr.wrapping_add(instruction.len());
+                }
+            },
+            _ => println!("{}", HELP)
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs the instruction at pc, then carries on until debugger stops, like after a prompt
+    fn run_to_break(nes: &mut Nes, debugger: &mut Debugger, args: &[&str]) -> u16 {
+        debugger.command(args, &mut nes.cpu, &mut nes.chipset, &mut None);
+        for _ in 0..1000 {
+            nes.step().unwrap();
+            if debugger.should_break(&nes.cpu, &mut nes.chipset) {
+                return nes.cpu.pc;
+            }
+        }
+        panic!("The debugger did not stop");
+    }
+
+    #[test]
+    fn step_out_stops_on_the_return_from_the_current_subroutine() {
+        let mut program = vec![0xEA; 0x30];
+        program[0x00..0x03].copy_from_slice(&[0x20, 0x10, 0x80]); // JSR $8010
+        // PHA, JSR $8020, PLA, RTS
+        program[0x10..0x16].copy_from_slice(&[0x48, 0x20, 0x20, 0x80, 0x68, 0x60]);
+        program[0x20] = 0x60; // RTS
+        let mut nes = nes_with_program(&program);
+        let mut debugger = Debugger::new();
+
+        debugger.command(&["b", "8014"], &mut nes.cpu, &mut nes.chipset, &mut None);
+        assert_eq!(run_to_break(&mut nes, &mut debugger, &["c"]), 0x8014);
+        debugger.command(&["bd", "0"], &mut nes.cpu, &mut nes.chipset, &mut None);
+        // Not at the PLA, which also pulls the stack above where it was
+        assert_eq!(run_to_break(&mut nes, &mut debugger, &["o"]), 0x8003);
+
+        // With the return address pushed at $0101 and $0100, the RTS wraps s back to $01
+        nes.cpu.pc = 0x8000;
+        nes.cpu.s = 0x01;
+        assert_eq!(run_to_break(&mut nes, &mut debugger, &["s"]), 0x8010);
+        assert_eq!(run_to_break(&mut nes, &mut debugger, &["o"]), 0x8003);
+        assert_eq!(nes.cpu.s, 0x01);
+    }
+
+    #[test]
+    fn breakpoints_stop_when_their_condition_holds() {
+        // LDX #$00, INX, JMP $8002
+        let mut nes = nes_with_program(&[0xA2, 0x00, 0xE8, 0x4C, 0x02, 0x80]);
+        let mut debugger = Debugger::new();
+
+        debugger.command(&["b", "8003", "if", "x", ">=", "5"], &mut nes.cpu, &mut nes.chipset,
+                         &mut None);
+        assert_eq!(run_to_break(&mut nes, &mut debugger, &["c"]), 0x8003);
+        assert_eq!(nes.cpu.x, 5);
+        assert_eq!(run_to_break(&mut nes, &mut debugger, &["c"]), 0x8003);
+        assert_eq!(nes.cpu.x, 6);
+    }
+
+    #[test]
+    fn watchpoints_stop_after_the_access() {
+        // LDA $10, STA $10, LDA $20, STA $20, NOP
+        let mut nes = nes_with_program(&[0xA5, 0x10, 0x85, 0x10, 0xA5, 0x20, 0x85, 0x20, 0xEA]);
+        let mut debugger = Debugger::new();
+
+        debugger.command(&["w", "10-11", "w"], &mut nes.cpu, &mut nes.chipset, &mut None);
+        debugger.command(&["w", "20", "r"], &mut nes.cpu, &mut nes.chipset, &mut None);
+        assert_eq!(run_to_break(&mut nes, &mut debugger, &["c"]), 0x8004);
+        assert_eq!(run_to_break(&mut nes, &mut debugger, &["c"]), 0x8006);
+        assert_eq!(run_to_break(&mut nes, &mut debugger, &["s", "2"]), 0x8009);
+    }
+}
@@ -0,0 +1,283 @@
+use cpu::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndirectX,
+    IndirectY,
+    Relative,
+}
+
+impl Mode {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Mode::Implied => "implied",
+            Mode::Accumulator => "accumulator",
+            Mode::Immediate => "immediate",
+            Mode::ZeroPage => "zero page",
+            Mode::ZeroPageX => "zero page,X",
+            Mode::ZeroPageY => "zero page,Y",
+            Mode::Absolute => "absolute",
+            Mode::AbsoluteX => "absolute,X",
+            Mode::AbsoluteY => "absolute,Y",
+            Mode::Indirect => "indirect",
+            Mode::IndirectX => "(indirect,X)",
+            Mode::IndirectY => "(indirect),Y",
+            Mode::Relative => "relative",
+        }
+    }
+
+    pub fn operand_size(&self) -> u16 {
+        match *self {
+            Mode::Implied | Mode::Accumulator => 0,
+            Mode::Absolute | Mode::AbsoluteX | Mode::AbsoluteY | Mode::Indirect => 2,
+            _ => 1
+        }
+    }
+}
+
+static UNOFFICIAL: [&'static str; 20] = ["SLO", "RLA", "SRE", "RRA", "SAX", "LAX", "DCP", "ISB",
+    "ANC", "ALR", "ARR", "AXS", "LXA", "XAA", "AHX", "TAS", "SHY", "SHX", "LAS", "KIL"];
+
+fn is_official(op: u8, mnemonic: &str) -> bool {
+    match mnemonic {
+        "NOP" => op == 0xEA,
+        "SBC" => op != 0xEB,
+        _ => !UNOFFICIAL.contains(&mnemonic)
+    }
+}
+
+pub struct Instruction {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub mnemonic: &'static str,
+    pub mode: Mode,
+    pub official: bool,
+}
+
+fn peek16(mem: &mut Bus, lo: u16, hi: u16) -> u16 {
+    mem.peek(lo) as u16 + ((mem.peek(hi) as u16)<<8)
+}
+
+impl Instruction {
+    pub fn len(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    fn arg8(&self) -> u8 {
+        self.bytes[1]
+    }
+
+    fn arg16(&self) -> u16 {
+        self.bytes[1] as u16 + ((self.bytes[2] as u16)<<8)
+    }
+
+    fn is_jump(&self) -> bool {
+        self.mnemonic == "JMP" || self.mnemonic == "JSR"
+    }
+
+    // The operand as it is written in assembly, for example #$10 or ($20),Y
+    pub fn operand(&self) -> String {
+        match self.mode {
+            Mode::Implied => String::new(),
+            Mode::Accumulator => "A".to_string(),
+            Mode::Immediate => format!("#${:02X}", self.arg8()),
+            Mode::ZeroPage => format!("${:02X}", self.arg8()),
+            Mode::ZeroPageX => format!("${:02X},X", self.arg8()),
+            Mode::ZeroPageY => format!("${:02X},Y", self.arg8()),
+            Mode::Absolute => format!("${:04X}", self.arg16()),
+            Mode::AbsoluteX => format!("${:04X},X", self.arg16()),
+            Mode::AbsoluteY => format!("${:04X},Y", self.arg16()),
+            Mode::Indirect => format!("(${:04X})", self.arg16()),
+            Mode::IndirectX => format!("(${:02X},X)", self.arg8()),
+            Mode::IndirectY => format!("(${:02X}),Y", self.arg8()),
+            Mode::Relative => {
+                let target = self.addr.wrapping_add(2).wrapping_add(self.arg8() as i8 as u16);
+                format!("${:04X}", target)
+            },
+        }
+    }
+
+    pub fn text(&self) -> String {
+        match self.mode {
+            Mode::Implied => self.mnemonic.to_string(),
+            _ => format!("{} {}", self.mnemonic, self.operand())
+        }
+    }
+
+    // The address the instruction reads or writes, if it is executed with the registers in cpu
+    pub fn effective_addr(&self, cpu: &Cpu, mem: &mut Bus) -> Option<u16> {
+        match self.mode {
+            Mode::ZeroPage => Some(self.arg8() as u16),
+            Mode::ZeroPageX => Some(self.arg8().wrapping_add(cpu.x) as u16),
+            Mode::ZeroPageY => Some(self.arg8().wrapping_add(cpu.y) as u16),
+            Mode::Absolute if !self.is_jump() => Some(self.arg16()),
+            Mode::AbsoluteX => Some(self.arg16().wrapping_add(cpu.x as u16)),
+            Mode::AbsoluteY => Some(self.arg16().wrapping_add(cpu.y as u16)),
+            Mode::IndirectX => {
+                let ptr = self.arg8().wrapping_add(cpu.x);
+                Some(peek16(mem, ptr as u16, ptr.wrapping_add(1) as u16))
+            },
+            Mode::IndirectY => {
+                let ptr = self.arg8();
+                let base = peek16(mem, ptr as u16, ptr.wrapping_add(1) as u16);
+                Some(base.wrapping_add(cpu.y as u16))
+            },
+            _ => None
+        }
+    }
+
+    // The text with the effective address and the value there, as Nintendulator shows it:
+    // LDA ($80),Y = 0300 @ 0302 = 5A
+    pub fn annotated(&self, cpu: &Cpu, mem: &mut Bus) -> String {
+        let text = self.text();
+        let addr = match self.effective_addr(cpu, mem) {
+            Some(addr) => addr,
+            None => {
+                if self.mode == Mode::Indirect {
+                    // The high byte is read without carrying into the next page
+                    let ptr = self.arg16();
+                    let target = peek16(mem, ptr, (ptr&0xFF00) + (ptr.wrapping_add(1)&0x00FF));
+                    return format!("{} = {:04X}", text, target);
+                }
+                return text;
+            }
+        };
+
+        let val = mem.peek(addr);
+        match self.mode {
+            Mode::ZeroPage | Mode::Absolute => format!("{} = {:02X}", text, val),
+            Mode::ZeroPageX | Mode::ZeroPageY => format!("{} @ {:02X} = {:02X}", text, addr, val),
+            Mode::IndirectX => {
+                let ptr = self.arg8().wrapping_add(cpu.x);
+                format!("{} @ {:02X} = {:04X} = {:02X}", text, ptr, addr, val)
+            },
+            Mode::IndirectY => {
+                let base = addr.wrapping_sub(cpu.y as u16);
+                format!("{} = {:04X} @ {:04X} = {:02X}", text, base, addr, val)
+            },
+            _ => format!("{} @ {:04X} = {:02X}", text, addr, val)
+        }
+    }
+}
+
+pub fn disassemble(mem: &mut Bus, addr: u16) -> Instruction {
+    let op = mem.peek(addr);
+    let (mnemonic, mode) = instruction_info(op);
+
+    let mut bytes = vec![op];
+    for i in 1...mode.operand_size() {
+        bytes.push(mem.peek(addr.wrapping_add(i)));
+    }
+
+    Instruction {
+        addr: addr,
+        bytes: bytes,
+        mnemonic: mnemonic,
+        mode: mode,
+        official: is_official(op, mnemonic),
+    }
+}
+
+// Decodes the instructions starting in start..end, inclusive
+pub fn disassemble_range(mem: &mut Bus, start: u16, end: u16) -> Vec<Instruction> {
+    let mut instructions = vec![];
+    let mut addr = start as u32;
+    while addr <= end as u32 {
+        let instruction = disassemble(mem, addr as u16);
+        addr += instruction.len() as u32;
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+#[cfg(test)]
+mod tests {
+    use ram_bus::RamBus;
+    use super::*;
+
+    #[test]
+    fn decodes_addressing_modes_and_marks_unofficial_opcodes() {
+        let mut bus = RamBus::new();
+        bus.load(0x0600, &[
+            0xA9, 0x10,       // LDA #$10
+            0xB6, 0x80,       // LDX $80,Y
+            0xBD, 0x34, 0x12, // LDA $1234,X
+            0x6C, 0xFF, 0x02, // JMP ($02FF)
+            0xB1, 0x20,       // LDA ($20),Y
+            0x0A,             // ASL A
+            0xD0, 0xFE,       // BNE $060D
+            0x07, 0x10,       // SLO $10
+            0xEB, 0x01,       // SBC #$01, unofficial
+            0xE9, 0x01,       // SBC #$01
+            0x1A,             // NOP, unofficial
+            0xEA,             // NOP
+        ]);
+
+        let instructions = disassemble_range(&mut bus, 0x0600, 0x0616);
+        let decoded: Vec<(u16, String, Mode, bool)> = instructions.iter()
+            .map(|i| (i.addr, i.text(), i.mode, i.official)).collect();
+        assert_eq!(decoded, vec![
+            (0x0600, "LDA #$10".to_string(), Mode::Immediate, true),
+            (0x0602, "LDX $80,Y".to_string(), Mode::ZeroPageY, true),
+            (0x0604, "LDA $1234,X".to_string(), Mode::AbsoluteX, true),
+            (0x0607, "JMP ($02FF)".to_string(), Mode::Indirect, true),
+            (0x060A, "LDA ($20),Y".to_string(), Mode::IndirectY, true),
+            (0x060C, "ASL A".to_string(), Mode::Accumulator, true),
+            (0x060D, "BNE $060D".to_string(), Mode::Relative, true),
+            (0x060F, "SLO $10".to_string(), Mode::ZeroPage, false),
+            (0x0611, "SBC #$01".to_string(), Mode::Immediate, false),
+            (0x0613, "SBC #$01".to_string(), Mode::Immediate, true),
+            (0x0615, "NOP".to_string(), Mode::Implied, false),
+            (0x0616, "NOP".to_string(), Mode::Implied, true),
+        ]);
+        assert_eq!(instructions[2].bytes, vec![0xBD, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn annotates_addresses_with_page_wrap() {
+        let mut bus = RamBus::new();
+        bus.load(0x0600, &[
+            0xB5, 0xFE,       // LDA $FE,X
+            0xA1, 0xFA,       // LDA ($FA,X)
+            0xB1, 0xFF,       // LDA ($FF),Y
+            0xB9, 0xF0, 0x12, // LDA $12F0,Y
+            0x6C, 0xFF, 0x02, // JMP ($02FF)
+            0x8D, 0x10, 0x00, // STA $0010
+            0x20, 0x00, 0x06, // JSR $0600
+        ]);
+        bus.load(0x0000, &[0x12]);
+        bus.load(0x0003, &[0xA5]);
+        bus.load(0x0010, &[0x42]);
+        bus.load(0x00FF, &[0x34]);
+        bus.load(0x0200, &[0x06]);
+        bus.load(0x02FF, &[0x00, 0x99]);
+        bus.load(0x1234, &[0x5A]);
+        bus.load(0x1244, &[0x77]);
+        bus.load(0x1300, &[0x3C]);
+
+        let mut cpu = Cpu::new(0x0600);
+        cpu.x = 0x05;
+        cpu.y = 0x10;
+        let annotated: Vec<String> = disassemble_range(&mut bus, 0x0600, 0x060F).iter()
+            .map(|i| i.annotated(&cpu, &mut bus)).collect();
+        assert_eq!(annotated, vec![
+            "LDA $FE,X @ 03 = A5",
+            "LDA ($FA,X) @ FF = 1234 = 5A",
+            "LDA ($FF),Y = 1234 @ 1244 = 77",
+            "LDA $12F0,Y @ 1300 = 3C",
+            "JMP ($02FF) = 0600",
+            "STA $0010 = 42",
+            "JSR $0600",
+        ]);
+    }
+}
@@ -0,0 +1,47 @@
+use std::error::Error;
+use std::fmt;
+
+// The part of the console that could not handle an access
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Component {
+    Memory,
+    Mapper,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmulationError {
+    // Nothing answered at addr while the instruction at pc ran. Reads got the open bus value
+    // and writes were dropped, so emulation can go on.
+    Unmapped {
+        component: Component,
+        pc: u16,
+        addr: u16,
+        write: bool,
+    },
+    UnsupportedMapper(u8),
+    // The rom does not fit its mapper
+    InvalidRom(String),
+}
+
+impl fmt::Display for EmulationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EmulationError::Unmapped { component, pc, addr, write } => {
+                write!(f, "{:?}: {} unmapped address {:04X} at PC {:04X}", component,
+                       if write { "write to" } else { "read from" }, addr, pc)
+            },
+            EmulationError::UnsupportedMapper(mapper) => write!(f, "Unsupported mapper {}", mapper),
+            EmulationError::InvalidRom(ref reason) => write!(f, "Invalid rom: {}", reason),
+        }
+    }
+}
+
+impl Error for EmulationError {
+    fn description(&self) -> &str {
+        match *self {
+            EmulationError::Unmapped { .. } => "unmapped address",
+            EmulationError::UnsupportedMapper(_) => "unsupported mapper",
+            EmulationError::InvalidRom(_) => "invalid rom",
+        }
+    }
+}
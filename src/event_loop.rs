@@ -7,10 +7,11 @@ pub mod event_loop {
     use piston::input::Input;
     use sdl2_window::Sdl2Window;
 
+    // The game runs a frame on every render, so fps sets its speed
     pub fn run<T>(mut window: Sdl2Window,
                   handler: fn(w: &mut Sdl2Window, e: Input, a: &mut T),
-                  mut arg: T) {
-        let mut events = Events::new(EventSettings::new());
+                  mut arg: T, fps: u64) {
+        let mut events = Events::new(EventSettings::new().max_fps(fps));
         while let Some(e) = events.next(&mut window) {
             handler(&mut window, e, &mut arg);
         }
@@ -34,9 +35,10 @@ pub mod event_loop {
         arg: T,
     }
 
+    // The browser decides the frame rate
     pub fn run<T>(window: Sdl2Window,
                   handler: fn(w: &mut Sdl2Window, e: Input, a: &mut T),
-                  arg: T) {
+                  arg: T, _fps: u64) {
         unsafe {
             let mut events = Box::new(EventLoop {
                 last_updated: emscripten_sys::emscripten_get_now() as f64,
@@ -0,0 +1,172 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::prelude::*;
+use cpu::*;
+use nes::*;
+use trace::trace_line;
+
+// Keeps the last instructions that ran, with the registers before each one and every bus
+// access it made, so that a fault can be written out with what led up to it.
+
+const REPORT_FILE: &'static str = "crash_report.txt";
+
+struct Entry {
+    cpu: Cpu,
+    // Scanline and dot of the ppu when the instruction started
+    position: (u32, u32),
+    // Address, value and whether it was a write, including any DMA the instruction was
+    // halted for
+    accesses: Vec<(u16, u8, bool)>,
+}
+
+pub struct History {
+    entries: VecDeque<Entry>,
+    length: usize,
+    // Only the first fault gets a report, since a broken game usually keeps faulting
+    pub reported: bool,
+}
+
+impl History {
+    pub fn new(length: usize) -> History {
+        History {
+            entries: VecDeque::with_capacity(length),
+            length: length,
+            reported: false,
+        }
+    }
+
+    // Called before every instruction
+    pub fn start(&mut self, cpu: &Cpu, position: (u32, u32)) {
+        let mut entry = if self.entries.len() >= self.length {
+            self.entries.pop_front().unwrap()
+        } else {
+            Entry {
+                cpu: cpu.clone(),
+                position: position,
+                accesses: vec![],
+            }
+        };
+        entry.cpu.clone_from(cpu);
+        entry.position = position;
+        entry.accesses.clear();
+        self.entries.push_back(entry);
+    }
+
+    pub fn access(&mut self, addr: u16, val: u8, write: bool) {
+        if let Some(entry) = self.entries.back_mut() {
+            entry.accesses.push((addr, val, write));
+        }
+    }
+}
+
+// Shows memory as the instruction saw it: the values it read, or what is there now
+struct Replay<'a> {
+    accesses: &'a [(u16, u8, bool)],
+    position: (u32, u32),
+    mem: &'a mut Chipset,
+}
+
+impl<'a> Bus for Replay<'a> {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+
+    fn write(&mut self, _addr: u16, _val: u8) {
+    }
+
+    fn peek(&mut self, addr: u16) -> u8 {
+        match self.accesses.iter().find(|a| a.0 == addr && !a.2) {
+            Some(&(_, val, _)) => val,
+            None => self.mem.peek(addr)
+        }
+    }
+
+    fn ppu_position(&self) -> (u32, u32) {
+        self.position
+    }
+}
+
+pub fn crash_report(cpu: &Cpu, mem: &mut Chipset, reason: &str) -> String {
+    let mut out = format!("{}\n", reason);
+
+    if let Some(history) = mem.history.take() {
+        out += &format!("\nLast {} instructions, oldest first:\n", history.entries.len());
+        for entry in history.entries.iter() {
+            let line = trace_line(&entry.cpu, &mut Replay {
+                accesses: &entry.accesses,
+                position: entry.position,
+                mem: mem,
+            });
+            out += &format!("{}\n", line);
+
+            for chunk in entry.accesses.chunks(8) {
+                let accesses: Vec<String> = chunk.iter()
+                    .map(|&(addr, val, write)| {
+                        format!("{} {:04X}={:02X}", if write { "W" } else { "R" }, addr, val)
+                    })
+                    .collect();
+                out += &format!("      {}\n", accesses.join("  "));
+            }
+        }
+        mem.history = Some(history);
+    }
+
+    out += &format!("\nNext instruction:\n{}\n", trace_line(cpu, mem));
+    out += &format!("\nChipset:\n{}\n", mem.state());
+    out += &format!("\nPPU:\n{}\n", mem.ppu.state());
+
+    out += "\nRAM:\n";
+    for row in 0..0x80 {
+        let bytes: Vec<String> = mem.mem.ram[row*16..row*16 + 16].iter()
+            .map(|b| format!("{:02X}", b))
+            .collect();
+        out += &format!("{:04X}: {}\n", row*16, bytes.join(" "));
+    }
+    out
+}
+
+pub fn write_crash_report(cpu: &Cpu, mem: &mut Chipset, reason: &str) {
+    let report = crash_report(cpu, mem, reason);
+    match File::create(REPORT_FILE).and_then(|mut f| f.write_all(report.as_bytes())) {
+        Ok(_) => println!("Wrote {}", REPORT_FILE),
+        Err(e) => println!("Could not write {}: {}", REPORT_FILE, e)
+    }
+}
+
+// The message panic! was called with
+pub fn panic_message(payload: &Box<Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else {
+        "Unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nes::*;
+    use super::*;
+
+    #[test]
+    fn report_shows_the_instructions_before_a_fault() {
+        let mut nes = nes_with_program(&[
+            0xA9, 0x07,       // LDA #$07
+            0xAD, 0x00, 0x50, // LDA $5000
+        ]);
+        let mut history = History::new(1);
+        // Keeps step from writing the report to a file
+        history.reported = true;
+        nes.chipset.history = Some(history);
+        nes.step().unwrap();
+        let error = nes.step().unwrap_err();
+
+        let report = crash_report(&nes.cpu, &mut nes.chipset, &error.to_string());
+        assert!(report.contains("Last 1 instructions"));
+        assert!(!report.contains("LDA #$07"));
+        assert!(report.contains("8002  AD 00 50  LDA $5000 = 50"));
+        assert!(report.contains("R 8002=AD  R 8003=00  R 8004=50  R 5000=50"));
+    }
+}
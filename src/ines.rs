@@ -2,9 +2,9 @@ use std::fs::File;
 use std::io::BufReader;
 use std::io::BufWriter;
 use std::io::prelude::*;
-use std::io::Result;
-use std::io::Error;
-use std::io::ErrorKind;
+use memory::Mirroring;
+use region::Region;
+use error::EmulationError;
 
 #[derive(Debug)]
 pub struct Flags {
@@ -12,8 +12,9 @@ pub struct Flags {
     chr_size: usize,
     pub prg_ram_size: usize,
     pub mapper: u8,
-    pub horiz_mirroring: bool,
-    i_nes: bool
+    pub mirroring: Mirroring,
+    // Only NES 2.0 headers say which console the game is for
+    pub region: Option<Region>,
 }
 
 pub fn lines_from_file(filename: &str) -> Vec<String> {
@@ -28,29 +29,120 @@ pub fn write_bytes_to_file(filename: String, vec: &[u8]) {
     bw.write_all(vec).unwrap();
 }
 
-pub fn load_file(file: &str) -> Result<(Flags, Vec<u8>, Vec<u8>)> {
-    let file = File::open(file)?;
-    let mut buf_reader = BufReader::new(file);
+pub fn load_file(file: &str) -> Result<(Flags, Vec<u8>, Vec<u8>), EmulationError> {
     let mut contents = vec![];
-    buf_reader.read_to_end(&mut contents)?;
+    let read = File::open(file).and_then(|file| BufReader::new(file).read_to_end(&mut contents));
+    if let Err(e) = read {
+        return Err(EmulationError::InvalidRom(format!("could not read the file: {}", e)));
+    }
+    parse(&contents)
+}
 
-    // See https://wiki.nesdev.com/w/index.php/INES
+// See https://wiki.nesdev.com/w/index.php/INES
+// and https://wiki.nesdev.com/w/index.php/NES_2.0
+pub fn parse(contents: &[u8]) -> Result<(Flags, Vec<u8>, Vec<u8>), EmulationError> {
+    if contents.len() < 16 || &contents[0..4] != b"NES\x1A" {
+        return Err(EmulationError::InvalidRom("missing iNES header".to_string()));
+    }
+    let nes_2 = (contents[7] & 0b00001100)>>2 == 2;
+
+    // NES 2.0 puts the high bits of the mapper number and the rom sizes, and the ram sizes, in
+    // bytes 8-11. Only plain mapper numbers and rom sizes are supported, and the mappers here
+    // always have 8kB of PRG ram and CHR ram when there is no CHR rom, so headers that ask for
+    // more are refused. Submappers are ignored.
+    let (prg_high, chr_high) = if nes_2 {
+        if contents[8] & 0x0F != 0 {
+            return Err(EmulationError::InvalidRom("mappers above 255 are not supported".to_string()));
+        }
+        if contents[9] & 0x0F == 0x0F || contents[9]>>4 == 0x0F {
+            return Err(EmulationError::InvalidRom("exponent rom sizes are not supported".to_string()));
+        }
+        if ram_size(contents[10]) > 8192 || ram_size(contents[11]) > 8192 {
+            return Err(EmulationError::InvalidRom("more than 8kB of PRG or CHR ram".to_string()));
+        }
+        ((contents[9] & 0x0F) as usize, (contents[9]>>4) as usize)
+    } else {
+        (0, 0)
+    };
 
     let flags = Flags {
-        prg_size: contents[4] as usize * 16384,
-        chr_size: contents[5] as usize * 8192,
+        prg_size: (prg_high<<8 | contents[4] as usize) * 16384,
+        chr_size: (chr_high<<8 | contents[5] as usize) * 8192,
         prg_ram_size: 8192 as usize,
-        mapper: (contents[6] & 0b11110000)>>4 + (contents[7] & 0b11110000),
-        horiz_mirroring: (contents[6] & 0b00000001) == 0,
-        i_nes: (contents[7] & 0b00001100)>>2 == 2
+        mapper: ((contents[6] & 0b11110000)>>4) | (contents[7] & 0b11110000),
+        mirroring: if contents[6] & 0b00001000 > 0 {
+            Mirroring::FourScreen
+        } else if contents[6] & 0b00000001 == 0 {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        },
+        region: if nes_2 { Some(Region::from_nes_2(contents[12])) } else { None },
     };
 
-    if flags.i_nes {
-        return Err(Error::new(ErrorKind::InvalidData, "iNES format not supported"));
+    // A trainer sits between the header and PRG rom
+    let prg_start = if contents[6] & 0b00000100 > 0 { 16 + 512 } else { 16 };
+    let chr_start = prg_start + flags.prg_size;
+    let end = chr_start + flags.chr_size;
+    if contents.len() < end {
+        return Err(EmulationError::InvalidRom(format!("the header asks for {} bytes but the file has {}",
+                                                      end, contents.len())));
     }
 
-    let prg = &contents[16..(16+flags.prg_size)];
-    let chr = &contents[(16+flags.prg_size)..(16+flags.prg_size+flags.chr_size)];
+    let prg = contents[prg_start..chr_start].to_vec();
+    let chr = contents[chr_start..end].to_vec();
+    Ok((flags, prg, chr))
+}
+
+// Both halves of a NES 2.0 ram size byte, the ram and the battery backed ram, are 64 bytes
+// shifted left by the value, or none when it is 0
+fn ram_size(byte: u8) -> usize {
+    let size = |shift: u8| if shift == 0 { 0 } else { 64 << shift };
+    size(byte & 0x0F) + size(byte>>4)
+}
 
-    Ok((flags, prg.to_vec(), chr.to_vec()))
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(header: [u8; 16], len: usize) -> Vec<u8> {
+        let mut rom = header.to_vec();
+        rom.resize(len, 0);
+        rom
+    }
+
+    #[test]
+    fn reads_the_header() {
+        let header = [b'N', b'E', b'S', 0x1A, 2, 1, 0x41, 0x40, 0, 0, 0, 0, 0, 0, 0, 0];
+        let (flags, prg, chr) = parse(&rom(header, 16 + 2*16384 + 8192)).unwrap();
+        assert_eq!(flags.mapper, 0x44);
+        assert_eq!(flags.mirroring, Mirroring::Vertical);
+        assert_eq!((prg.len(), chr.len()), (2*16384, 8192));
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let header = [b'N', b'E', b'S', 0x1A, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert!(parse(&rom(header, 16 + 2*16384)).is_err());
+        assert!(parse(&header[0..8]).is_err());
+
+        let mut not_ines = rom(header, 16 + 2*16384 + 8192);
+        not_ines[3] = 0;
+        assert!(parse(&not_ines).is_err());
+    }
+
+    #[test]
+    fn reads_nes_2_sizes_and_refuses_what_it_cannot_run() {
+        let mut header = [b'N', b'E', b'S', 0x1A, 2, 1, 0, 0x08, 0, 0x10, 0x07, 0, 0x01, 0, 0, 0];
+        let (flags, prg, chr) = parse(&rom(header, 16 + 2*16384 + 257*8192)).unwrap();
+        assert_eq!((prg.len(), chr.len()), (2*16384, 257*8192));
+        assert_eq!(flags.region, Some(Region::Pal));
+
+        header[8] = 0x01;
+        assert!(parse(&rom(header, 16 + 2*16384 + 257*8192)).is_err());
+        header[8] = 0;
+        // 8kB of PRG ram and 8kB of battery backed PRG ram
+        header[10] = 0x77;
+        assert!(parse(&rom(header, 16 + 2*16384 + 257*8192)).is_err());
+    }
+}
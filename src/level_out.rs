@@ -1,17 +1,13 @@
 #![feature(inclusive_range_syntax)]
-#![feature(plugin)]
-#![plugin(phf_macros)]
-extern crate phf;
+extern crate nes_emulator;
 extern crate rand;
 
-mod ines;
-use ines::write_bytes_to_file;
+use nes_emulator::ines::write_bytes_to_file;
 
 use rand::{Rng, SeedableRng, StdRng};
 use std::iter::FromIterator;
 
-mod level_consts;
-use level_consts::*;
+use nes_emulator::level_consts::*;
 
 const LEVELS:  &'static[u8] = &[
     //level 1-1,
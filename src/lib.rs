@@ -0,0 +1,35 @@
+#![feature(inclusive_range_syntax)]
+#![feature(inclusive_range)]
+#![feature(plugin)]
+
+#![plugin(phf_macros)]
+extern crate phf;
+extern crate image;
+
+pub mod cpu;
+pub mod disasm;
+pub mod apu;
+pub mod ines;
+pub mod controller;
+pub mod nes;
+pub mod memory;
+pub mod ppu;
+pub mod smb_hack;
+pub mod smb_level;
+pub mod settings;
+pub mod level_consts;
+pub mod trace;
+pub mod debugger;
+pub mod profiler;
+pub mod cdl;
+pub mod error;
+pub mod history;
+pub mod palette;
+pub mod ntsc;
+pub mod upscale;
+pub mod region;
+pub mod ram_bus;
+pub mod test_roms;
+
+pub mod mapper_0;
+pub mod mapper_4;
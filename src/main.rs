@@ -1,40 +1,29 @@
-#![feature(inclusive_range_syntax)]
-#![feature(inclusive_range)]
-#![feature(plugin)]
-
-#![plugin(phf_macros)]
-extern crate phf;
+extern crate nes_emulator;
 extern crate sdl2_window;
 extern crate piston;
 extern crate opengl_graphics;
-extern crate image;
 extern crate graphics;
 
 use sdl2_window::*;
 use piston::input::*;
+use std::process;
 use std::time::Instant;
 use piston::window::{OpenGLWindow, WindowSettings};
 use opengl_graphics::{GlGraphics, OpenGL, Texture, TextureSettings};
 
-mod cpu;
-mod ines;
-mod controller;
-mod nes;
-mod memory;
-mod ppu;
-mod smb_hack;
-mod smb_level;
-mod settings;
 mod event_loop;
-mod level_consts;
 
-mod mapper_0;
-mod mapper_4;
+use nes_emulator::{history, smb_hack, trace};
+use nes_emulator::ines::*;
+use nes_emulator::nes::*;
+use nes_emulator::settings::*;
+use nes_emulator::ppu::{make_canvas, scale_into, NesImageBuffer};
+use nes_emulator::cdl::Cdl;
+use nes_emulator::palette::{Ntsc, PaletteSource};
+use nes_emulator::ntsc::NtscFilter;
+use nes_emulator::region::Region;
 
-use ines::*;
-use nes::*;
-use settings::*;
-use ppu::{make_canvas, NesImageBuffer};
+const CDL_FILE: &'static str = "assets/smb.cdl";
 
 trait ControllerMethod {
     fn do_input(&mut self, nes: &mut Nes, e: &Input);
@@ -48,13 +37,48 @@ impl ControllerMethod for User {
     fn do_input(&mut self, nes: &mut Nes, e: &Input) {
         if let Some(button) = e.press_args() {
             match button {
-                Button::Keyboard(Key::D) => nes.cpu.debug = DEBUG,
+                Button::Keyboard(Key::D) => {
+                    if DEBUG {
+                        nes.debugger.pause();
+                    }
+                },
+                Button::Keyboard(Key::P) => {
+                    if DEBUG {
+                        nes.toggle_profiler();
+                    }
+                },
+                Button::Keyboard(Key::C) => {
+                    if CDL {
+                        match nes.chipset.save_cdl(CDL_FILE) {
+                            Ok(_) => println!("Wrote {}", CDL_FILE),
+                            Err(e) => println!("Could not write {}: {}", CDL_FILE, e)
+                        }
+                    }
+                },
                 Button::Keyboard(Key::R) => {
                     if DEBUG {
                         write_bytes_to_file(format!("{}.bin", self.dump_count), &nes.chipset.mem.ram);
                         self.dump_count += 1;
                     }
                 },
+                Button::Keyboard(Key::F1) => nes.reset(),
+                Button::Keyboard(Key::F2) => nes.power_cycle(),
+                Button::Keyboard(Key::F3) => {
+                    nes.chipset.ppu.sprite_limit = !nes.chipset.ppu.sprite_limit;
+                },
+                Button::Keyboard(Key::F7) => {
+                    nes.ntsc_filter = match nes.ntsc_filter {
+                        Some(_) => None,
+                        None => Some(NtscFilter::new(ntsc_settings()))
+                    };
+                },
+                Button::Keyboard(Key::F8) => {
+                    let filename = format!("screenshot-{}.png", nes.chipset.ppu.frame);
+                    match nes.frame().save(&filename) {
+                        Ok(_) => println!("Wrote {}", filename),
+                        Err(e) => println!("Could not write {}: {}", filename, e)
+                    }
+                },
                 Button::Keyboard(Key::K) => {
                     if SPECIAL && USE_HACKS {
                         smb_hack::kill_yourself(nes);
@@ -131,10 +155,35 @@ impl ControllerMethod for Movie {
     }
 }
 
+// What the window shows. F4 switches between them, F5 picks the palette for the pattern
+// tables and F6 saves all of them as PNGs.
+#[derive(Clone, Copy, PartialEq)]
+enum View {
+    Game,
+    Nametables,
+    PatternTables,
+    Sprites,
+    Palette,
+}
+
+impl View {
+    fn next(self) -> View {
+        match self {
+            View::Game => View::Nametables,
+            View::Nametables => View::PatternTables,
+            View::PatternTables => View::Sprites,
+            View::Sprites => View::Palette,
+            View::Palette => View::Game,
+        }
+    }
+}
+
 struct App {
     nes: Nes,
     frames: u64,
     last_time: Instant,
+    view: View,
+    pattern_palette: u8,
 
     gl_graphics: GlGraphics,
     controller_method: Box<ControllerMethod>,
@@ -142,6 +191,64 @@ struct App {
     canvas: NesImageBuffer,
 }
 
+fn make_nes(flags: Flags, prg: Vec<u8>, chr: Vec<u8>) -> Nes {
+    let (prg_size, chr_size) = (prg.len(), chr.len());
+    let mut nes = match Nes::new(prg, chr, flags.mapper, flags.prg_ram_size, flags.mirroring) {
+        Ok(nes) => nes,
+        Err(e) => {
+            println!("Could not start the rom: {}", e);
+            process::exit(1);
+        }
+    };
+    let region = REGION.or(flags.region).unwrap_or(Region::Ntsc);
+    nes.set_region(region);
+    match PALETTE.load(region) {
+        Ok(palette) => nes.chipset.ppu.palette = palette,
+        Err(e) => println!("Could not load palette: {}", e)
+    }
+    if NTSC_FILTER {
+        nes.ntsc_filter = Some(NtscFilter::new(ntsc_settings()));
+    }
+    if CDL {
+        nes.chipset.start_cdl(Cdl::load_or_new(CDL_FILE, prg_size, chr_size));
+    }
+    if TRACE {
+        nes.trace = Some(trace::Trace::to_file("trace.log"));
+    }
+    if PROFILE {
+        nes.toggle_profiler();
+    }
+    if HISTORY > 0 {
+        nes.chipset.history = Some(history::History::new(HISTORY));
+    }
+    if USE_HACKS {
+        if let Err(e) = nes.enable_smb_hacks() {
+            println!("{}", e);
+        }
+    }
+    nes
+}
+
+fn ntsc_settings() -> Ntsc {
+    match PALETTE {
+        PaletteSource::Ntsc(ntsc) => ntsc,
+        _ => Ntsc::default()
+    }
+}
+
+// Runs without a window, controlled from the debugger on stdin
+fn emulate_headless((flags, prg, chr) : (Flags, Vec<u8>, Vec<u8>)) {
+    println!("Loaded rom with {:?}", flags);
+
+    let mut nes = make_nes(flags, prg, chr);
+    nes.debugger.pause();
+    loop {
+        if let Err(e) = nes.tick() {
+            println!("{}", e);
+        }
+    }
+}
+
 fn emulate((flags, prg, chr) : (Flags, Vec<u8>, Vec<u8>), controller_method: Box<ControllerMethod>) {
     println!("Loaded rom with {:?}", flags);
 
@@ -158,7 +265,7 @@ fn emulate((flags, prg, chr) : (Flags, Vec<u8>, Vec<u8>), controller_method: Box
             .exit_on_esc(true).build().unwrap();
     let gl_graphics = GlGraphics::new(OpenGL::V2_1);
 
-    let nes = Nes::new(prg, chr, flags.mapper, flags.prg_ram_size, flags.horiz_mirroring);
+    let nes = make_nes(flags, prg, chr);
 
     let canvas = make_canvas(size[0], size[1]);
     let tex = Texture::from_image(&canvas, &TextureSettings::new());
@@ -167,6 +274,8 @@ fn emulate((flags, prg, chr) : (Flags, Vec<u8>, Vec<u8>), controller_method: Box
         nes: nes,
         frames: 0,
         last_time:Instant::now(),
+        view: View::Game,
+        pattern_palette: 0,
         controller_method: controller_method,
 
         gl_graphics: gl_graphics,
@@ -174,7 +283,8 @@ fn emulate((flags, prg, chr) : (Flags, Vec<u8>, Vec<u8>), controller_method: Box
         canvas: canvas,
     };
 
-    event_loop::event_loop::run(window, handle_event, app);
+    let frame_rate = app.nes.chipset.ppu.region.frame_rate();
+    event_loop::event_loop::run(window, handle_event, app, frame_rate.round() as u64);
 }
 
 fn handle_event(window: &mut Sdl2Window, e: Input, app: &mut App) {
@@ -200,9 +310,11 @@ fn handle_event(window: &mut Sdl2Window, e: Input, app: &mut App) {
             if USE_MOVIE {
                 app.controller_method.as_mut().do_input(&mut app.nes, &e);
             }
-            app.nes.tick();
+            if let Err(e) = app.nes.tick() {
+                println!("{}", e);
+            }
         }
-        app.nes.prepare_draw(&mut app.canvas);
+        draw_view(app);
 
         app.texture.update(&app.canvas);
         let tex = &app.texture;
@@ -210,7 +322,28 @@ fn handle_event(window: &mut Sdl2Window, e: Input, app: &mut App) {
         app.gl_graphics.draw(args.viewport(),
                               |ctx, g2d| graphics::image(tex, ctx.transform, g2d));
 
-        //app.canvas.save(format!("{}.png", app.frames)).unwrap();
+        if RECORD {
+            let filename = format!("frame-{}.png", app.nes.chipset.ppu.frame);
+            if let Err(e) = app.nes.frame().save(&filename) {
+                println!("Could not write {}: {}", filename, e);
+            }
+        }
+    }
+
+    if let Some(Button::Keyboard(key)) = e.press_args() {
+        match key {
+            Key::F4 => app.view = app.view.next(),
+            Key::F5 => app.pattern_palette = (app.pattern_palette + 1) % 8,
+            Key::F6 => {
+                let chipset = &mut app.nes.chipset;
+                match chipset.ppu.save_views(&mut chipset.mapper, app.pattern_palette) {
+                    Ok(_) => println!("Wrote nametables.png, patterns.png, sprites.png and palette.png"),
+                    Err(e) => println!("Could not write the ppu views: {}", e)
+                }
+                print!("{}\n{}", chipset.ppu.describe_sprites(), chipset.ppu.describe_palette());
+            },
+            _ => ()
+        }
     }
 
     if !USE_MOVIE {
@@ -218,6 +351,26 @@ fn handle_event(window: &mut Sdl2Window, e: Input, app: &mut App) {
     }
 }
 
+fn draw_view(app: &mut App) {
+    let image = {
+        let chipset = &mut app.nes.chipset;
+        match app.view {
+            View::Game => None,
+            View::Nametables => Some(chipset.ppu.draw_nametables(&mut chipset.mapper)),
+            View::PatternTables => {
+                Some(chipset.ppu.draw_pattern_tables(&mut chipset.mapper, app.pattern_palette))
+            },
+            View::Sprites => Some(chipset.ppu.draw_sprites(&mut chipset.mapper)),
+            View::Palette => Some(chipset.ppu.draw_palette()),
+        }
+    };
+
+    match image {
+        Some(image) => scale_into(&image, &mut app.canvas),
+        None => app.nes.prepare_draw(&mut app.canvas)
+    }
+}
+
 fn main() {
     let input: Box<ControllerMethod> = if !USE_MOVIE { Box::new(User { dump_count: 0 }) } else {
 //        let mut input_log = lines_from_file("tests/mars608,happylee-smb-warpless,walkathon.fm2");
@@ -228,9 +381,11 @@ fn main() {
         Box::new(Movie { input: Box::new(input_log) })
     };
     match load_file("assets/smb.nes") {
-        Ok(rom) => emulate(rom, input),
-        Err(e) => panic!("Error: {:?}", e)
+        Ok(rom) => if HEADLESS { emulate_headless(rom) } else { emulate(rom, input) },
+        Err(e) => {
+            println!("Could not load assets/smb.nes: {}", e);
+            process::exit(1);
+        }
     }
 }
 
-
@@ -1,43 +1,56 @@
 use memory::*;
+use error::EmulationError;
 
 pub struct Mapper0 {
     prg: Vec<u8>,
     prg_ram: Vec<u8>,
     chr: Vec<u8>,
+    mirroring: Mirroring,
+    // The extra nametables of four screen cartridges
+    vram: Vec<u8>,
 }
 
 impl Mapper0 {
-    pub fn new(prg: Vec<u8>, prg_ram_size: usize, chr: Vec<u8>) -> Mapper0 {
-        Mapper0 {
+    pub fn new(prg: Vec<u8>, prg_ram_size: usize, chr: Vec<u8>, mirroring: Mirroring)
+               -> Result<Mapper0, EmulationError> {
+        if prg.len() != 16*1024 && prg.len() != 32*1024 {
+            return Err(EmulationError::InvalidRom(format!("PRG rom must be 16 or 32kB, not {} bytes",
+                                                          prg.len())));
+        }
+        if prg_ram_size != 8*1024 {
+            return Err(EmulationError::InvalidRom("PRG ram must be 8kB".to_string()));
+        }
+        if chr.len() < 8*1024 {
+            return Err(EmulationError::InvalidRom("CHR must be at least 8kB".to_string()));
+        }
+
+        Ok(Mapper0 {
             prg: prg,
             prg_ram: vec![0; prg_ram_size],
             chr: chr,
-        }
+            mirroring: mirroring,
+            vram: if mirroring.uses_cartridge_vram() { vec![0; 2*1024] } else { vec![] },
+        })
     }
 }
 
 impl Mapper for Mapper0 {
-    fn read(&mut self, addr: u16) -> u8 {
-        assert!(self.prg_ram.len() == 8*1024, "PRG ram must be 8kB");
-        assert!(self.prg.len() == 16*1024 || self.prg.len() == 32*1024, "PRG ram must be 16 or 32kb");
-
+    fn read(&mut self, addr: u16) -> Option<u8> {
         match addr {
-            0x6000 ... 0x7FFF => self.prg_ram[addr as usize - 0x6000],
-            0x8000 ... 0xBFFF => self.prg[addr as usize - 0x8000],
+            0x6000 ... 0x7FFF => Some(self.prg_ram[addr as usize - 0x6000]),
+            0x8000 ... 0xBFFF => Some(self.prg[addr as usize - 0x8000]),
             0xC000 ... 0xFFFF => {
                 if self.prg.len() == 32 * 1024 {
-                    self.prg[addr as usize - 0x8000]
+                    Some(self.prg[addr as usize - 0x8000])
                 } else {
-                    self.prg[mirror_addr(0x8000 ... 0xBFFF, 0xC000 ... 0xFFFF, addr) as usize - 0x8000]
+                    Some(self.prg[mirror_addr(0x8000 ... 0xBFFF, 0xC000 ... 0xFFFF, addr) as usize - 0x8000])
                 }
             },
-            _ => {
-                panic!("Reference to invalid mapper 0 address {:X}", addr);
-            }
+            _ => None
         }
     }
 
-    fn write(&mut self, addr: u16, val: u8) {
+    fn write(&mut self, addr: u16, val: u8) -> bool {
         match addr {
             0x6000 ... 0x7FFF => self.prg_ram[addr as usize - 0x6000] = val,
             0x8000 ... 0xBFFF => self.prg[addr as usize - 0x8000] = val,
@@ -48,27 +61,45 @@ impl Mapper for Mapper0 {
                     self.prg[mirror_addr(0x8000 ... 0xBFFF, 0xC000 ... 0xFFFF, addr) as usize - 0x8000] = val
                 }
             },
-            _ => {
-                panic!("Reference to invalid mapper 0 address {:X}", addr);
-            }
+            _ => return false
         }
+        true
     }
 
-    fn read_ppu(&mut self, addr: u16) -> u8 {
+    // There are no registers, so writes only change memory
+    fn poke(&mut self, addr: u16, val: u8) -> bool {
+        self.write(addr, val)
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
         match addr {
-            0x0000...0x1FFF => self.chr[addr as usize],
-            _ => {
-                panic!("Reference to invalid mapper 0 ppu address {:X}", addr);
-            }
+            0x8000 ... 0xFFFF => Some((addr as usize - 0x8000) % self.prg.len()),
+            _ => None
         }
     }
 
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        Some(addr as usize)
+    }
+
+    // The ppu only comes to the cartridge for its pattern tables
+    fn read_ppu(&mut self, addr: u16) -> u8 {
+        self.chr[(addr&0x1FFF) as usize]
+    }
+
     fn write_ppu(&mut self, addr: u16, val: u8) {
-        match addr {
-            0x0000...0x1FFF => self.chr[addr as usize] = val,
-            _ => {
-                panic!("Reference to invalid mapper 0 ppu address {:X}", addr);
-            }
-        }
+        self.chr[(addr&0x1FFF) as usize] = val;
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn read_nametable(&mut self, addr: u16) -> u8 {
+        self.vram[(addr&0x07FF) as usize]
+    }
+
+    fn write_nametable(&mut self, addr: u16, val: u8) {
+        self.vram[(addr&0x07FF) as usize] = val;
     }
 }
\ No newline at end of file
@@ -1,71 +1,291 @@
 use memory::*;
+use error::EmulationError;
 
+// MMC3. See https://wiki.nesdev.com/w/index.php/MMC3
 pub struct Mapper4 {
     prg: Vec<u8>,
     prg_ram: Vec<u8>,
     chr: Vec<u8>,
+    mirroring: Mirroring,
+    // The extra nametables of four screen cartridges
+    vram: Vec<u8>,
+
+    // Which of the bank registers the next $8001 write goes to, in the low 3 bits, and the
+    // PRG (bit 6) and CHR (bit 7) banking modes
+    bank_select: u8,
+    // R0-R5 are the CHR banks in 1kB units, R6 and R7 the switchable 8kB PRG banks
+    banks: [u8; 8],
+    prg_ram_enabled: bool,
+    prg_ram_writable: bool,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+    // The last pattern table the ppu accessed, which is bit 12 of its address
+    a12: bool,
 }
 
 impl Mapper4 {
-    pub fn new(prg: Vec<u8>, prg_ram_size: usize, chr: Vec<u8>) -> Mapper4 {
-        Mapper4 {
+    pub fn new(prg: Vec<u8>, prg_ram_size: usize, chr: Vec<u8>, mirroring: Mirroring)
+               -> Result<Mapper4, EmulationError> {
+        if prg.len() < 16*1024 || prg.len() % (8*1024) != 0 {
+            return Err(EmulationError::InvalidRom(format!("PRG rom must be a multiple of 8kB and at least 16kB, not {} bytes",
+                                                          prg.len())));
+        }
+        if prg_ram_size != 8*1024 {
+            return Err(EmulationError::InvalidRom("PRG ram must be 8kB".to_string()));
+        }
+        if chr.len() < 8*1024 || chr.len() % 1024 != 0 {
+            return Err(EmulationError::InvalidRom("CHR must be a multiple of 1kB and at least 8kB".to_string()));
+        }
+
+        Ok(Mapper4 {
             prg: prg,
             prg_ram: vec![0; prg_ram_size],
             chr: chr,
+            mirroring: mirroring,
+            vram: if mirroring.uses_cartridge_vram() { vec![0; 2*1024] } else { vec![] },
+            bank_select: 0,
+            banks: [0, 2, 4, 5, 6, 7, 0, 1],
+            prg_ram_enabled: true,
+            prg_ram_writable: true,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            a12: false,
+        })
+    }
+
+    // $C000-$DFFF and $8000-$9FFF swap places in PRG mode 1. $A000 is always R7, and $E000
+    // is always the last bank.
+    fn prg_offset(&self, addr: u16) -> usize {
+        let last = self.prg.len()/0x2000 - 1;
+        let swapped = self.bank_select & 0x40 != 0;
+        let bank = match (addr - 0x8000)/0x2000 {
+            0 => if swapped { last - 1 } else { self.banks[6] as usize },
+            1 => self.banks[7] as usize,
+            2 => if swapped { self.banks[6] as usize } else { last - 1 },
+            _ => last,
+        };
+        (bank % (last + 1))*0x2000 + (addr as usize & 0x1FFF)
+    }
+
+    // R0 and R1 are 2kB banks at $0000 and $0800, and R2-R5 are 1kB banks from $1000. CHR
+    // mode 1 swaps the two halves.
+    fn chr_offset(&self, addr: u16) -> usize {
+        let addr = if self.bank_select & 0x80 != 0 { addr ^ 0x1000 } else { addr } & 0x1FFF;
+        let bank = match addr/0x400 {
+            0 => self.banks[0] & 0xFE,
+            1 => self.banks[0] | 1,
+            2 => self.banks[1] & 0xFE,
+            3 => self.banks[1] | 1,
+            n => self.banks[n as usize - 2],
+        };
+        (bank as usize % (self.chr.len()/0x400))*0x400 + (addr as usize & 0x3FF)
+    }
+
+    // The counter is reloaded when it is 0 (or a reload was asked for), and otherwise counts
+    // down. The IRQ is raised whenever it ends up at 0.
+    fn clock_irq(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
         }
     }
 }
 
 impl Mapper for Mapper4 {
-    fn read(&mut self, addr: u16) -> u8 {
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000 ... 0x7FFF if self.prg_ram_enabled => Some(self.prg_ram[addr as usize - 0x6000]),
+            // Disabled PRG ram is open bus
+            0x6000 ... 0x7FFF => None,
+            0x8000 ... 0xFFFF => Some(self.prg[self.prg_offset(addr)]),
+            _ => None
+        }
+    }
+
+    // The registers come in pairs, picked by whether the address is even or odd
+    fn write(&mut self, addr: u16, val: u8) -> bool {
+        let even = addr % 2 == 0;
         match addr {
-            0x6000 ... 0x7FFF => self.prg_ram[addr as usize - 0x6000],
-            0x8000 ... 0xBFFF => self.prg[addr as usize - 0x8000],
-            0xC000 ... 0xFFFF => {
-                if self.prg.len() == 32 * 1024 {
-                    self.prg[addr as usize - 0x8000]
-                } else {
-                    self.prg[mirror_addr(0x8000 ... 0xBFFF, 0xC000 ... 0xFFFF, addr) as usize - 0x8000]
+            0x6000 ... 0x7FFF => {
+                if self.prg_ram_enabled && self.prg_ram_writable {
+                    self.prg_ram[addr as usize - 0x6000] = val;
+                }
+            },
+            0x8000 ... 0x9FFF if even => self.bank_select = val,
+            0x8000 ... 0x9FFF => self.banks[(self.bank_select & 0b111) as usize] = val,
+            // Four screen boards do not use the mirroring register
+            0xA000 ... 0xBFFF if even => {
+                if self.mirroring != Mirroring::FourScreen {
+                    self.mirroring = if val & 1 == 0 { Mirroring::Vertical } else { Mirroring::Horizontal };
                 }
             },
-            _ => {
-                panic!("Reference to invalid mapper 4 address {:X}", addr);
-            }
+            0xA000 ... 0xBFFF => {
+                self.prg_ram_enabled = val & 0x80 != 0;
+                self.prg_ram_writable = val & 0x40 == 0;
+            },
+            0xC000 ... 0xDFFF if even => self.irq_latch = val,
+            0xC000 ... 0xDFFF => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            },
+            0xE000 ... 0xFFFF if even => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            },
+            0xE000 ... 0xFFFF => self.irq_enabled = true,
+            _ => return false
         }
+        true
     }
 
-    fn write(&mut self, addr: u16, val: u8) {
+    // Ignores the PRG ram protection, and changes whichever bank is mapped at addr
+    fn poke(&mut self, addr: u16, val: u8) -> bool {
         match addr {
             0x6000 ... 0x7FFF => self.prg_ram[addr as usize - 0x6000] = val,
-            0x8000 ... 0xBFFF => self.prg[addr as usize - 0x8000] = val,
-            0xC000 ... 0xFFFF => {
-                if self.prg.len() == 32 * 1024 {
-                    self.prg[addr as usize - 0x8000] = val
-                } else {
-                    self.prg[mirror_addr(0x8000 ... 0xBFFF, 0xC000 ... 0xFFFF, addr) as usize - 0x8000] = val
-                }
+            0x8000 ... 0xFFFF => {
+                let offset = self.prg_offset(addr);
+                self.prg[offset] = val;
             },
-            _ => {
-                panic!("Reference to invalid mapper 4 address {:X}", addr);
-            }
+            _ => return false
         }
+        true
     }
 
-    fn read_ppu(&mut self, addr: u16) -> u8 {
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
         match addr {
-            0x0000...0x1FFF => self.chr[addr as usize],
-            _ => {
-                panic!("Reference to invalid mapper 4 ppu address {:X}", addr);
-            }
+            0x8000 ... 0xFFFF => Some(self.prg_offset(addr)),
+            _ => None
         }
     }
 
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        Some(self.chr_offset(addr))
+    }
+
+    fn read_ppu(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+
     fn write_ppu(&mut self, addr: u16, val: u8) {
-        match addr {
-            0x0000...0x1FFF => self.chr[addr as usize] = val,
-            _ => {
-                panic!("Reference to invalid mapper 4 ppu address {:X}", addr);
-            }
+        let offset = self.chr_offset(addr);
+        self.chr[offset] = val;
+    }
+
+    // The counter is clocked when A12 rises, which is once per scanline when the background
+    // and the sprites use different pattern tables. The real MMC3 ignores rises that come
+    // within a few cpu cycles of the last one, which is not modelled.
+    fn ppu_access(&mut self, addr: u16) {
+        let a12 = addr & 0x1000 != 0;
+        if a12 && !self.a12 {
+            self.clock_irq();
+        }
+        self.a12 = a12;
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn read_nametable(&mut self, addr: u16) -> u8 {
+        self.vram[(addr&0x07FF) as usize]
+    }
+
+    fn write_nametable(&mut self, addr: u16, val: u8) {
+        self.vram[(addr&0x07FF) as usize] = val;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapper() -> Mapper4 {
+        // Each 8kB PRG bank and 1kB CHR bank starts with its number
+        let mut prg = vec![0; 64*1024];
+        for bank in 0..8 {
+            prg[bank*0x2000] = bank as u8;
         }
+        let mut chr = vec![0; 16*1024];
+        for bank in 0..16 {
+            chr[bank*0x400] = bank as u8;
+        }
+        Mapper4::new(prg, 8*1024, chr, Mirroring::Vertical).unwrap()
     }
-}
\ No newline at end of file
+
+    fn prg_banks(mapper: &mut Mapper4) -> Vec<u8> {
+        [0x8000, 0xA000, 0xC000, 0xE000].iter().map(|&a| mapper.read(a).unwrap()).collect()
+    }
+
+    fn chr_banks(mapper: &mut Mapper4) -> Vec<u8> {
+        (0..8).map(|i| mapper.read_ppu(i*0x400)).collect()
+    }
+
+    #[test]
+    fn switches_prg_and_chr_banks() {
+        let mut mapper = mapper();
+        for (r, &bank) in [2, 6, 8, 9, 10, 11, 3, 4].iter().enumerate() {
+            mapper.write(0x8000, r as u8);
+            mapper.write(0x8001, bank);
+        }
+        assert_eq!(prg_banks(&mut mapper), vec![3, 4, 6, 7]);
+        assert_eq!(chr_banks(&mut mapper), vec![2, 3, 6, 7, 8, 9, 10, 11]);
+
+        // Both modes are in the bank select register, and the odd registers are still there
+        mapper.write(0x9FFE, 0xC0);
+        assert_eq!(prg_banks(&mut mapper), vec![6, 4, 3, 7]);
+        assert_eq!(chr_banks(&mut mapper), vec![8, 9, 10, 11, 2, 3, 6, 7]);
+
+        // Odd addresses in $A000-$BFFF protect PRG ram instead of changing the mirroring
+        mapper.write(0x6000, 0x42);
+        mapper.write(0xA001, 0xC0);
+        mapper.write(0x6000, 0x43);
+        assert_eq!(mapper.read(0x6000), Some(0x42));
+        assert_eq!(mapper.mirroring(), Mirroring::Vertical);
+        mapper.write(0xA000, 1);
+        assert_eq!(mapper.mirroring(), Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn counts_scanlines_on_a12_rises() {
+        // Background tiles from $0000 and sprites from $1000
+        fn scanline(mapper: &mut Mapper4) {
+            mapper.ppu_access(0x0010);
+            mapper.ppu_access(0x1FF0);
+            mapper.ppu_access(0x1FF8);
+        }
+
+        let mut mapper = mapper();
+
+        mapper.write(0xC000, 2);
+        mapper.write(0xC001, 0);
+        mapper.write(0xE001, 0);
+        scanline(&mut mapper);
+        scanline(&mut mapper);
+        assert!(!mapper.irq());
+        scanline(&mut mapper);
+        assert!(mapper.irq());
+
+        // The line stays low until the game writes $E000
+        scanline(&mut mapper);
+        assert!(mapper.irq());
+        mapper.write(0xE000, 0);
+        assert!(!mapper.irq());
+    }
+}
@@ -1,13 +1,90 @@
 use std::ops::RangeInclusive;
 
 pub trait Mapper {
-    fn read(&mut self, addr: u16) -> u8;
+    // None, or false for writes, when nothing on the cartridge answers at addr
+    fn read(&mut self, addr: u16) -> Option<u8>;
 
-    fn write(&mut self, addr: u16, val: u8);
+    fn write(&mut self, addr: u16, val: u8) -> bool;
+
+    // Changes PRG ram or rom at addr without touching any registers, for the debugger
+    fn poke(&mut self, addr: u16, val: u8) -> bool;
 
     fn read_ppu(&mut self, addr: u16) -> u8;
 
     fn write_ppu(&mut self, addr: u16, val: u8);
+
+    // Where the byte at a cpu or ppu address is in the rom, for the code/data logger
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize>;
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize>;
+
+    // Sees every pattern table access the ppu makes, for mappers that watch its address bus
+    fn ppu_access(&mut self, _addr: u16) {
+    }
+
+    // Mappers with an interrupt counter hold the cpu's IRQ line until the game acknowledges it
+    fn irq(&self) -> bool {
+        false
+    }
+
+    // Asked on every nametable access, since many mappers switch it while the game runs
+    fn mirroring(&self) -> Mirroring;
+
+    // Accesses to nametables that the mirroring puts on the cartridge, with addr in
+    // $2000-$2FFF
+    fn read_nametable(&mut self, _addr: u16) -> u8 {
+        0
+    }
+
+    fn write_nametable(&mut self, _addr: u16, _val: u8) {
+    }
+}
+
+// Where each of the four nametables at $2000, $2400, $2800 and $2C00 is.
+// See https://wiki.nesdev.com/w/index.php/Mirroring#Nametable_Mirroring
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mirroring {
+    // $2000 and $2400 are the same, for games that scroll vertically
+    Horizontal,
+    Vertical,
+    // All four are the first or the second kB of the ppu's vram
+    SingleScreenA,
+    SingleScreenB,
+    // The cartridge has another 2kB of vram for $2800 and $2C00
+    FourScreen,
+    Custom([Nametable; 4]),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Nametable {
+    // One of the two kB of vram in the console
+    Ciram(u16),
+    // Handled by the mapper's read_nametable and write_nametable
+    Cartridge,
+}
+
+impl Mirroring {
+    pub fn nametable(&self, addr: u16) -> Nametable {
+        let table = (addr>>10) & 3;
+        match *self {
+            Mirroring::Horizontal => Nametable::Ciram(table/2),
+            Mirroring::Vertical => Nametable::Ciram(table%2),
+            Mirroring::SingleScreenA => Nametable::Ciram(0),
+            Mirroring::SingleScreenB => Nametable::Ciram(1),
+            Mirroring::FourScreen if table < 2 => Nametable::Ciram(table),
+            Mirroring::FourScreen => Nametable::Cartridge,
+            Mirroring::Custom(tables) => tables[table as usize],
+        }
+    }
+
+    // Whether the mapper needs its own 2kB of vram for read_nametable and write_nametable
+    pub fn uses_cartridge_vram(&self) -> bool {
+        match *self {
+            Mirroring::FourScreen => true,
+            Mirroring::Custom(tables) => tables.contains(&Nametable::Cartridge),
+            _ => false
+        }
+    }
 }
 
 pub trait Mem {
@@ -25,6 +102,34 @@ pub trait Mem {
     }
 }
 
+// What ram holds at power on. It is random on hardware, and a few games depend on it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RamFill {
+    Zeros,
+    Ones,
+    // The same seed always gives the same contents
+    Random(u32),
+}
+
+impl RamFill {
+    pub fn fill(&self, ram: &mut [u8]) {
+        match *self {
+            RamFill::Zeros => for b in ram.iter_mut() { *b = 0 },
+            RamFill::Ones => for b in ram.iter_mut() { *b = 0xFF },
+            RamFill::Random(seed) => {
+                // xorshift32, which gets stuck at 0
+                let mut x = if seed == 0 { 0x9E3779B9 } else { seed };
+                for b in ram.iter_mut() {
+                    x ^= x << 13;
+                    x ^= x >> 17;
+                    x ^= x << 5;
+                    *b = (x >> 24) as u8;
+                }
+            }
+        }
+    }
+}
+
 pub struct Memory {
     pub ram: [u8; 2 * 1024],
 }
@@ -35,29 +140,34 @@ impl Memory {
             ram: [0; 2048]
         }
     }
-}
 
-impl Mem for Memory {
-    fn read(&mut self, mapper: &mut Box<Mapper>, addr: u16) -> u8 {
+    // Ram and the cartridge. None, or false for writes, means nothing answered.
+    pub fn read(&mut self, mapper: &mut Box<Mapper>, addr: u16) -> Option<u8> {
         match addr {
-            0...0x07FF => self.ram[addr as usize],
+            0...0x07FF => Some(self.ram[addr as usize]),
             0x0800...0x1FFF => self.read(mapper, mirror_addr(0...0x07FF, 0x0800...0x1FFF, addr)),
             0x4020...0xFFFF => mapper.read(addr),
-            _ => {
-                panic!("Reference to invalid main address {:X}", addr);
-            }
+            _ => None
         }
     }
 
-    fn write(&mut self, mapper: &mut Box<Mapper>, addr: u16, val: u8) {
+    pub fn write(&mut self, mapper: &mut Box<Mapper>, addr: u16, val: u8) -> bool {
         match addr {
             0...0x07FF => self.ram[addr as usize] = val,
-            0x0800...0x1FFF => self.write(mapper, mirror_addr(0...0x07FF, 0x0800...0x1FFF, addr), val),
-            0x4020...0xFFFF => mapper.write(addr, val),
-            _ => {
-                panic!("Reference to invalid main address {:X}", addr);
-            }
+            0x0800...0x1FFF => return self.write(mapper, mirror_addr(0...0x07FF, 0x0800...0x1FFF, addr), val),
+            0x4020...0xFFFF => return mapper.write(addr, val),
+            _ => return false
+        }
+        true
+    }
+
+    pub fn poke(&mut self, mapper: &mut Box<Mapper>, addr: u16, val: u8) -> bool {
+        match addr {
+            0...0x1FFF => self.ram[(addr & 0x07FF) as usize] = val,
+            0x4020...0xFFFF => return mapper.poke(addr, val),
+            _ => return false
         }
+        true
     }
 }
 
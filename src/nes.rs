@@ -2,127 +2,254 @@ use settings::*;
 use cpu::*;
 use memory::*;
 use controller::*;
+use apu::*;
 use ppu::*;
-use std::io;
 use mapper_0::*;
 use mapper_4::*;
 use smb_hack::SmbHack;
 use smb_hack;
+use trace::Trace;
+use profiler::Profiler;
+use profiler;
+use debugger::*;
+use ntsc::NtscFilter;
+use upscale::Upscaler;
+use region::Region;
+use cdl::Cdl;
+use cdl;
+use history::History;
+use history;
+use error::{Component, EmulationError};
+use std::io;
+use std::panic;
 
 pub struct Nes {
     pub cpu: Cpu,
     pub chipset: Chipset,
     pub smb_hack: SmbHack,
+    pub trace: Option<Trace>,
+    pub profiler: Option<Profiler>,
+    pub debugger: Debugger,
+    // Run over each frame before it is drawn, when set
+    pub ntsc_filter: Option<NtscFilter>,
+    pub upscaler: Upscaler,
+    // The ppu's indexed output converted to colours with its palette, then upscaled
+    converted: NesImageBuffer,
+    upscaled: NesImageBuffer,
+    use_hacks: bool,
 }
 
 pub struct Chipset {
     pub mapper: Box<Mapper>,
     pub mem: Memory,
     pub ppu: Ppu,
+    pub apu: Apu,
     pub controller1: Controller,
     pub controller2: Controller,
 
     ppu_dma_requested: bool,
     ppu_dma_val: u8,
 
-    ppu_writes_requested: Vec<(u16, u8)>,
-}
+    pub watchpoints: Vec<Watchpoint>,
+    // Address, value and whether it was a write
+    pub watch_hit: Option<(u16, u8, bool)>,
 
-fn get_line() -> String {
-    let mut input = String::new();
-    match io::stdin().read_line(&mut input) {
-        Ok(_) => {
-            input
-        }
-        Err(_) => panic!("Could not read from stdin"),
-    }
+    // The CHR half of the log is kept by the ppu while logging
+    pub cdl: Option<Cdl>,
+
+    pub ram_fill: RamFill,
+
+    // The last instructions, for crash reports
+    pub history: Option<History>,
+
+    // The last value read or written, which is what reads from unmapped addresses return
+    open_bus: u8,
+    // The first access this instruction that nothing answered: component, address and
+    // whether it was a write
+    fault: Option<(Component, u16, bool)>,
 }
 
 impl Nes {
     pub fn new(prg: Vec<u8>, mut chr: Vec<u8>, mapper: u8, prg_ram_size: usize,
-               horiz_mapping: bool) -> Nes {
+               mirroring: Mirroring) -> Result<Nes, EmulationError> {
         if chr.len() == 0 {
             chr = vec![0; 8*1024];
         }
 
         let mut mem = Memory::new();
-        let mut mapper = match mapper {
-            0 => Box::new(Mapper0::new(prg, prg_ram_size, chr)) as Box<Mapper>,
-            4 => Box::new(Mapper4::new(prg, prg_ram_size, chr)) as Box<Mapper>,
-            _ => panic!()
+        RAM_FILL.fill(&mut mem.ram);
+        let mapper = match mapper {
+            0 => Box::new(Mapper0::new(prg, prg_ram_size, chr, mirroring)?) as Box<Mapper>,
+            4 => Box::new(Mapper4::new(prg, prg_ram_size, chr, mirroring)?) as Box<Mapper>,
+            _ => return Err(EmulationError::UnsupportedMapper(mapper))
         };
 
         let mut nes = Nes {
-            cpu: Cpu::new(mem.read16(&mut mapper, 0xFFFC)),
+            cpu: Cpu::new(0),
             smb_hack: SmbHack::new(),
+            trace: None,
+            profiler: None,
+            debugger: Debugger::new(),
+            ntsc_filter: None,
+            upscaler: UPSCALER,
+            converted: make_canvas(256, 240),
+            upscaled: make_canvas(1, 1),
+            use_hacks: false,
             chipset: Chipset {
                 mapper: mapper,
                 mem: mem,
-                ppu: Ppu::new(horiz_mapping),
+                ppu: Ppu::new(),
+                apu: Apu::new(Region::Ntsc),
                 ppu_dma_requested: false,
                 ppu_dma_val: 0,
+                watchpoints: vec![],
+                watch_hit: None,
+                cdl: None,
+                ram_fill: RAM_FILL,
+                history: None,
+                open_bus: 0,
+                fault: None,
                 controller1: Controller::new(),
                 controller2: Controller::new(),
-
-                ppu_writes_requested: vec![],
             },
         };
+        nes.chipset.ppu.sprite_limit = SPRITE_LIMIT;
+        nes.cpu.pc = nes.chipset.read16(0xFFFC);
+        Ok(nes)
+    }
 
-        if USE_HACKS {
-            smb_hack::initial_state(&mut nes);
-        }
+    pub fn enable_smb_hacks(&mut self) -> Result<(), EmulationError> {
+        self.use_hacks = true;
+        smb_hack::initial_state(self)
+    }
 
-        nes
+    pub fn reset(&mut self) {
+        self.chipset.reset(&mut self.cpu);
     }
 
-    pub fn tick(&mut self) {
-        let frame_time = 262*341/3;
-        while self.cpu.count < frame_time {
-            if self.chipset.ppu_dma_requested {
-                self.chipset.ppu_dma_requested = false;
-                self.chipset.ppu.ppudma(&mut self.chipset.mapper, self.chipset.ppu_dma_val,
-                                        &mut self.cpu, &mut self.chipset.mem);
+    pub fn power_cycle(&mut self) {
+        self.chipset.power_cycle(&mut self.cpu);
+        if self.use_hacks {
+            self.smb_hack = SmbHack::new();
+            if let Err(e) = smb_hack::initial_state(self) {
+                println!("{}", e);
             }
+        }
+    }
+
+    // Like moving the cartridge to another console, so it powers on again, with the colours of
+    // that console
+    pub fn set_region(&mut self, region: Region) {
+        self.chipset.ppu.region = region;
+        self.chipset.ppu.palette = region.default_palette();
+        self.power_cycle();
+    }
+
+    pub fn toggle_profiler(&mut self) {
+        profiler::toggle(&mut self.profiler, &mut self.chipset);
+    }
 
-            if self.chipset.ppu_writes_requested.len() > 0 {
-                for &(addr, val) in &self.chipset.ppu_writes_requested {
-                    self.chipset.ppu.write_main(&mut self.chipset.mapper, addr, val, &self.cpu);
+    // Runs a whole frame even if something went wrong, and returns the first error
+    pub fn tick(&mut self) -> Result<(), EmulationError> {
+        let mut result = Ok(());
+        let frame = self.chipset.ppu.frame;
+        while self.chipset.ppu.frame == frame {
+            if let Err(e) = self.step() {
+                if result.is_ok() {
+                    result = Err(e);
                 }
-                self.chipset.ppu_writes_requested.clear();
             }
+        }
 
-            self.cpu.tick(&mut self.chipset);
-            if USE_HACKS {
-                smb_hack::tick(self);
-            }
-            self.chipset.ppu.tick(&mut self.cpu, &mut self.chipset.mapper);
+        self.debugger.end_frame();
+        result
+    }
+
+    // Runs a single instruction
+    pub fn step(&mut self) -> Result<(), EmulationError> {
+        if self.debugger.should_break(&self.cpu, &mut self.chipset) {
+            self.debugger.prompt(&mut self.cpu, &mut self.chipset, &mut self.profiler);
+        }
+
+        if let Some(ref mut trace) = self.trace {
+            trace.log(&self.cpu, &mut self.chipset);
+        }
+
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.log(&self.cpu, &mut self.chipset);
+        }
 
-            if self.cpu.debug {
-                if get_line().starts_with("d") {
-                    self.cpu.debug = false;
+        let logged_op = if self.chipset.cdl.is_some() {
+            Some(cdl::log_instruction(&self.cpu, &mut self.chipset))
+        } else {
+            None
+        };
+
+        let position = self.chipset.ppu_position();
+        if let Some(ref mut history) = self.chipset.history {
+            history.start(&self.cpu, position);
+        }
+
+        let pc = self.cpu.pc;
+        let hacks = if self.chipset.history.is_some() {
+            // Any panic gets a report too, and then carries on unwinding
+            let result = panic::catch_unwind(panic::AssertUnwindSafe(|| self.run_instruction(logged_op)));
+            match result {
+                Ok(hacks) => hacks,
+                Err(payload) => {
+                    let reason = format!("Panic at PC {:04X}: {}", pc,
+                                         history::panic_message(&payload));
+                    self.report_crash(&reason);
+                    panic::resume_unwind(payload);
                 }
             }
+        } else {
+            self.run_instruction(logged_op)
+        };
+
+        let (component, addr, write) = match self.chipset.fault.take() {
+            Some(fault) => fault,
+            None => return hacks
+        };
+        let error = EmulationError::Unmapped {
+            component: component,
+            pc: pc,
+            addr: addr,
+            write: write,
+        };
+
+        self.report_crash(&error.to_string());
+        Err(error)
+    }
+
+    // The smb hacks run whole frames of their own, and their errors are returned here
+    fn run_instruction(&mut self, logged_op: Option<u8>) -> Result<(), EmulationError> {
+        self.cpu.tick(&mut self.chipset);
+        if let Some(op) = logged_op {
+            cdl::log_jump(op, &self.cpu, &mut self.chipset);
         }
 
-        self.cpu.count -= frame_time;
+        if self.use_hacks {
+            smb_hack::tick(self)?;
+        }
+        Ok(())
     }
 
-    pub fn prepare_draw(&mut self, canvas: &mut NesImageBuffer) {
-        self.chipset.ppu.prepare_draw(&mut self.chipset.mapper);
+    fn report_crash(&mut self, reason: &str) {
+        if self.chipset.history.as_ref().map_or(false, |h| !h.reported) {
+            history::write_crash_report(&self.cpu, &mut self.chipset, reason);
+            self.chipset.history.as_mut().unwrap().reported = true;
+        }
+    }
 
+    pub fn prepare_draw(&mut self, canvas: &mut NesImageBuffer) {
         if !SPECIAL {
-            let w = self.chipset.ppu.output_canvas.width();
-            let h = self.chipset.ppu.output_canvas.height();
-            let cw = canvas.width();
-            let ch = canvas.height();
-
-            for (x,y,p) in canvas.enumerate_pixels_mut() {
-                *p = *self.chipset.ppu.output_canvas.get_pixel(x*w/cw, y*h/ch);
-            }
+            scale_into(self.frame(), canvas);
             return;
         }
 
-        for (x,y,p) in self.chipset.ppu.output_canvas.enumerate_pixels() {
+        self.convert_frame();
+        for (x,y,p) in self.converted.enumerate_pixels() {
             let x = x as f64;
             let y = y as f64;
 
@@ -144,10 +271,32 @@ impl Nes {
         }
     }
 
+    // The last frame as it is shown, through the ntsc filter or the upscaler, before it is
+    // stretched to the window. Screenshots are taken from here too.
+    pub fn frame(&mut self) -> &NesImageBuffer {
+        if let Some(ref mut filter) = self.ntsc_filter {
+            let ppu = &self.chipset.ppu;
+            filter.apply(&ppu.indexed_output, ppu.frame_phase);
+            return &filter.output;
+        }
+
+        self.convert_frame();
+        if self.upscaler == Upscaler::Nearest {
+            return &self.converted;
+        }
+        self.upscaler.apply(&self.converted, &mut self.upscaled);
+        &self.upscaled
+    }
+
+    fn convert_frame(&mut self) {
+        let ppu = &self.chipset.ppu;
+        ppu.palette.convert(&ppu.indexed_output, &mut self.converted);
+    }
+
     fn get_mapped(&self, x: f64, y: f64, out_width: u32, out_height: u32) -> (f64, f64) {
-        let w = self.chipset.ppu.output_canvas.width();
+        let w = self.converted.width();
         let hw = w as f64 / 2.;
-        let h = self.chipset.ppu.output_canvas.height();
+        let h = self.converted.height();
         let hh = h as f64 / 2.;
 
         let x_off = x as f64 - hw;
@@ -163,24 +312,162 @@ impl Nes {
 }
 
 impl Chipset {
-    pub fn read(&mut self, addr: u16) -> u8 {
-        match addr as usize {
-            0x2000 ... 0x2007 => self.ppu.read_main(&mut self.mapper, addr),
-            0x2008...0x3FFF => self.read(mirror_addr(0x2000...0x2007, 0x2008...0x3FFF, addr)),
-            0x4014 => self.ppu.read_main(&mut self.mapper, addr),
-            0x4016 => self.controller1.read(&mut self.mapper, addr),
-            0x4017 => self.controller2.read(&mut self.mapper, addr),
-            0x4000 ... 0x4017 => 0 /* apu */,
-            _ => self.mem.read(&mut self.mapper, addr)
+    // Pressing the reset button. Ram and the cartridge are untouched.
+    pub fn reset(&mut self, cpu: &mut Cpu) {
+        self.ppu.reset(&mut self.mapper);
+        self.apu.reset();
+        self.ppu_dma_requested = false;
+        cpu.reset(self);
+    }
+
+    // Turning the console off and on again. Breakpoints and logs are kept.
+    pub fn power_cycle(&mut self, cpu: &mut Cpu) {
+        self.ram_fill.fill(&mut self.mem.ram);
+        self.ppu.power_cycle();
+        self.apu = Apu::new(self.ppu.region);
+        self.ppu_dma_requested = false;
+        *cpu = Cpu::new(self.read16(0xFFFC));
+    }
+
+    // Everything a crash report needs that is not in ram or the ppu
+    pub fn state(&self) -> String {
+        format!("open bus: {:02X}  OAM DMA pending: {}  fault: {:?}",
+                self.open_bus, self.ppu_dma_requested, self.fault)
+    }
+
+    fn unmapped(&mut self, addr: u16, write: bool) {
+        if self.fault.is_none() {
+            let component = if addr >= 0x4020 { Component::Mapper } else { Component::Memory };
+            self.fault = Some((component, addr, write));
         }
     }
 
-    pub fn write(&mut self, addr: u16, val: u8) {
+    fn watch(&mut self, addr: u16, val: u8, write: bool) {
+        if self.watch_hit.is_none() && self.watchpoints.iter().any(|w| w.matches(addr, write)) {
+            self.watch_hit = Some((addr, val, write));
+        }
+    }
+
+    pub fn start_cdl(&mut self, cdl: Cdl) {
+        self.ppu.chr_log = cdl.chr.clone();
+        self.cdl = Some(cdl);
+    }
+
+    pub fn merge_cdl(&mut self, other: &Cdl) {
+        if let Some(ref mut cdl) = self.cdl {
+            cdl.chr.clone_from(&self.ppu.chr_log);
+            cdl.merge(other);
+            self.ppu.chr_log.clone_from(&cdl.chr);
+        }
+    }
+
+    pub fn save_cdl(&mut self, filename: &str) -> io::Result<()> {
+        match self.cdl {
+            Some(ref mut cdl) => {
+                cdl.chr.clone_from(&self.ppu.chr_log);
+                cdl.save(filename)
+            },
+            None => Ok(())
+        }
+    }
+
+    // Changes ram or the cartridge's memory without the side effects of a cpu write: no
+    // registers, DMA, watchpoints or logs. False for addresses that are not memory.
+    pub fn poke(&mut self, addr: u16, val: u8) -> bool {
+        match addr {
+            0x2000...0x401F => false,
+            _ => self.mem.poke(&mut self.mapper, addr, val)
+        }
+    }
+
+    pub fn read16(&mut self, addr: u16) -> u16 {
+        self.read(addr) as u16 + ((self.read(addr+1) as u16)<<8)
+    }
+}
+
+impl Bus for Chipset {
+    fn tick(&mut self, cpu: &mut Cpu) {
+        self.ppu.tick(cpu, &mut self.mapper);
+        self.apu.tick();
+        cpu.set_irq(IRQ_MAPPER, self.mapper.irq());
+        cpu.set_irq(IRQ_APU_FRAME, self.apu.frame_irq());
+        cpu.set_irq(IRQ_DMC, self.apu.dmc_irq());
+    }
+
+    fn dma(&mut self) -> Option<Dma> {
+        if self.ppu_dma_requested {
+            self.ppu_dma_requested = false;
+            return Some(Dma::Oam(self.ppu_dma_val));
+        }
+
+        match self.apu.dmc_request() {
+            Some(addr) => {
+                if let Some(ref mut cdl) = self.cdl {
+                    cdl.log_prg(&self.mapper, addr, cdl::PCM);
+                }
+                Some(Dma::Dmc(addr))
+            },
+            None => None
+        }
+    }
+
+    fn dmc_sample(&mut self, val: u8) {
+        self.apu.dmc_fill(val);
+    }
+
+    fn ppu_position(&self) -> (u32, u32) {
+        (self.ppu.scanline, self.ppu.dot)
+    }
+
+    fn read(&mut self, addr: u16) -> u8 {
+        let val = match addr as usize {
+            0x2000 ... 0x2007 => Some(self.ppu.read_main(&mut self.mapper, addr)),
+            0x2008...0x3FFF => {
+                let addr = mirror_addr(0x2000...0x2007, 0x2008...0x3FFF, addr);
+                Some(self.ppu.read_main(&mut self.mapper, addr))
+            },
+            0x4015 => Some(self.apu.read_status()),
+            0x4016 => Some(self.controller1.read(&mut self.mapper, addr)),
+            0x4017 => Some(self.controller2.read(&mut self.mapper, addr)),
+            // Write only apu registers, and the apu test registers which are disabled
+            0x4000 ... 0x401F => None,
+            _ => {
+                let val = self.mem.read(&mut self.mapper, addr);
+                if val.is_none() {
+                    self.unmapped(addr, false);
+                }
+                val
+            }
+        };
+        let val = val.unwrap_or(self.open_bus);
+        self.open_bus = val;
+
+        if let Some(ref mut history) = self.history {
+            history.access(addr, val, false);
+        }
+
+        if !self.watchpoints.is_empty() {
+            self.watch(addr, val, false);
+        }
+        val
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        if !self.watchpoints.is_empty() {
+            self.watch(addr, val, true);
+        }
+        self.open_bus = val;
+
+        if let Some(ref mut history) = self.history {
+            history.access(addr, val, true);
+        }
+
         match addr as usize {
-            0x2000 ... 0x2007 => {
-                self.ppu_writes_requested.push((addr, val));
+            0x2000 ... 0x2007 => self.ppu.write_main(&mut self.mapper, addr, val),
+            0x2008...0x3FFF => {
+                let addr = mirror_addr(0x2000...0x2007, 0x2008...0x3FFF, addr);
+                self.ppu.write_main(&mut self.mapper, addr, val)
             },
-            0x2008...0x3FFF => self.write(mirror_addr(0x2000...0x2007, 0x2008...0x3FFF, addr), val),
             0x4014 => {
                 self.ppu_dma_requested = true;
                 self.ppu_dma_val = val;
@@ -189,17 +476,135 @@ impl Chipset {
                 self.controller1.write(&mut self.mapper, addr, val);
                 self.controller2.write(&mut self.mapper, addr, val);
             },
-            0x4000 ... 0x4017 => () /* apu */,
-            _ => self.mem.write(&mut self.mapper, addr, val)
+            0x4000 ... 0x4017 => self.apu.write(addr, val),
+            0x4018 ... 0x401F => (),
+            _ => if !self.mem.write(&mut self.mapper, addr, val) {
+                self.unmapped(addr, true);
+            }
         }
     }
 
-    pub fn read16(&mut self, addr: u16) -> u16 {
-        self.read(addr) as u16 + ((self.read(addr+1) as u16)<<8)
+    // Registers read as open bus
+    fn peek(&mut self, addr: u16) -> u8 {
+        match addr as usize {
+            0x2000 ... 0x5FFF => 0xFF,
+            _ => self.mem.read(&mut self.mapper, addr).unwrap_or(0xFF)
+        }
+    }
+}
+
+// A mapper 0 console that runs program from $8000 with zeroed ram, for tests
+#[cfg(test)]
+pub fn nes_with_program(program: &[u8]) -> Nes {
+    nes_with_ram_fill(program, RamFill::Zeros)
+}
+
+#[cfg(test)]
+pub fn nes_with_ram_fill(program: &[u8], ram_fill: RamFill) -> Nes {
+    let mut prg = vec![0; 16*1024];
+    prg[..program.len()].copy_from_slice(program);
+    prg[0x3FFC] = 0x00;
+    prg[0x3FFD] = 0x80;
+    let mut nes = Nes::new(prg, vec![0; 8*1024], 0, 8*1024, Mirroring::Horizontal).unwrap();
+    nes.chipset.ram_fill = ram_fill;
+    nes.power_cycle();
+    nes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Cycles taken by the last instruction of program, including any DMA it was halted for
+    fn last_instruction_cycles(program: &[u8]) -> u64 {
+        let mut nes = nes_with_program(program);
+        while nes.cpu.pc != 0x8000 + program.len() as u16 - 1 {
+            nes.step().unwrap();
+        }
+        let start = nes.cpu.total_count;
+        nes.step().unwrap();
+        nes.cpu.total_count - start
     }
 
-    fn write16(&mut self, addr: u16, val: u16) {
-        self.write(addr, (val&0x00FF) as u8);
-        self.write(addr+1, ((val&0xFF00)>>8) as u8);
+    #[test]
+    fn dma_halts_the_cpu() {
+        // LDA #$02, STA $4014, NOP ends the STA on an even cycle
+        assert_eq!(last_instruction_cycles(&[0xA9, 0x02, 0x8D, 0x14, 0x40, 0xEA]), 514 + 2);
+        // LDA $02, STA $4014, NOP ends it on an odd one
+        assert_eq!(last_instruction_cycles(&[0xA5, 0x02, 0x8D, 0x14, 0x40, 0xEA]), 513 + 2);
+        // LDA #$10, STA $4015, NOP starts a DMC sample, which fetches its first byte
+        assert_eq!(last_instruction_cycles(&[0xA9, 0x10, 0x8D, 0x15, 0x40, 0xEA]), 3 + 2);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn reset_keeps_ram_and_restarts_at_the_vector() {
+        // LDA #$42, STA $10, NOP
+        let mut nes = nes_with_program(&[0xA9, 0x42, 0x85, 0x10, 0xEA]);
+        for _ in 0..3 {
+            nes.step().unwrap();
+        }
+        let s = nes.cpu.s;
+        let p = nes.cpu.get_p();
+        nes.cpu.set_p(p & !0x04);
+        nes.reset();
+
+        assert_eq!(nes.cpu.pc, 0x8000);
+        assert_eq!(nes.cpu.s, s.wrapping_sub(3));
+        assert!(nes.cpu.get_p() & 0x04 > 0);
+        assert_eq!(nes.chipset.peek(0x10), 0x42);
+
+        nes.power_cycle();
+        assert_eq!(nes.chipset.peek(0x10), 0);
+    }
+
+    #[test]
+    fn power_on_fills_ram_with_the_pattern() {
+        let ones = nes_with_ram_fill(&[0xEA], RamFill::Ones);
+        assert!(ones.chipset.mem.ram.iter().all(|&b| b == 0xFF));
+
+        let mut random = nes_with_ram_fill(&[0xEA], RamFill::Random(1234));
+        let ram = random.chipset.mem.ram;
+        assert!(ram.iter().any(|&b| b != ram[0]));
+        assert!(ram[..] == nes_with_ram_fill(&[0xEA], RamFill::Random(1234)).chipset.mem.ram[..]);
+        assert!(ram[..] != nes_with_ram_fill(&[0xEA], RamFill::Random(1235)).chipset.mem.ram[..]);
+
+        random.chipset.poke(0x10, !ram[0x10]);
+        random.power_cycle();
+        assert!(ram[..] == random.chipset.mem.ram[..]);
+    }
+
+    #[test]
+    fn pokes_skip_registers_and_watchpoints() {
+        let mut nes = nes_with_program(&[0xEA]);
+        nes.chipset.watchpoints.push(Watchpoint { start: 0, end: 0xFFFF, read: true, write: true });
+        let open_bus = nes.chipset.open_bus;
+
+        assert!(nes.chipset.poke(0x0810, 0x42));
+        assert!(nes.chipset.poke(0x6000, 0x43));
+        assert!(nes.chipset.poke(0x8000, 0x4C));
+        assert!(!nes.chipset.poke(0x2000, 0x80));
+        assert!(!nes.chipset.poke(0x4014, 0x02));
+
+        assert_eq!([nes.chipset.peek(0x0010), nes.chipset.peek(0x6000), nes.chipset.peek(0x8000)],
+                   [0x42, 0x43, 0x4C]);
+        assert!(nes.chipset.watch_hit.is_none());
+        assert!(!nes.chipset.ppu_dma_requested);
+        assert_eq!(nes.chipset.open_bus, open_bus);
+    }
+
+    #[test]
+    fn unmapped_reads_are_open_bus_and_reported() {
+        let mut nes = nes_with_program(&[0xAD, 0x00, 0x50]); // LDA $5000
+        assert_eq!(nes.step(), Err(EmulationError::Unmapped {
+            component: Component::Mapper,
+            pc: 0x8000,
+            addr: 0x5000,
+            write: false,
+        }));
+        // The high byte of the address was the last thing on the bus
+        assert_eq!(nes.cpu.a, 0x50);
+
+        assert_eq!(Nes::new(vec![0; 16*1024], vec![], 2, 8*1024, Mirroring::Horizontal).err(),
+                   Some(EmulationError::UnsupportedMapper(2)));
+    }
+}
@@ -0,0 +1,154 @@
+use image;
+use ppu::{make_canvas, NesImageBuffer};
+use palette::{composite_signal, Ntsc};
+
+// Simulates the composite video signal between the ppu and a tv, like blargg's nes_ntsc.
+// Each dot of the ppu is 8 samples of a signal whose colour is the phase of a square wave,
+// and the tv separates brightness and colour again with filters that are too short and too
+// long. What leaks through gives the dot crawl, colour bleed and fringing of a real tv.
+// See https://wiki.nesdev.com/w/index.php/NTSC_video
+
+// Output pixels for each dot
+const SCALE: usize = 3;
+const SAMPLES_PER_DOT: usize = 8;
+// Less than a whole cycle of the colour subcarrier, so some of it is left in the brightness
+const LUMA_WINDOW: usize = 10;
+// Two whole cycles, which blurs colour over 3 dots
+const CHROMA_WINDOW: usize = 24;
+// Black around the picture, so the windows at the edges have something to read
+const BORDER: usize = CHROMA_WINDOW;
+
+pub struct NtscFilter {
+    ntsc: Ntsc,
+    // The signal for each colour and emphasis at each phase of the subcarrier
+    signals: Vec<[f32; 12]>,
+    cos: [f32; 12],
+    sin: [f32; 12],
+    // Gamma correction of a channel from 0 to 1, in 1024 steps
+    to_byte: Vec<u8>,
+
+    // Running sums over a scanline of the signal, and of it demodulated into I and Q
+    luma: Vec<f32>,
+    i: Vec<f32>,
+    q: Vec<f32>,
+
+    pub output: NesImageBuffer,
+}
+
+impl NtscFilter {
+    pub fn new(ntsc: Ntsc) -> NtscFilter {
+        let mut signals = Vec::with_capacity(512);
+        for index in 0..512 {
+            let mut signal = [0.0; 12];
+            for phase in 0..12 {
+                signal[phase] = composite_signal(index, phase) as f32;
+            }
+            signals.push(signal);
+        }
+
+        let (mut cos, mut sin) = ([0.0; 12], [0.0; 12]);
+        for phase in 0..12 {
+            let angle = ntsc.carrier_angle(phase as f64);
+            cos[phase] = angle.cos() as f32;
+            sin[phase] = angle.sin() as f32;
+        }
+
+        let samples = 256*SAMPLES_PER_DOT + 2*BORDER + 1;
+        NtscFilter {
+            ntsc: ntsc,
+            signals: signals,
+            cos: cos,
+            sin: sin,
+            to_byte: (0..1024).map(|i| ntsc.to_byte(i as f64/1023.0)).collect(),
+            luma: vec![0.0; samples],
+            i: vec![0.0; samples],
+            q: vec![0.0; samples],
+            output: make_canvas((256*SCALE) as u32, 240),
+        }
+    }
+
+    // frame is the ppu's indexed output, and frame_phase where the subcarrier was when it
+    // started
+    pub fn apply(&mut self, frame: &[u16], frame_phase: u8) {
+        for y in 0..240 {
+            let line_phase = (frame_phase as usize + 4*y) % 12;
+            self.sum_scanline(&frame[y*256..(y + 1)*256], line_phase);
+
+            for x in 0..256*SCALE {
+                let centre = BORDER + x*SAMPLES_PER_DOT/SCALE + 1;
+                let window = |sums: &[f32], len: usize| {
+                    (sums[centre + len/2] - sums[centre - len/2])/len as f32
+                };
+                // The signal is scaled so that a whole cycle gives the colours of the palette
+                let luma = window(&self.luma, LUMA_WINDOW);
+                let i = window(&self.i, CHROMA_WINDOW);
+                let q = window(&self.q, CHROMA_WINDOW);
+
+                let rgb = self.ntsc.yiq_to_rgb(luma as f64, i as f64, q as f64);
+                let to_byte = &self.to_byte;
+                let byte = |f: f64| to_byte[(f.max(0.0).min(1.0)*1023.0) as usize];
+                self.output.put_pixel(x as u32, y as u32,
+                                      image::Rgba([byte(rgb[0]), byte(rgb[1]), byte(rgb[2]), 0xFF]));
+            }
+        }
+    }
+
+    fn sum_scanline(&mut self, line: &[u16], line_phase: usize) {
+        let (mut luma, mut i, mut q) = (0.0, 0.0, 0.0);
+        self.luma[0] = 0.0;
+        self.i[0] = 0.0;
+        self.q[0] = 0.0;
+
+        for n in 0..self.luma.len() - 1 {
+            let phase = (line_phase + n + 12 - BORDER % 12) % 12;
+            let signal = if n < BORDER || n >= BORDER + 256*SAMPLES_PER_DOT {
+                0.0
+            } else {
+                let index = line[(n - BORDER)/SAMPLES_PER_DOT] as usize & 0x1FF;
+                self.signals[index][phase]
+            };
+
+            luma += signal;
+            i += signal*self.cos[phase];
+            q += signal*self.sin[phase];
+            self.luma[n + 1] = luma;
+            self.i[n + 1] = i;
+            self.q[n + 1] = q;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use palette::*;
+    use super::*;
+
+    #[test]
+    fn flat_colours_match_the_generated_palette() {
+        let ntsc = Ntsc::default();
+        let palette = Palette::generate(ntsc);
+        let mut filter = NtscFilter::new(ntsc);
+
+        for &colour in [0x00, 0x16, 0x2A, 0x30].iter() {
+            filter.apply(&vec![colour; 256*240], 0);
+            let p = filter.output.get_pixel(384, 120).0;
+            let expected = palette.colour(colour as u8, 0);
+            for c in 0..3 {
+                assert!((p[c] as i32 - expected[c] as i32).abs() <= 24,
+                        "{:02X}: {:?} is not close to {:?}", colour, p, expected);
+            }
+        }
+
+        // A sharp edge between two colours bleeds into the next dot
+        let mut frame = vec![0x0F; 256*240];
+        for y in 0..240 {
+            for x in 128..256 {
+                frame[y*256 + x] = 0x16;
+            }
+        }
+        filter.apply(&frame, 0);
+        let black = filter.output.get_pixel(0, 0).0;
+        let bled = filter.output.get_pixel((127*SCALE) as u32, 0).0;
+        assert!(bled[0] > black[0]);
+    }
+}
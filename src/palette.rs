@@ -0,0 +1,351 @@
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use image;
+use ppu::NesImageBuffer;
+use region::Region;
+
+// The rgb colour for each combination of a 6 bit colour and the 3 emphasis bits of PPUMASK,
+// in the order of 512 colour .pal files: red, green and blue emphasis are bits 6, 7 and 8 of
+// the index.
+pub struct Palette {
+    colours: Vec<[u8; 3]>,
+}
+
+// What the emphasis bits leave of the other colours, for palettes that only have 64 colours
+const EMPHASIS_ATTENUATION: f64 = 0.816;
+
+static DEFAULT_COLOURS: [u8; 192] = [
+    124,124,124,
+    0,0,252,
+    0,0,188,
+    68,40,188,
+    148,0,132,
+    168,0,32,
+    168,16,0,
+    136,20,0,
+    80,48,0,
+    0,120,0,
+    0,104,0,
+    0,88,0,
+    0,64,88,
+    0,0,0,
+    0,0,0,
+    0,0,0,
+    188,188,188,
+    0,120,248,
+    0,88,248,
+    104,68,252,
+    216,0,204,
+    228,0,88,
+    248,56,0,
+    228,92,16,
+    172,124,0,
+    0,184,0,
+    0,168,0,
+    0,168,68,
+    0,136,136,
+    0,0,0,
+    0,0,0,
+    0,0,0,
+    248,248,248,
+    60,188,252,
+    104,136,252,
+    152,120,248,
+    248,120,248,
+    248,88,152,
+    248,120,88,
+    252,160,68,
+    248,184,0,
+    184,248,24,
+    88,216,84,
+    88,248,152,
+    0,232,216,
+    120,120,120,
+    0,0,0,
+    0,0,0,
+    252,252,252,
+    164,228,252,
+    184,184,248,
+    216,184,248,
+    248,184,248,
+    248,164,192,
+    240,208,176,
+    252,224,168,
+    248,216,120,
+    216,248,120,
+    184,248,184,
+    184,248,216,
+    0,252,252,
+    248,216,248,
+    0,0,0,
+    0,0,0
+];
+
+// Settings for decoding the ppu's composite signal, for the generated palette and the NTSC
+// filter. The defaults are Bisqwit's, which look like the palettes of FCEUX and Mesen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ntsc {
+    pub hue: f64,
+    pub saturation: f64,
+    pub contrast: f64,
+    pub brightness: f64,
+    pub gamma: f64,
+}
+
+impl Ntsc {
+    pub fn default() -> Ntsc {
+        Ntsc {
+            hue: 0.0,
+            saturation: 1.2,
+            contrast: 1.0,
+            brightness: 1.0,
+            gamma: 1.8,
+        }
+    }
+
+    // The angle of the colour subcarrier at a phase from 0 to 12
+    pub fn carrier_angle(&self, phase: f64) -> f64 {
+        // Starting 4 samples in lines the hues up with the palettes of FCEUX and Mesen
+        PI/6.0*(phase + 4.0 + self.hue/30.0)
+    }
+
+    // Applies the settings to a decoded colour, and converts it with the FCC's YIQ to rgb
+    // matrix. The result is before gamma correction.
+    pub fn yiq_to_rgb(&self, y: f64, i: f64, q: f64) -> [f64; 3] {
+        let y = y*self.contrast + self.brightness - 1.0;
+        let (i, q) = (i*self.saturation, q*self.saturation);
+        [y + 0.946882*i + 0.623557*q,
+         y - 0.274788*i - 0.635691*q,
+         y - 1.108545*i + 1.709007*q]
+    }
+
+    // The same for a PAL tv, which decodes U and V instead, with the matrix of BT.470
+    pub fn yuv_to_rgb(&self, y: f64, u: f64, v: f64) -> [f64; 3] {
+        let y = y*self.contrast + self.brightness - 1.0;
+        let (u, v) = (u*self.saturation, v*self.saturation);
+        [y + 1.139837*v,
+         y - 0.394652*u - 0.580599*v,
+         y + 2.032110*u]
+    }
+
+    pub fn to_byte(&self, f: f64) -> u8 {
+        let f = if f <= 0.0 { 0.0 } else { f.powf(2.2/self.gamma) };
+        (f*255.0).round().max(0.0).min(255.0) as u8
+    }
+}
+
+// The ppu's composite signal for a colour, with the emphasis bits above it, at one of the 12
+// phases of the colour subcarrier. It is a square wave between two voltages, so the phase of
+// the wave is the hue. Black is 0 and white is 1.
+pub fn composite_signal(index: usize, phase: usize) -> f64 {
+    // Voltages of the signal, low and high, for each of the 4 brightness levels
+    let levels = [0.350, 0.518, 0.962, 1.550, 1.094, 1.506, 1.962, 1.962];
+    let (black, white) = (levels[1], levels[6]);
+    let attenuation = 0.746;
+    let in_colour_phase = |colour: usize| (colour + phase) % 12 < 6;
+
+    let colour = index & 0x0F;
+    // $xE and $xF are black
+    let level = if colour < 0x0E { (index>>4) & 3 } else { 1 };
+    let low = levels[level + if colour == 0x00 { 4 } else { 0 }];
+    let high = levels[level + if colour < 0x0D { 4 } else { 0 }];
+
+    let mut signal = if in_colour_phase(colour) { high } else { low };
+    if (index & 0x040 > 0 && in_colour_phase(0x0C))
+        || (index & 0x080 > 0 && in_colour_phase(0x04))
+        || (index & 0x100 > 0 && in_colour_phase(0x08)) {
+        signal *= attenuation;
+    }
+    (signal - black)/(white - black)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PaletteSource {
+    // The one the region picks
+    Default,
+    // A .pal file of 64 or 512 colours, 3 bytes each
+    File(&'static str),
+    // Generated by decoding as NTSC, whatever the region
+    Ntsc(Ntsc),
+}
+
+impl PaletteSource {
+    pub fn load(&self, region: Region) -> io::Result<Palette> {
+        match *self {
+            PaletteSource::Default => Ok(region.default_palette()),
+            PaletteSource::File(filename) => {
+                let mut data = vec![];
+                File::open(filename)?.read_to_end(&mut data)?;
+                Palette::from_bytes(&data).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData,
+                                   format!("{} is not a 64 or 512 colour palette", filename))
+                })
+            },
+            PaletteSource::Ntsc(ntsc) => Ok(Palette::generate(ntsc)),
+        }
+    }
+}
+
+impl Palette {
+    pub fn default() -> Palette {
+        Palette::from_bytes(&DEFAULT_COLOURS).unwrap()
+    }
+
+    // 64 colour palettes get the emphasised colours by dimming the other two channels
+    pub fn from_bytes(data: &[u8]) -> Option<Palette> {
+        if data.len() != 64*3 && data.len() != 512*3 {
+            return None;
+        }
+
+        let colours: Vec<[u8; 3]> = data.chunks(3).map(|c| [c[0], c[1], c[2]]).collect();
+        if colours.len() == 512 {
+            return Some(Palette { colours: colours });
+        }
+
+        let mut emphasised = Vec::with_capacity(512);
+        for emphasis in 0..8 {
+            for colour in colours.iter() {
+                let mut rgb = [0; 3];
+                for channel in 0..3 {
+                    let mut val = colour[channel] as f64;
+                    for bit in 0..3 {
+                        if emphasis & (1<<bit) > 0 && bit != channel {
+                            val *= EMPHASIS_ATTENUATION;
+                        }
+                    }
+                    rgb[channel] = val.round() as u8;
+                }
+                emphasised.push(rgb);
+            }
+        }
+        Some(Palette { colours: emphasised })
+    }
+
+    // Decodes the composite signal the ppu makes for each colour like an ideal NTSC tv would.
+    // See https://bisqwit.iki.fi/utils/nespalette.php and
+    // https://wiki.nesdev.com/w/index.php/NTSC_video
+    pub fn generate(ntsc: Ntsc) -> Palette {
+        let mut colours = Vec::with_capacity(512);
+        for index in 0..512 {
+            // A whole cycle of the colour subcarrier
+            let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+            for phase in 0..12 {
+                let signal = composite_signal(index, phase)/12.0;
+                let angle = ntsc.carrier_angle(phase as f64);
+                y += signal;
+                i += signal*angle.cos();
+                q += signal*angle.sin();
+            }
+
+            let rgb = ntsc.yiq_to_rgb(y, i, q);
+            colours.push([ntsc.to_byte(rgb[0]), ntsc.to_byte(rgb[1]), ntsc.to_byte(rgb[2])]);
+        }
+        Palette { colours: colours }
+    }
+
+    // Decodes like an ideal PAL tv, for the 2C07 and the Dendy's ppu. They send the phase of the
+    // colour the other way round on every other line, and the tv flips V back, so an error in
+    // the phase turns the hue one way on one line and the other way on the next. Averaging
+    // the two lines, like the delay line of a PAL tv, leaves the hue right but less saturated,
+    // so the hue setting only takes saturation away.
+    // See https://wiki.nesdev.com/w/index.php/PAL_video
+    pub fn generate_pal(settings: Ntsc) -> Palette {
+        // U and V are 33 degrees round from I and Q
+        let to_uv = 33.0f64.to_radians();
+        let error = settings.hue.to_radians();
+        let mut colours = Vec::with_capacity(512);
+        for index in 0..512 {
+            let (mut y, mut u, mut v) = (0.0, 0.0, 0.0);
+            for &line_error in [error, -error].iter() {
+                for phase in 0..12 {
+                    let signal = composite_signal(index, phase)/24.0;
+                    let angle = settings.carrier_angle(phase as f64) - error + line_error - to_uv;
+                    y += signal;
+                    u += signal*angle.sin();
+                    v += signal*angle.cos();
+                }
+            }
+
+            let rgb = settings.yuv_to_rgb(y, u, v);
+            colours.push([settings.to_byte(rgb[0]), settings.to_byte(rgb[1]),
+                          settings.to_byte(rgb[2])]);
+        }
+        Palette { colours: colours }
+    }
+
+    // emphasis is bits 5-7 of PPUMASK, shifted down
+    pub fn colour(&self, colour: u8, emphasis: u8) -> [u8; 3] {
+        self.colours[((emphasis as usize)<<6) | (colour as usize & 0x3F)]
+    }
+
+    // Turns the ppu's indexed output into the colours of this palette. canvas is 256x240.
+    pub fn convert(&self, frame: &[u16], canvas: &mut NesImageBuffer) {
+        for (p, &index) in canvas.pixels_mut().zip(frame.iter()) {
+            let rgb = self.colours[index as usize & 0x1FF];
+            *p = image::Rgba([rgb[0], rgb[1], rgb[2], 0xFF]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pal_files_with_64_colours_get_emphasis() {
+        let mut data = vec![0; 64*3];
+        data[0x30*3..0x30*3 + 3].copy_from_slice(&[200, 100, 50]);
+        let palette = Palette::from_bytes(&data).unwrap();
+        assert_eq!(palette.colour(0x30, 0), [200, 100, 50]);
+        // Red emphasis dims green and blue, and red and green together dim blue twice
+        assert_eq!(palette.colour(0x30, 0b001), [200, 82, 41]);
+        assert_eq!(palette.colour(0x30, 0b011), [163, 82, 33]);
+
+        let mut data = vec![0; 512*3];
+        data[(64 + 0x30)*3] = 7;
+        assert_eq!(Palette::from_bytes(&data).unwrap().colour(0x30, 0b001), [7, 0, 0]);
+        assert!(Palette::from_bytes(&[0; 100]).is_none());
+    }
+
+    #[test]
+    fn generated_palette_has_black_white_and_hues() {
+        let palette = Palette::generate(Ntsc::default());
+        assert_eq!(palette.colour(0x0F, 0), [0, 0, 0]);
+        assert_eq!(palette.colour(0x30, 0), [255, 255, 255]);
+
+        let red = palette.colour(0x16, 0);
+        assert!(red[0] > red[1] && red[0] > red[2]);
+        let blue = palette.colour(0x12, 0);
+        assert!(blue[2] > blue[0] && blue[2] > blue[1]);
+
+        // Blue emphasis darkens a red
+        let dimmed = palette.colour(0x16, 0b100);
+        assert!(dimmed[0] < red[0]);
+    }
+
+    #[test]
+    fn pal_regions_default_to_a_pal_decoded_palette() {
+        let ntsc = Ntsc::default();
+        let pal = Palette::generate_pal(ntsc);
+        assert_eq!(pal.colour(0x0F, 0), [0, 0, 0]);
+        assert_eq!(pal.colour(0x30, 0), [255, 255, 255]);
+        let red = pal.colour(0x16, 0);
+        assert!(red[0] > red[1] && red[0] > red[2]);
+
+        // A phase error is cancelled out, whichever way it goes
+        let turned = |hue: f64| Palette::generate_pal(Ntsc { hue: hue, ..ntsc }).colours;
+        assert!(turned(20.0) == turned(-20.0));
+        assert!(turned(20.0) != pal.colours);
+        assert!(Palette::generate(Ntsc { hue: 20.0, ..ntsc }).colours
+                != Palette::generate(Ntsc { hue: -20.0, ..ntsc }).colours);
+
+        let load = |source: PaletteSource, region: Region| source.load(region).unwrap().colours;
+        assert!(load(PaletteSource::Default, Region::Ntsc) == Palette::default().colours);
+        assert!(load(PaletteSource::Default, Region::Pal) == pal.colours);
+        assert!(load(PaletteSource::Default, Region::Dendy) == pal.colours);
+        // Asking for NTSC decoding gets it on any console
+        assert!(load(PaletteSource::Ntsc(ntsc), Region::Pal) == Palette::generate(ntsc).colours);
+    }
+}
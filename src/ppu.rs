@@ -1,111 +1,61 @@
 use cpu::*;
 
+use std::mem;
 use std::cmp;
+use std::io;
 use image;
 use memory::*;
+use cdl;
+use palette::Palette;
+use region::Region;
 
 pub type NesImageBuffer = image::ImageBuffer<image::Rgba<u8>, Vec<u8>>;
 
-static VBL: u32 = 21;
-static PALETTE: [u8; 192] = [
-    124,124,124,
-    0,0,252,
-    0,0,188,
-    68,40,188,
-    148,0,132,
-    168,0,32,
-    168,16,0,
-    136,20,0,
-    80,48,0,
-    0,120,0,
-    0,104,0,
-    0,88,0,
-    0,64,88,
-    0,0,0,
-    0,0,0,
-    0,0,0,
-    188,188,188,
-    0,120,248,
-    0,88,248,
-    104,68,252,
-    216,0,204,
-    228,0,88,
-    248,56,0,
-    228,92,16,
-    172,124,0,
-    0,184,0,
-    0,168,0,
-    0,168,68,
-    0,136,136,
-    0,0,0,
-    0,0,0,
-    0,0,0,
-    248,248,248,
-    60,188,252,
-    104,136,252,
-    152,120,248,
-    248,120,248,
-    248,88,152,
-    248,120,88,
-    252,160,68,
-    248,184,0,
-    184,248,24,
-    88,216,84,
-    88,248,152,
-    0,232,216,
-    120,120,120,
-    0,0,0,
-    0,0,0,
-    252,252,252,
-    164,228,252,
-    184,184,248,
-    216,184,248,
-    248,184,248,
-    248,164,192,
-    240,208,176,
-    252,224,168,
-    248,216,120,
-    216,248,120,
-    184,248,184,
-    184,248,216,
-    0,252,252,
-    248,216,248,
-    0,0,0,
-    0,0,0
-];
-
-struct MidframeState {
-    count: u32,
-
-    nametable: u8,
-    ppuscroll_x: u8,
-    ppuscroll_y: u8,
-
-    spritetable: u8,
-    backgroundtable: u8,
-    sprite_size: u8,
+// About 600ms
+const LATCH_DECAY_FRAMES: u64 = 36;
+
+// A sprite on the scanline being drawn, with its pattern already fetched and flipped
+#[derive(Clone, Copy)]
+struct Sprite {
+    x: u8,
+    lo: u8,
+    hi: u8,
+    palette: u8,
+    behind_background: bool,
+    sprite_0: bool,
+}
 
-    greyscale: bool,
-    show_background: bool,
-    show_sprites: bool,
+// An OAM entry, decoded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpriteAttrs {
+    pub x: u8,
+    pub y: u8,
+    pub tile: u8,
+    // The top tile, with 8x16 sprites taking the one after it for the bottom half
+    pub pattern_addr: u16,
+    pub height: u16,
+    pub palette: u8,
+    pub behind_background: bool,
+    pub flip_h: bool,
+    pub flip_v: bool,
 }
 
 pub struct Ppu {
     vram: [u8; 2*1024],
     palette_rame: [u8; 32],
-    horiz_mapping: bool,
 
     oamaddr: u8,
     oam: [u8; 256],
 
-    ppuscroll_x: u8,
-    ppuscroll_y: u8,
-    ppuscroll_ppuaddr_pick: bool,
+    // Loopy's scroll registers: v is the vram address, t the address of the top left
+    // onscreen tile, x the fine x scroll, and w the write toggle shared by $2005 and $2006.
+    // During rendering v walks the nametable, and t is copied back into it every line.
+    // See https://wiki.nesdev.com/w/index.php/PPU_scrolling
+    v: u16,
+    t: u16,
+    x: u8,
+    w: bool,
 
-    ppuaddr_hi: u8,
-    ppuaddr_lo: u8,
-
-    nametable: u8, //0 = $2000; 1 = $2400; 2 = $2800; 3 = $2C00
     vram_inc: u8, //0=+1 across, 1=+32 down
     spritetable: u8, //0: $0000; 1: $1000; ignored in 8x16 mode
     backgroundtable: u8, //0: $0000; 1: $1000
@@ -113,8 +63,17 @@ pub struct Ppu {
     ppu_mss: bool,
     generate_nmi: bool,
 
-    ppu_chr_rom_delay_buffer: u8,
-    enable_ppu_chr_delay: bool,
+    // $2007 reads return what the last one fetched, except for the palette
+    read_buffer: u8,
+    // Whether the current access comes from $2007, for the code/data logger
+    ppudata_access: bool,
+
+    // The ppu's data bus holds the last value written to or read from its registers, which is
+    // what the write only registers read back. Each bit fades to 0 if it is not driven again
+    // for about 600ms, so this keeps the frame each bit was last refreshed on.
+    // See https://wiki.nesdev.com/w/index.php/PPU_registers#Ports
+    io_latch: u8,
+    latch_refreshed: [u64; 8],
 
     greyscale: bool,
     mask_left_background: bool, // 1: Show background in leftmost 8 pixels of screen, 0: Hide
@@ -128,36 +87,67 @@ pub struct Ppu {
     sprite_overflow: bool,
     sprite_0_hit: bool,
     vertical_blanking: bool,
-
-    pub output_canvas: NesImageBuffer,
-    sprite_output: [[u16; 30*8]; 32*8],
-    bg_output: [[u16; 30*8]; 32*8],
-    sprite_priority: [[bool; 30*8]; 32*8],
-    pixel_greyscale: [[bool; 30*8]; 32*8],
-    has_blanked: bool,
-
-    states: Vec<MidframeState>,
-    has_drawn_sprite0_background: bool,
+    // NMIs happen when vblank and generate_nmi are both set, but were not on the last cycle
+    nmi_line: bool,
+
+    // Scanlines 0-239 are visible, and the last one is the pre-render line. On NTSC 241-260
+    // are vblank and 261 is the pre-render line.
+    pub scanline: u32,
+    pub dot: u32,
+    odd_frame: bool,
+    // Fifths of a dot left over from the last cpu cycle, for PAL's 3.2 dots per cycle
+    dot_fraction: u32,
+    // Kept across power cycles, like sprite_limit
+    pub region: Region,
+    // Counts up at the start of every vblank
+    pub frame: u64,
+
+    // Background fetches for the next tile, and the shift registers that hold the pattern and
+    // palette bits of the current and next tiles
+    nametable_byte: u8,
+    attribute_bits: u8,
+    pattern_lo: u8,
+    pattern_hi: u8,
+    bg_lo: u16,
+    bg_hi: u16,
+    attr_lo: u16,
+    attr_hi: u16,
+
+    sprites: Vec<Sprite>,
+    // The copies of the sprites on the next line that the ppu fetches patterns for
+    secondary_oam: [u8; 32],
+    // Off draws every sprite on a line instead of the first 8, which removes flicker but not
+    // the overflow flag
+    pub sprite_limit: bool,
+
+    // The frame as 9 bit indices into the palette: the colour from palette ram, with the
+    // emphasis bits above it. Palette::convert turns it into rgb.
+    pub indexed_output: Vec<u16>,
+    // Where the NTSC colour subcarrier is, from 0 to 12. Each dot is 8 phases, so it moves by
+    // 4 every scanline, and frame_phase is where it was at the start of the last frame.
+    colour_phase: u8,
+    pub frame_phase: u8,
+    // Only used by the debug views. The frame is converted with it outside of the ppu.
+    pub palette: Palette,
+
+    // Code/data logger flags for each byte of CHR rom, empty when it is not logging
+    pub chr_log: Vec<u8>,
 }
 
 impl Ppu {
-    pub fn new(horiz_mapping: bool) -> Ppu {
+    pub fn new() -> Ppu {
         Ppu {
             vram: [0; 2 * 1024],
-            horiz_mapping: horiz_mapping,
             palette_rame: [0; 32],
 
             oamaddr: 0,
             oam: [0; 256],
 
-            ppuscroll_x: 0,
-            ppuscroll_y: 0,
-            ppuscroll_ppuaddr_pick: false,
-
-            ppuaddr_hi: 0,
-            ppuaddr_lo: 0,
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
 
-            nametable: 0,
             vram_inc: 0,
             spritetable: 0,
             backgroundtable: 0,
@@ -165,8 +155,11 @@ impl Ppu {
             ppu_mss: false,
             generate_nmi: false,
 
-            ppu_chr_rom_delay_buffer: 0,
-            enable_ppu_chr_delay: false,
+            read_buffer: 0,
+            ppudata_access: false,
+
+            io_latch: 0,
+            latch_refreshed: [0; 8],
 
             greyscale: false,
             mask_left_background: false,
@@ -180,79 +173,167 @@ impl Ppu {
             sprite_overflow: false,
             sprite_0_hit: false,
             vertical_blanking: false,
-
-            output_canvas: make_canvas(32 * 8, 30 * 8),
-            sprite_output: [[0; 30*8]; 32*8],
-            bg_output: [[0; 30*8]; 32*8],
-            sprite_priority: [[false; 30*8]; 32*8],
-            pixel_greyscale: [[false; 30*8]; 32*8],
-            has_blanked: false,
-
-            states: vec![],
-            has_drawn_sprite0_background: false,
+            nmi_line: false,
+
+            scanline: 0,
+            dot: 0,
+            odd_frame: false,
+            dot_fraction: 0,
+            region: Region::Ntsc,
+            frame: 0,
+
+            nametable_byte: 0,
+            attribute_bits: 0,
+            pattern_lo: 0,
+            pattern_hi: 0,
+            bg_lo: 0,
+            bg_hi: 0,
+            attr_lo: 0,
+            attr_hi: 0,
+
+            sprites: Vec::with_capacity(64),
+            secondary_oam: [0xFF; 32],
+            sprite_limit: true,
+
+            indexed_output: vec![0; 256*240],
+            colour_phase: 0,
+            frame_phase: 0,
+            palette: Palette::default(),
+
+            chr_log: vec![],
         }
     }
 
-    fn make_midframe_state(&self, count: u32) -> MidframeState {
-        MidframeState {
-            count: count,
-            nametable: self.nametable,
-            ppuscroll_x: self.ppuscroll_x,
-            ppuscroll_y: self.ppuscroll_y,
-            spritetable: self.spritetable,
-            backgroundtable: self.backgroundtable,
-            sprite_size: self.sprite_size,
-            greyscale: self.greyscale,
-            show_background: self.show_background,
-            show_sprites: self.show_sprites,
-        }
+    // The registers, for crash reports
+    pub fn state(&self) -> String {
+        format!("PPUCTRL: increment {} sprites {} background {} size {} nmi {}\n\
+                 PPUMASK: greyscale {} left bg {} left sprites {} bg {} sprites {} emphasis {}{}{}\n\
+                 PPUSTATUS: vblank {} sprite 0 {} overflow {}\n\
+                 OAMADDR: {:02X}  v: {:04X}  t: {:04X}  x: {}  w: {}  read buffer: {:02X}\n\
+                 scanline {} dot {} frame {}  open bus: {:02X}",
+                self.vram_inc, self.spritetable, self.backgroundtable, self.sprite_size,
+                self.generate_nmi, self.greyscale, self.mask_left_background,
+                self.mask_left_sprites, self.show_background, self.show_sprites,
+                self.em_red as u8, self.em_green as u8, self.em_blue as u8,
+                self.vertical_blanking, self.sprite_0_hit, self.sprite_overflow, self.oamaddr,
+                self.v, self.t, self.x, self.w, self.read_buffer, self.scanline,
+                self.dot, self.frame, self.io_latch)
     }
 
-    pub fn push_state(&mut self, cpu: &Cpu) {
-        let state = self.make_midframe_state(cpu.count);
-        self.states.push(state);
+    // Everything but the code/data log and the display settings is lost when the power is off
+    pub fn power_cycle(&mut self) {
+        let chr_log = mem::replace(&mut self.chr_log, vec![]);
+        let palette = mem::replace(&mut self.palette, Palette::default());
+        *self = Ppu {
+            chr_log: chr_log,
+            palette: palette,
+            sprite_limit: self.sprite_limit,
+            region: self.region,
+            ..Ppu::new()
+        };
+    }
+
+    // The reset button clears PPUCTRL, PPUMASK, the scroll and the read buffer. The rest,
+    // including vblank and OAM, is untouched.
+    pub fn reset(&mut self, mapper: &mut Box<Mapper>) {
+        self.write_main(mapper, 0x2000, 0);
+        self.write_main(mapper, 0x2001, 0);
+        self.w = false;
+        self.write_main(mapper, 0x2005, 0);
+        self.write_main(mapper, 0x2005, 0);
+        self.read_buffer = 0;
     }
 
     pub fn read_main(&mut self, mapper: &mut Box<Mapper>, addr: u16) -> u8 {
+        self.decay_latch();
         match addr as usize {
             0x2002 => {
                 let blanking = self.vertical_blanking;
                 self.vertical_blanking = false;
-                self.ppuscroll_ppuaddr_pick = false;
+                self.w = false;
 
-                ((blanking as u8)<<7)
+                let status = ((blanking as u8)<<7)
                     + ((self.sprite_0_hit as u8)<<6)
-                    + ((self.sprite_overflow as u8)<<5)
+                    + ((self.sprite_overflow as u8)<<5);
+                self.refresh_latch(status, 0b11100000);
+            },
+            0x2004 => {
+                let val = self.read_oam();
+                self.refresh_latch(val, 0xFF);
             },
-            0x2003 => self.oam[self.oamaddr as usize],
             0x2007 => {
-                let addr = ((self.ppuaddr_lo as u16)&0x00FF)
-                    + (((self.ppuaddr_hi as u16)&0xFF)<<8);
-
-                self.enable_ppu_chr_delay = true;
-                let val = self.read(mapper, addr);
-                self.enable_ppu_chr_delay = false;
+                let addr = self.v & 0x3FFF;
+                self.ppudata_access = true;
+                if addr >= 0x3F00 {
+                    // Palette reads are not buffered, but still put the nametable byte under
+                    // the palette in the buffer. The top two bits are open bus.
+                    self.read_buffer = self.read(mapper, addr - 0x1000);
+                    let mask = if self.greyscale { 0x30 } else { 0x3F };
+                    let val = self.read(mapper, addr) & mask;
+                    self.refresh_latch(val, 0b00111111);
+                } else {
+                    let val = self.read_buffer;
+                    self.read_buffer = self.read(mapper, addr);
+                    self.refresh_latch(val, 0xFF);
+                }
+                self.ppudata_access = false;
 
                 self.increment_ppuaddr();
-                val
             },
-            _ => {
-                panic!("Read from invalid main address {:X}", addr);
+            _ => ()
+        }
+        self.io_latch
+    }
+
+    // During rendering, $2004 shows what sprite evaluation and the sprite fetches are reading
+    fn read_oam(&self) -> u8 {
+        let dot = self.dot as usize;
+        if !self.rendering() || self.scanline >= 240 {
+            // The unused bits of the attribute byte do not exist
+            return if self.oamaddr & 3 == 2 {
+                self.oam[self.oamaddr as usize] & 0b11100011
+            } else {
+                self.oam[self.oamaddr as usize]
+            };
+        }
+
+        match dot {
+            // Secondary OAM is being cleared
+            1 ... 64 => 0xFF,
+            65 ... 256 => self.oam[self.oamaddr as usize],
+            257 ... 320 => self.secondary_oam[(dot - 257)/8*4 + cmp::min((dot - 257)%8, 3)],
+            _ => self.secondary_oam[0]
+        }
+    }
+
+    fn refresh_latch(&mut self, val: u8, bits: u8) {
+        self.io_latch = (self.io_latch & !bits) | (val & bits);
+        for bit in 0..8 {
+            if bits & (1<<bit) > 0 {
+                self.latch_refreshed[bit] = self.frame;
             }
         }
     }
 
-    pub fn write_main(&mut self, mapper: &mut Box<Mapper>, addr: u16, val: u8, cpu: &Cpu) {
+    fn decay_latch(&mut self) {
+        for bit in 0..8 {
+            if self.frame - self.latch_refreshed[bit] >= LATCH_DECAY_FRAMES {
+                self.io_latch &= !(1<<bit);
+            }
+        }
+    }
+
+    pub fn write_main(&mut self, mapper: &mut Box<Mapper>, addr: u16, val: u8) {
+        self.refresh_latch(val, 0xFF);
         match addr as usize {
             0x2000 => {
-                self.nametable              = val&0b00000011;
+                self.t = (self.t & !0x0C00) | (((val&0b00000011) as u16)<<10);
                 self.vram_inc               = (val&0b00000100)>>2;
                 self.spritetable            = (val&0b00001000)>>3;
                 self.backgroundtable        = (val&0b00010000)>>4;
                 self.sprite_size            = (val&0b00100000)>>5;
                 self.ppu_mss                = val&0b01000000>0;
                 self.generate_nmi           = val&0b10000000>0;
-                self.push_state(cpu);
             }
             0x2001 => {
                 self.greyscale              = val&0b00000001>0;
@@ -263,7 +344,6 @@ impl Ppu {
                 self.em_red                 = val&0b00100000>0;
                 self.em_green               = val&0b01000000>0;
                 self.em_blue                = val&0b10000000>0;
-                self.push_state(cpu);
             }
             0x2003 => self.oamaddr = val,
             0x2004 => {
@@ -271,384 +351,576 @@ impl Ppu {
                 self.oamaddr = self.oamaddr.wrapping_add(1);
             },
             0x2005 => {
-                if self.ppuscroll_ppuaddr_pick {
-                    self.ppuscroll_y = val;
+                if self.w {
+                    // Fine and coarse y
+                    self.t = (self.t & !0x73E0) | (((val&0b00000111) as u16)<<12)
+                        | (((val&0b11111000) as u16)<<2);
                 }
                 else {
-                    self.ppuscroll_x = val;
-                    self.push_state(cpu);
+                    self.t = (self.t & !0x001F) | ((val>>3) as u16);
+                    self.x = val&0b00000111;
                 }
-                self.ppuscroll_ppuaddr_pick = !self.ppuscroll_ppuaddr_pick;
+                self.w = !self.w;
             },
             0x2006 => {
-                if self.ppuscroll_ppuaddr_pick {
-                    self.ppuaddr_lo = val;
-
-                    // This is a hack so that I don't have to do full scanline emulation
-                    let nametable = (self.ppuaddr_hi&0b00001100)>>2;
-                    if nametable != self.nametable {
-                        self.nametable = nametable;
-                        self.push_state(cpu);
-                    }
+                if self.w {
+                    self.t = (self.t & 0xFF00) | val as u16;
+                    self.v = self.t;
                 }
                 else {
-                    self.ppuaddr_hi = val;
+                    self.t = (self.t & 0x00FF) | (((val&0b00111111) as u16)<<8);
                 }
-                self.ppuscroll_ppuaddr_pick = !self.ppuscroll_ppuaddr_pick;
+                self.w = !self.w;
             },
             0x2007 => {
-                let addr = ((self.ppuaddr_lo as u16)&0x00FF)
-                    + (((self.ppuaddr_hi as u16)&0xFF)<<8);
+                let addr = self.v & 0x3FFF;
                 self.write(mapper, addr, val);
                 self.increment_ppuaddr()
             },
-            _ => {
-                panic!("Write to invalid main address {:X}", addr);
-            }
+            _ => () // $2002 is read only
         }
     }
 
-    pub fn ppudma(&mut self, mapper: &mut Box<Mapper>, val: u8, cpu: &mut Cpu, mem: &mut Mem) {
-        cpu.count += 1;
-        cpu.count += cpu.count%2;
-        cpu.count += 512;
+    fn rendering(&self) -> bool {
+        self.show_background || self.show_sprites
+    }
 
-        let addr = ((val as u16)&0x00FF)<<8;
-        for i in 0...255 {
-            self.oam[self.oamaddr.wrapping_add(i) as usize]
-                = mem.read(mapper, addr + i as u16);
-        }
+    fn pre_render(&self) -> bool {
+        self.scanline == self.region.scanlines() - 1
     }
 
+    // The emphasis bits as the palette has them: red, green, blue
+    fn emphasis(&self) -> u8 {
+        let (red, green) = if self.region.swaps_emphasis() {
+            (self.em_green, self.em_red)
+        } else {
+            (self.em_red, self.em_green)
+        };
+        (red as u8) | ((green as u8)<<1) | ((self.em_blue as u8)<<2)
+    }
+
+    // Runs the dots that happen during one cpu cycle, 3 of them or 3.2 on PAL
     pub fn tick(&mut self, cpu: &mut Cpu, mapper: &mut Box<Mapper>) {
-        let y = cpu.count*3/341;
+        let (dots, cycles) = self.region.dots_per_cycle();
+        self.dot_fraction += dots;
+        while self.dot_fraction >= cycles {
+            self.dot_fraction -= cycles;
+            self.step(mapper);
+        }
 
-        if y < VBL && !self.has_blanked {
-            self.has_blanked = true;
-            self.vertical_blanking = true;
-            self.oamaddr = 0;
+        let nmi = self.vertical_blanking && self.generate_nmi;
+        if nmi && !self.nmi_line {
+            cpu.nmi();
+        }
+        self.nmi_line = nmi;
+    }
+
+    // See https://wiki.nesdev.com/w/index.php/PPU_rendering for what happens on each dot
+    fn step(&mut self, mapper: &mut Box<Mapper>) {
+        let visible = self.scanline < 240;
+        let pre_render = self.pre_render();
+        let dot = self.dot;
+
+        if self.rendering() && (visible || pre_render) {
+            if (dot >= 2 && dot <= 257) || (dot >= 322 && dot <= 337) {
+                self.shift_background();
+                match dot % 8 {
+                    1 => {
+                        self.load_background();
+                        self.fetch_nametable(mapper);
+                    },
+                    3 => self.fetch_attribute(mapper),
+                    5 => self.fetch_pattern(mapper, 0),
+                    7 => self.fetch_pattern(mapper, 8),
+                    0 => self.increment_x(),
+                    _ => ()
+                }
+            } else if dot == 1 || dot == 321 {
+                self.fetch_nametable(mapper);
+            }
 
-            if self.generate_nmi {
-                cpu.nmi();
+            if dot == 256 {
+                self.increment_y();
+            }
+            if dot == 257 {
+                // Horizontal position
+                self.v = (self.v & !0x041F) | (self.t & 0x041F);
+                self.oamaddr = 0;
+                if visible {
+                    self.evaluate_sprites(mapper);
+                } else {
+                    self.sprites.clear();
+                    self.fetch_empty_sprites(mapper);
+                }
+            }
+            if pre_render && dot >= 280 && dot <= 304 {
+                // Vertical position
+                self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
             }
         }
 
-        if y >= VBL && self.has_blanked {
-            self.has_blanked = false;
+        if visible && dot >= 1 && dot <= 256 {
+            self.render_pixel(mapper);
+        }
+
+        if self.scanline == self.region.vblank_line() && dot == 1 {
+            self.vertical_blanking = true;
+            self.frame += 1;
+        }
+        // Flags set on a dot are seen by reads from the next dot, but the ones cleared at the
+        // start of dot 1 of the pre-render line are already clear for reads on that dot
+        if pre_render && dot == 0 {
             self.vertical_blanking = false;
-            self.has_drawn_sprite0_background = false;
             self.sprite_0_hit = false;
-
-            self.states.clear();
-            self.push_state(cpu);
+            self.sprite_overflow = false;
         }
 
-        let sprite_0_y = self.oam[self.oamaddr as usize] as u32 + 1;
-        if self.show_sprites && self.show_background && !self.sprite_0_hit &&
-                y >= sprite_0_y + VBL + 1 && y < sprite_0_y + VBL + 1 + 8 {
-            let idx = self.states.len()-1;
-            let (sprite_0_x,_,_, pattern_addr, _, _, _, _) = self.get_sprite_attrs(0, idx);
-
-            if !self.has_drawn_sprite0_background {
-                self.has_drawn_sprite0_background = true;
-
-                // This could be more efficient, but what the hell
-                self.show_sprites = false;
-                self.draw_with_state(idx, sprite_0_y as u16, sprite_0_y as u16 + 8, mapper);
-                self.show_sprites = true;
+        // Odd frames are one dot shorter while rendering
+        if pre_render && dot == 339 && self.odd_frame && self.rendering()
+            && self.region.skips_odd_dot() {
+            self.dot += 1;
+            self.colour_phase = (self.colour_phase + 4) % 12;
+        }
+        self.dot += 1;
+        if self.dot > 340 {
+            self.dot = 0;
+            self.scanline += 1;
+            self.colour_phase = (self.colour_phase + 4) % 12;
+            if self.scanline >= self.region.scanlines() {
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+                self.frame_phase = self.colour_phase;
             }
+        }
+    }
 
-            let py = y as u16 - sprite_0_y as u16  - VBL as u16 - 1;
+    fn fetch_nametable(&mut self, mapper: &mut Box<Mapper>) {
+        let addr = 0x2000 | (self.v & 0x0FFF);
+        self.nametable_byte = self.read(mapper, addr);
+    }
 
-            // Who cares about 16px sprites!
-            let lo = self.read(mapper, pattern_addr + py);
-            let hi = self.read(mapper, pattern_addr + py + 8);
+    fn fetch_attribute(&mut self, mapper: &mut Box<Mapper>) {
+        let v = self.v;
+        let addr = 0x23C0 | (v & 0x0C00) | ((v>>4) & 0x38) | ((v>>2) & 0x07);
+        // Each byte covers 4x4 tiles, with two bits for each 2x2 quarter
+        let shift = ((v>>4) & 0b100) | (v & 0b10);
+        self.attribute_bits = (self.read(mapper, addr)>>shift) & 0b11;
+    }
 
-            for px in 0..8 {
-                let mask = 0b00000001<<(7-px);
-                let solid = (((lo&mask)>>(7-px))
-                    + (((hi&mask)>>(7-px))<<1)) != 0;
+    fn fetch_pattern(&mut self, mapper: &mut Box<Mapper>, plane: u16) {
+        let fine_y = (self.v>>12) & 0b111;
+        let addr = (self.backgroundtable as u16)*0x1000 + (self.nametable_byte as u16)*16
+            + fine_y + plane;
+        let val = self.read(mapper, addr);
+        if plane == 0 {
+            self.pattern_lo = val;
+        } else {
+            self.pattern_hi = val;
+        }
+    }
 
-                if !solid { continue; }
-                if self.bg_output[sprite_0_x as usize + px as usize]
-                        [sprite_0_y as usize + py as usize]&0b00000011 == 0 {
-                    continue;
-                }
+    // The fetched tile goes into the low half of the shift registers, behind the one that is
+    // being drawn
+    fn load_background(&mut self) {
+        self.bg_lo = (self.bg_lo & 0xFF00) | self.pattern_lo as u16;
+        self.bg_hi = (self.bg_hi & 0xFF00) | self.pattern_hi as u16;
+        self.attr_lo = (self.attr_lo & 0xFF00) | if self.attribute_bits & 0b01 > 0 { 0xFF } else { 0 };
+        self.attr_hi = (self.attr_hi & 0xFF00) | if self.attribute_bits & 0b10 > 0 { 0xFF } else { 0 };
+    }
 
-                self.sprite_0_hit = true;
-                break;
-            }
+    fn shift_background(&mut self) {
+        self.bg_lo <<= 1;
+        self.bg_hi <<= 1;
+        self.attr_lo <<= 1;
+        self.attr_hi <<= 1;
+    }
+
+    // Coarse x, wrapping into the next horizontal nametable
+    fn increment_x(&mut self) {
+        if self.v & 0x001F == 31 {
+            self.v = (self.v & !0x001F) ^ 0x0400;
+        } else {
+            self.v += 1;
         }
     }
 
-    fn draw_tile(&mut self, state_idx: usize, nametable: u8, tile_x: u16, tile_y: u16,
-            screen_x_start: u16, screen_y_start: u16, screen_x_end: u16, screen_y_end: u16,
-            x_offset: u16, y_offset: u16, mapper: &mut Box<Mapper>) {
-        let nametable = match nametable {
-            0 => 0x2000,
-            1 => 0x2400,
-            2 => 0x2800,
-            3 => 0x2C00,
-            _ => panic!("Name table {} not recognized", nametable)
-        };
+    // Fine y, then coarse y, wrapping into the next vertical nametable after row 29
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
 
-        let bg_pattern = match self.states[state_idx].backgroundtable {
-            0 => 0x0000,
-            1 => 0x1000,
-            _ => panic!("Background table {} not recognized", self.states[state_idx].backgroundtable)
-        };
+        self.v &= !0x7000;
+        let mut y = (self.v & 0x03E0)>>5;
+        if y == 29 {
+            y = 0;
+            self.v ^= 0x0800;
+        } else if y == 31 {
+            // Rows 30 and 31 are the attribute table, and wrap without switching nametables
+            y = 0;
+        } else {
+            y += 1;
+        }
+        self.v = (self.v & !0x03E0) | (y<<5);
+    }
 
-        let pattern_number = self.read(mapper, nametable + tile_x + 32*tile_y);
-
-        let attr_x = tile_x/4;
-        let attr_y = tile_y/4;
-        let over_x = (tile_x/2)%2;
-        let over_y = (tile_y/2)%2;
-        let attr = self.read(mapper, nametable + 0x3C0 + attr_x + 8*attr_y);
-        let mask = 0b00000011 << (4*over_y + 2*over_x);
-        let colour_bits = ((attr&(mask)) >> (4*over_y + 2*over_x))<<2;
-
-        let pattern_addr = bg_pattern as u16 + 16*pattern_number as u16;
-        for y in y_offset...(screen_y_end-screen_y_start+y_offset) {
-            let lo = self.read(mapper, pattern_addr + y);
-            let hi = self.read(mapper, pattern_addr + y + 8);
-
-            for x in x_offset...(screen_x_end-screen_x_start+x_offset) {
-                let mask = 0b00000001<<(7-x);
-                let mut palette_idx = ((lo&mask)>>(7-x)) as u16
-                    + (((hi&mask)>>(7-x))<<1) as u16;
-                if palette_idx != 0 {
-                    palette_idx += colour_bits as u16;
-                }
+    // Copies the first 8 sprites on the next line into secondary OAM, like the hardware, and
+    // fetches their patterns. Without the sprite limit the rest are drawn as well.
+    // See https://wiki.nesdev.com/w/index.php/PPU_sprite_evaluation
+    fn evaluate_sprites(&mut self, mapper: &mut Box<Mapper>) {
+        self.sprites.clear();
+        self.secondary_oam = [0xFF; 32];
+        let mut n = 0;
+        while n < 64 && self.sprites.len() < 8 {
+            if self.sprite_in_range(self.oam[4*n]) {
+                let found = self.sprites.len();
+                self.secondary_oam[4*found..4*found + 4].copy_from_slice(&self.oam[4*n..4*n + 4]);
+                self.fetch_sprite(mapper, n);
+            }
+            n += 1;
+        }
+        let rest = n;
+        self.fetch_empty_sprites(mapper);
+
+        // Once secondary OAM is full the ppu keeps looking for a ninth sprite, but it also
+        // steps through the bytes of each entry, so it compares tiles, attributes and x
+        // positions against the scanline instead of y. This misses real overflows and finds
+        // false ones.
+        let mut m = 0;
+        while n < 64 {
+            if self.sprite_in_range(self.oam[4*n + m]) {
+                self.sprite_overflow = true;
+                break;
+            }
+            n += 1;
+            m = (m + 1) % 4;
+        }
 
-                if self.states[state_idx].show_background {
-                    self.bg_output[(x+screen_x_start-x_offset) as usize][(y+screen_y_start-y_offset) as usize]
-                        = 0x3F00 + palette_idx;
+        if !self.sprite_limit {
+            for n in rest..64 {
+                if self.sprite_in_range(self.oam[4*n]) {
+                    self.fetch_sprite(mapper, n);
                 }
-
-                self.pixel_greyscale[(x+screen_x_start-x_offset) as usize][(y+screen_y_start-y_offset) as usize]
-                    = self.states[state_idx].greyscale;
             }
         }
     }
 
-    fn get_sprite_attrs(&self, s: u8, state_idx: usize) -> (u8, u16, u8, u16, u16, bool, bool, bool) {
-        let state = &self.states[state_idx];
-
-        let y = self.oam[self.oamaddr.wrapping_add(4*s) as usize] as u16 + 1;
-
-        let (height, table, idx) = if state.sprite_size == 0 {
-            (8, state.spritetable, self.oam[self.oamaddr.wrapping_add(4*s + 1) as usize])
-        } else if state.sprite_size == 1 {
-            let val = self.oam[self.oamaddr.wrapping_add(4*s + 1) as usize];
-            (16, val&0b00000001, val&0b11111110)
-        } else { panic!() };
+    // Sprites are drawn one line below their y
+    fn sprite_in_range(&self, y: u8) -> bool {
+        let height = if self.sprite_size == 0 { 8 } else { 16 };
+        self.scanline.wrapping_sub(y as u32) < height
+    }
 
-        let pattern_base = match table {
-            0 => 0x0000,
-            1 => 0x1000,
-            _ => panic!()
-        };
-        let pattern_addr = pattern_base + 16*idx as u16;
-
-        let flags = self.oam[self.oamaddr.wrapping_add(4*s + 2) as usize];
-        let palette = 0x3F10 + (((flags&0b00000011) as u16)<<2);
-        let priority = flags&0b00100000==0;
-        let fh = flags&0b01000000>0;
-        let fv = flags&0b10000000>0;
-
-        let x = self.oam[self.oamaddr.wrapping_add(4*s + 3) as usize];
-
-        (x, y, height, pattern_addr, palette, priority, fh, fv)
-    }
-
-    fn draw_with_state(&mut self, state_idx: usize, state_start_y: u16, state_end_y: u16,
-                       mapper: &mut Box<Mapper>) {
-        let sx = self.states[state_idx].ppuscroll_x as u16;
-        let sy = self.states[state_idx].ppuscroll_y as u16;
-        let base_nt = self.states[state_idx].nametable;
-        let (base_nt_x, base_nt_y) = match base_nt {
-            0 => (0,0),
-            1 => (1,0),
-            2 => (0,1),
-            3 => (1,1),
-            _ => panic!()
+    pub fn get_sprite_attrs(&self, n: usize) -> SpriteAttrs {
+        let tile = self.oam[4*n + 1];
+        let flags = self.oam[4*n + 2];
+        let (height, pattern_addr) = if self.sprite_size == 0 {
+            (8, (self.spritetable as u16)*0x1000 + (tile as u16)*16)
+        } else {
+            // 8x16 sprites take the table from bit 0
+            (16, ((tile&1) as u16)*0x1000 + ((tile&0b11111110) as u16)*16)
         };
 
-        for screen_x in 0..33 {
-            for screen_y in (state_start_y/8)...(state_end_y/8+1) {
-                let x_nt = ((sx / 8 + screen_x + 32 * base_nt_x) % 64) / 32;
-                let y_nt = ((sy / 8 + screen_y + 30 * base_nt_y) % 60) / 30;
+        SpriteAttrs {
+            x: self.oam[4*n + 3],
+            y: self.oam[4*n],
+            tile: tile,
+            pattern_addr: pattern_addr,
+            height: height,
+            palette: flags&0b00000011,
+            behind_background: flags&0b00100000 > 0,
+            flip_h: flags&0b01000000 > 0,
+            flip_v: flags&0b10000000 > 0,
+        }
+    }
+
+    // Slots without a sprite fetch tile $FF and throw it away. Nothing is read here, but
+    // mappers that count scanlines by watching the address bus need to see the fetches.
+    fn fetch_empty_sprites(&mut self, mapper: &mut Box<Mapper>) {
+        let addr = if self.sprite_size == 0 { (self.spritetable as u16)*0x1000 + 0x0FF0 } else { 0x1FF0 };
+        for _ in self.sprites.len()..8 {
+            mapper.ppu_access(addr);
+            mapper.ppu_access(addr + 8);
+        }
+    }
 
-                let n = match (x_nt, y_nt) {
-                    (0, 0) => 0,
-                    (1, 0) => 1,
-                    (0, 1) => 2,
-                    (1, 1) => 3,
-                    _ => panic!()
-                };
+    fn fetch_sprite(&mut self, mapper: &mut Box<Mapper>, n: usize) {
+        let attrs = self.get_sprite_attrs(n);
+        let row = self.scanline.wrapping_sub(attrs.y as u32) as u16;
+        let row = if attrs.flip_v { attrs.height - 1 - row } else { row };
+        let pattern_addr = attrs.pattern_addr + (row/8)*16 + row%8;
+
+        let mut lo = self.read(mapper, pattern_addr);
+        let mut hi = self.read(mapper, pattern_addr + 8);
+        if attrs.flip_h {
+            lo = reverse_bits(lo);
+            hi = reverse_bits(hi);
+        }
 
-                let tile_x = ((sx / 8 + screen_x + 32 * base_nt_x) % 64) % 32;
-                let tile_y = ((sy / 8 + screen_y + 30 * base_nt_y) % 60) % 30;
+        self.sprites.push(Sprite {
+            x: attrs.x,
+            lo: lo,
+            hi: hi,
+            palette: attrs.palette,
+            behind_background: attrs.behind_background,
+            sprite_0: n == 0,
+        });
+    }
 
-                let (start_x, off_x) = if screen_x == 0 { (0, sx % 8) } else { (screen_x * 8 - sx % 8, 0) };
-                let (start_y, off_y) = if screen_y*8 + 8 - sy%8 <= state_start_y {
-                    continue;
-                } else if screen_y*8 <= state_start_y + sy%8 {
-                    (state_start_y, state_start_y + sy%8 - screen_y*8)
-                } else {
-                    (screen_y*8 - sy%8, 0)
-                };
-                let end_x = cmp::min(screen_x*8 + 8 - sx%8 - 1, self.output_canvas.width() as u16 - 1);
-                let end_y = cmp::min(screen_y*8 + 8 - sy%8 - 1, state_end_y);
+    fn render_pixel(&mut self, mapper: &mut Box<Mapper>) {
+        let x = (self.dot - 1) as u8;
+
+        // PPUMASK can hide either layer in the leftmost 8 pixels
+        let left = x < 8;
+        let mut bg = 0;
+        if self.show_background && (!left || self.mask_left_background) {
+            let bit = 15 - self.x as u16;
+            let pattern = ((self.bg_lo>>bit) & 1) | (((self.bg_hi>>bit) & 1)<<1);
+            if pattern != 0 {
+                let attr = ((self.attr_lo>>bit) & 1) | (((self.attr_hi>>bit) & 1)<<1);
+                bg = ((attr<<2) | pattern) as u8;
+            }
+        }
 
-                if start_x > end_x || start_y > end_y {
+        // The first sprite with a solid pixel here wins, even if it is behind the background
+        let mut sprite = None;
+        if self.show_sprites && (!left || self.mask_left_sprites) {
+            for s in self.sprites.iter() {
+                if x < s.x || x - s.x >= 8 {
                     continue;
                 }
+                let bit = 7 - (x - s.x);
+                let pattern = ((s.lo>>bit) & 1) | (((s.hi>>bit) & 1)<<1);
+                if pattern != 0 {
+                    sprite = Some(((s.palette<<2) | pattern, s.behind_background, s.sprite_0));
+                    break;
+                }
+            }
+        }
 
-                self.draw_tile(state_idx, n, tile_x, tile_y, start_x, start_y, end_x, end_y,
-                          off_x, off_y, mapper);
+        if let Some((_, _, true)) = sprite {
+            if bg != 0 && x != 255 {
+                self.sprite_0_hit = true;
             }
         }
 
-        for s in 0..64 {
-            let (x,  y, height, pattern_addr, palette, priority, fh, fv) = self.get_sprite_attrs(s, state_idx);
-            if y >= 0xF0 { continue; }
-            if y <= 2 { continue; }
-            if y > state_end_y { continue; }
-            if y + (height as u16) < state_start_y { continue; }
+        let addr = match sprite {
+            Some((colour, behind, _)) if !(behind && bg != 0) => 0x3F10 + colour as u16,
+            _ => 0x3F00 + bg as u16
+        };
+        let mask = if self.greyscale { 0x30 } else { 0x3F };
+        let colour = self.read(mapper, addr) & mask;
+        self.indexed_output[self.scanline as usize*256 + x as usize] =
+            ((self.emphasis() as u16)<<6) | colour as u16;
+    }
 
-            for i in 0..(height/8) {
-                for py in 0..8 {
-                    let lo = self.read(mapper, pattern_addr + 16*i as u16 + py);
-                    let hi = self.read(mapper, pattern_addr + 16*i as u16 + py + 8);
+    // Outside of rendering this steps by 1 or 32. During rendering, accessing $2007 bumps
+    // both the coarse x and y scroll instead.
+    pub fn increment_ppuaddr(&mut self) {
+        if self.rendering() && (self.scanline < 240 || self.pre_render()) {
+            self.increment_x();
+            self.increment_y();
+        } else {
+            self.v = self.v.wrapping_add(if self.vram_inc==0 { 1 } else { 32 }) & 0x7FFF;
+        }
+    }
+}
 
-                    for px in 0..8 {
-                        let real_x = if !fh {
-                            x as u32 + px as u32
-                        } else {
-                            x as u32 + 7 - px as u32
-                        };
-                        let real_y = if !fv {
-                            y as u32 + (8 * i as u32 + py as u32)
-                        } else {
-                            y as u32 + (8 * (height/8) as u32 - 1) - (8 * i as u32 + py as u32)
-                        };
+// Views of the ppu's memory for debugging, which do not touch the code/data log
+impl Ppu {
+    // Pattern tables go straight to the mapper so that they are not logged as rendered
+    fn peek(&mut self, mapper: &mut Box<Mapper>, addr: u16) -> u8 {
+        if addr < 0x2000 {
+            mapper.read_ppu(addr)
+        } else {
+            self.read(mapper, addr)
+        }
+    }
 
-                        if real_x >= self.output_canvas.width()
-                            || real_y > state_end_y as u32
-                            || real_y < state_start_y as u32 {
-                            continue;
-                        }
-
-                        if self.sprite_output[real_x as usize][real_y as usize] != 0 {
-                            continue;
-                        }
-
-                        let mask = 0b00000001 << (7 - px);
-                        let palette_idx = ((lo & mask) >> (7 - px))
-                            + (((hi & mask) >> (7 - px)) << 1);
-                        if palette_idx == 0 {
-                            continue;
-                        }
-
-                        if self.states[state_idx].show_sprites {
-                            self.sprite_output[real_x as usize][real_y as usize]
-                                = palette_idx as u16 + palette;
-                            self.sprite_priority[real_x as usize][real_y as usize] = priority;
-                        }
-                    }
-                }
+    // index is into palette ram, 0-31
+    fn palette_rgba(&self, index: u8) -> image::Rgba<u8> {
+        // $3F10, $3F14, $3F18 and $3F1C are the same as $3F00, $3F04, $3F08 and $3F0C
+        let index = if index & 0b10011 == 0b10000 { index & 0x0F } else { index & 0x1F };
+        let emphasis = self.emphasis();
+        let rgb = self.palette.colour(self.palette_rame[index as usize], emphasis);
+        image::Rgba([rgb[0], rgb[1], rgb[2], 0xFF])
+    }
+
+    // Draws the 8x8 tile at pattern_addr with its top left at (x, y). palette is 0-3 for the
+    // background palettes and 4-7 for the sprite ones.
+    fn draw_tile(&mut self, mapper: &mut Box<Mapper>, canvas: &mut NesImageBuffer,
+                 pattern_addr: u16, palette: u8, x: u32, y: u32, flip_h: bool, flip_v: bool) {
+        for row in 0..8 {
+            let lo = self.peek(mapper, pattern_addr + row);
+            let hi = self.peek(mapper, pattern_addr + row + 8);
+            let py = y + if flip_v { 7 - row as u32 } else { row as u32 };
+
+            for col in 0..8 {
+                let bit = 7 - col;
+                let colour = ((lo>>bit) & 1) | (((hi>>bit) & 1)<<1);
+                let index = if colour == 0 { 0 } else { (palette<<2) | colour };
+                let px = x + if flip_h { 7 - col as u32 } else { col as u32 };
+                canvas.put_pixel(px, py, self.palette_rgba(index));
             }
         }
     }
 
-    pub fn prepare_draw(&mut self, mapper: &mut Box<Mapper>) {
-        for x in 0..self.output_canvas.width() {
-            for y in 0..self.output_canvas.height() {
-                self.sprite_output[x as usize][y as usize] = 0;
-                self.bg_output[x as usize][y as usize] = 0;
-                self.sprite_priority[x as usize][y as usize] = false;
+    // All four nametables in a 512x480 image, with the scroll position the next frame will
+    // start at outlined
+    pub fn draw_nametables(&mut self, mapper: &mut Box<Mapper>) -> NesImageBuffer {
+        let mut canvas = make_canvas(512, 480);
+        let table = (self.backgroundtable as u16)*0x1000;
+
+        for nametable in 0..4 {
+            let base = 0x2000 + nametable*0x400;
+            let (left, top) = ((nametable as u32 % 2)*256, (nametable as u32 / 2)*240);
+            for tile_y in 0..30 {
+                for tile_x in 0..32 {
+                    let tile = self.peek(mapper, base + tile_y*32 + tile_x);
+                    let attr = self.peek(mapper, base + 0x3C0 + (tile_y/4)*8 + tile_x/4);
+                    let shift = ((tile_y/2)%2)*4 + ((tile_x/2)%2)*2;
+                    let palette = (attr>>shift) & 0b11;
+                    self.draw_tile(mapper, &mut canvas, table + (tile as u16)*16, palette,
+                                   left + tile_x as u32*8, top + tile_y as u32*8, false, false);
+                }
             }
         }
 
-        for i in 0..self.states.len() {
-            let start_y = (self.states[i].count*3/341) as u16 - VBL as u16;
-            let end_y = if i < self.states.len()-1 {
-                cmp::min(cmp::max((self.states[i+1].count*3/341) as u16 - VBL as u16, 1),
-                         self.output_canvas.height() as u16)
-            } else {
-                self.output_canvas.height() as u16
+        let t = self.t as u32;
+        let scroll_x = ((t>>10) & 1)*256 + (t & 0x1F)*8 + self.x as u32;
+        let scroll_y = ((t>>11) & 1)*240 + ((t>>5) & 0x1F)*8 + ((t>>12) & 7);
+        {
+            let mut invert = |x: u32, y: u32| {
+                let p = canvas.get_pixel_mut(x % 512, y % 480);
+                p.0 = [0xFF - p.0[0], 0xFF - p.0[1], 0xFF - p.0[2], 0xFF];
             };
-            if end_y < start_y { continue; }
-
-            self.draw_with_state(i, start_y, end_y-1, mapper);
+            for i in 0..256 {
+                invert(scroll_x + i, scroll_y);
+                invert(scroll_x + i, scroll_y + 239);
+            }
+            for i in 1..239 {
+                invert(scroll_x, scroll_y + i);
+                invert(scroll_x + 255, scroll_y + i);
+            }
         }
+        canvas
+    }
 
-        for x in 0..self.output_canvas.width() {
-            for y in 0..self.output_canvas.height() {
-                let sprite = self.sprite_output[x as usize][y as usize];
-                let bg = self.bg_output[x as usize][y as usize];
+    // $0000 on the left and $1000 on the right, 256x128
+    pub fn draw_pattern_tables(&mut self, mapper: &mut Box<Mapper>, palette: u8) -> NesImageBuffer {
+        let mut canvas = make_canvas(256, 128);
+        for tile in 0..512 {
+            let (x, y) = ((tile / 256)*128 + (tile % 16)*8, ((tile % 256) / 16)*8);
+            self.draw_tile(mapper, &mut canvas, tile as u16*16, palette & 7, x, y, false, false);
+        }
+        canvas
+    }
 
-                let mask = if self.pixel_greyscale[x as usize][y as usize] { 0x30 } else { 0xFF };
+    // The 64 sprites in OAM order, 8 to a row, each in an 8x16 cell
+    pub fn draw_sprites(&mut self, mapper: &mut Box<Mapper>) -> NesImageBuffer {
+        let mut canvas = make_canvas(64, 128);
+        for n in 0..64 {
+            let attrs = self.get_sprite_attrs(n);
+            let (x, y) = ((n as u32 % 8)*8, (n as u32 / 8)*16);
+            if attrs.height == 8 {
+                self.draw_tile(mapper, &mut canvas, attrs.pattern_addr, attrs.palette + 4, x, y,
+                               attrs.flip_h, attrs.flip_v);
+            } else {
+                // Flipping an 8x16 sprite vertically swaps its tiles too
+                let (top, bottom) = if attrs.flip_v { (y + 8, y) } else { (y, y + 8) };
+                self.draw_tile(mapper, &mut canvas, attrs.pattern_addr, attrs.palette + 4, x, top,
+                               attrs.flip_h, attrs.flip_v);
+                self.draw_tile(mapper, &mut canvas, attrs.pattern_addr + 16, attrs.palette + 4, x,
+                               bottom, attrs.flip_h, attrs.flip_v);
+            }
+        }
+        canvas
+    }
 
-                let p_idx = if sprite > 0
-                        && (self.sprite_priority[x as usize][y as usize] || bg == 0x3F00) {
-                    sprite
-                } else {
-                    bg
-                };
+    // Background palettes on the top row and sprite palettes on the bottom, 16x16 per colour
+    pub fn draw_palette(&self) -> NesImageBuffer {
+        let mut canvas = make_canvas(256, 32);
+        for (x, y, p) in canvas.enumerate_pixels_mut() {
+            *p = self.palette_rgba(((y/16)*16 + x/16) as u8);
+        }
+        canvas
+    }
 
-                let hsv = (self.read(mapper, p_idx) & mask) as usize;
-                self.output_canvas.put_pixel(x, y, image::Rgba([PALETTE[hsv * 3],
-                    PALETTE[hsv * 3 + 1],
-                    PALETTE[hsv * 3 + 2], 0xFF]));
-            }
+    pub fn describe_sprites(&self) -> String {
+        let mut out = String::from(" #   X   Y  tile  pal  behind  flip\n");
+        for n in 0..64 {
+            let a = self.get_sprite_attrs(n);
+            out += &format!("{:2}  {:02X}  {:02X}  {:02X}    {}    {:6}  {}{}\n", n, a.x, a.y, a.tile,
+                            a.palette, a.behind_background, if a.flip_h { "H" } else { "-" },
+                            if a.flip_v { "V" } else { "-" });
         }
+        out
     }
 
-    pub fn increment_ppuaddr(&mut self) {
-        let addr = ((self.ppuaddr_lo as u16)&0x00FF)
-            + (((self.ppuaddr_hi as u16)&0xFF)<<8);
-        let addr = addr.wrapping_add(if self.vram_inc==0 { 1 } else { 32 });
-        self.ppuaddr_lo = (addr&0x00FF) as u8;
-        self.ppuaddr_hi = ((addr&0xFF00)>>8) as u8;
+    pub fn describe_palette(&self) -> String {
+        let row = |start: usize| {
+            let bytes: Vec<String> = self.palette_rame[start..start + 16].iter()
+                .map(|b| format!("{:02X}", b))
+                .collect();
+            format!("{:04X}: {}\n", 0x3F00 + start, bytes.join(" "))
+        };
+        row(0) + &row(16)
+    }
+
+    // Writes nametables.png, patterns.png, sprites.png and palette.png
+    pub fn save_views(&mut self, mapper: &mut Box<Mapper>, palette: u8) -> io::Result<()> {
+        self.draw_nametables(mapper).save("nametables.png")?;
+        self.draw_pattern_tables(mapper, palette).save("patterns.png")?;
+        self.draw_sprites(mapper).save("sprites.png")?;
+        self.draw_palette().save("palette.png")
     }
 }
 
+fn reverse_bits(b: u8) -> u8 {
+    let mut out = 0;
+    for i in 0..8 {
+        out |= ((b>>i) & 1)<<(7 - i);
+    }
+    out
+}
+
 pub fn make_canvas(width: u32, height: u32) -> NesImageBuffer {
     image::ImageBuffer::new(width, height)
 }
 
+// Stretches image over all of canvas
+pub fn scale_into(image: &NesImageBuffer, canvas: &mut NesImageBuffer) {
+    let (w, h) = (image.width(), image.height());
+    let (cw, ch) = (canvas.width(), canvas.height());
+    for (x, y, p) in canvas.enumerate_pixels_mut() {
+        *p = *image.get_pixel(x*w/cw, y*h/ch);
+    }
+}
+
 impl Mem for Ppu {
     fn read(&mut self, mapper: &mut Box<Mapper>, addr: u16) -> u8 {
         match addr as usize {
             0x0000...0x1FFF => {
-                if self.enable_ppu_chr_delay {
-                    let val = self.ppu_chr_rom_delay_buffer;
-                    self.ppu_chr_rom_delay_buffer = mapper.read_ppu(addr);
-                    val
-                }
-                else {
-                    mapper.read_ppu(addr)
-                }
-            },
-            0x2000...0x23FF => self.vram[addr as usize - 0x2000],
-            0x2400...0x27FF => {
-                if self.horiz_mapping {
-                    self.vram[addr as usize - 0x2400]
-                } else {
-                    self.vram[addr as usize - 0x2000]
+                if !self.chr_log.is_empty() {
+                    if let Some(offset) = mapper.chr_rom_offset(addr) {
+                        self.chr_log[offset] |= if self.ppudata_access {
+                            cdl::READ
+                        } else {
+                            cdl::RENDERED
+                        };
+                    }
                 }
+
+                mapper.ppu_access(addr);
+                mapper.read_ppu(addr)
             },
-            0x2800...0x2BFF => {
-                if self.horiz_mapping {
-                    self.vram[addr as usize - 0x2400]
-                } else {
-                    self.vram[addr as usize - 0x2800]
+            0x2000...0x2FFF => {
+                match mapper.mirroring().nametable(addr) {
+                    Nametable::Ciram(page) => self.vram[(page*0x400 + (addr&0x3FF)) as usize],
+                    Nametable::Cartridge => mapper.read_nametable(addr)
                 }
             },
-            0x2C00...0x2FFF => self.vram[addr as usize - 0x2800],
             0x3000...0x3EFF => self.read(mapper, mirror_addr(0x2000...0x2FFF, 0x3000...0x3EFF, addr)),
             0x3F10 => self.read(mapper, 0x3F00),
             0x3F14 => self.read(mapper, 0x3F04),
@@ -665,23 +937,16 @@ impl Mem for Ppu {
 
     fn write(&mut self, mapper: &mut Box<Mapper>, addr: u16, val: u8) {
         match addr as usize {
-            0x0000...0x1FFF => mapper.write_ppu(addr, val),
-            0x2000...0x23FF => self.vram[addr as usize - 0x2000] = val,
-            0x2400...0x27FF => {
-                if self.horiz_mapping {
-                    self.vram[addr as usize - 0x2400] = val;
-                } else {
-                    self.vram[addr as usize - 0x2000] = val;
-                }
+            0x0000...0x1FFF => {
+                mapper.ppu_access(addr);
+                mapper.write_ppu(addr, val)
             },
-            0x2800...0x2BFF => {
-                if self.horiz_mapping {
-                    self.vram[addr as usize - 0x2400] = val;
-                } else {
-                    self.vram[addr as usize - 0x2800] = val;
+            0x2000...0x2FFF => {
+                match mapper.mirroring().nametable(addr) {
+                    Nametable::Ciram(page) => self.vram[(page*0x400 + (addr&0x3FF)) as usize] = val,
+                    Nametable::Cartridge => mapper.write_nametable(addr, val)
                 }
             },
-            0x2C00...0x2FFF => self.vram[addr as usize - 0x2800] = val,
             0x3000...0x3EFF => self.write(mapper, mirror_addr(0x2000...0x2FFF, 0x3000...0x3EFF, addr), val),
             0x3F10 => self.write(mapper, 0x3F00, val),
             0x3F14 => self.write(mapper, 0x3F04, val),
@@ -695,4 +960,267 @@ impl Mem for Ppu {
             }
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use mapper_0::*;
+    use mapper_4::*;
+    use super::*;
+
+    fn test_mapper(mirroring: Mirroring) -> Box<Mapper> {
+        mapper_with_chr(vec![0; 8*1024], mirroring)
+    }
+
+    fn mapper_with_chr(chr: Vec<u8>, mirroring: Mirroring) -> Box<Mapper> {
+        Box::new(Mapper0::new(vec![0; 16*1024], 8*1024, chr, mirroring).unwrap())
+    }
+
+    fn run_to_scanline(ppu: &mut Ppu, cpu: &mut Cpu, mapper: &mut Box<Mapper>, scanline: u32) {
+        while ppu.scanline != scanline {
+            ppu.tick(cpu, mapper);
+        }
+    }
+
+    #[test]
+    fn scroll_writes_follow_loopy_registers() {
+        let mut mapper = test_mapper(Mirroring::Horizontal);
+        let mut ppu = Ppu::new();
+
+        ppu.write_main(&mut mapper, 0x2000, 0b00000010);
+        ppu.write_main(&mut mapper, 0x2005, 0b01111101);
+        ppu.write_main(&mut mapper, 0x2005, 0b01011110);
+        assert_eq!((ppu.t, ppu.x, ppu.w), (0b110_10_01011_01111, 0b101, false));
+
+        // The first $2006 write clears bit 14, and the second copies t into v
+        ppu.write_main(&mut mapper, 0x2006, 0b00111101);
+        assert_eq!(ppu.v, 0);
+        ppu.write_main(&mut mapper, 0x2006, 0b11110000);
+        assert_eq!((ppu.t, ppu.v), (0x3DF0, 0x3DF0));
+
+        // Reading $2002 resets the toggle between writes
+        ppu.write_main(&mut mapper, 0x2005, 0);
+        ppu.read_main(&mut mapper, 0x2002);
+        assert!(!ppu.w);
+    }
+
+    #[test]
+    fn sprite_overflow_has_the_hardware_bug() {
+        let mut mapper = test_mapper(Mirroring::Horizontal);
+        let mut ppu = Ppu::new();
+        ppu.scanline = 10;
+        ppu.oam = [0xFF; 256];
+        for n in 0..8 {
+            ppu.oam[4*n] = 5;
+        }
+        // The ninth sprite on the line is checked by its tile number instead of its y
+        ppu.oam[4*9] = 5;
+        ppu.evaluate_sprites(&mut mapper);
+        assert!(!ppu.sprite_overflow);
+        assert_eq!(ppu.sprites.len(), 8);
+
+        ppu.oam[4*9 + 1] = 8;
+        ppu.evaluate_sprites(&mut mapper);
+        assert!(ppu.sprite_overflow);
+
+        ppu.sprite_limit = false;
+        ppu.evaluate_sprites(&mut mapper);
+        assert_eq!(ppu.sprites.len(), 9);
+    }
+
+    #[test]
+    fn nametables_follow_the_mappers_mirroring() {
+        let mut mapper = Box::new(Mapper4::new(vec![0; 16*1024], 8*1024, vec![0; 8*1024],
+                                              Mirroring::Vertical).unwrap())
+            as Box<Mapper>;
+        let mut ppu = Ppu::new();
+        ppu.write(&mut mapper, 0x2000, 1);
+        ppu.write(&mut mapper, 0x2400, 2);
+        assert_eq!((ppu.read(&mut mapper, 0x2800), ppu.read(&mut mapper, 0x2C00)), (1, 2));
+
+        // Switching an MMC3 to horizontal mirroring
+        mapper.write(0xA000, 1);
+        assert_eq!((ppu.read(&mut mapper, 0x2400), ppu.read(&mut mapper, 0x2800)), (1, 2));
+
+        let mut mapper = test_mapper(Mirroring::FourScreen);
+        for (i, &addr) in [0x2000, 0x2400, 0x2800, 0x2C00].iter().enumerate() {
+            ppu.write(&mut mapper, addr + 5, i as u8 + 10);
+        }
+        for (i, &addr) in [0x2000, 0x2400, 0x2800, 0x2C00].iter().enumerate() {
+            assert_eq!(ppu.read(&mut mapper, addr + 5), i as u8 + 10);
+        }
+
+        // Mapper mapped nametables get the cartridge's vram too
+        let tables = [Nametable::Ciram(1), Nametable::Cartridge, Nametable::Ciram(0), Nametable::Cartridge];
+        let mut mapper = test_mapper(Mirroring::Custom(tables));
+        ppu.write(&mut mapper, 0x2405, 20);
+        ppu.write(&mut mapper, 0x2805, 21);
+        assert_eq!((ppu.read(&mut mapper, 0x2C05), ppu.read(&mut mapper, 0x2005)), (20, 11));
+    }
+
+    #[test]
+    fn ppudata_reads_are_buffered_except_for_the_palette() {
+        let mut mapper = test_mapper(Mirroring::Horizontal);
+        let mut ppu = Ppu::new();
+        ppu.write(&mut mapper, 0x2F00, 0x12);
+        ppu.write(&mut mapper, 0x2F01, 0x34);
+        ppu.write(&mut mapper, 0x3F00, 0x21);
+
+        ppu.write_main(&mut mapper, 0x2006, 0x2F);
+        ppu.write_main(&mut mapper, 0x2006, 0x00);
+        ppu.read_main(&mut mapper, 0x2007);
+        assert_eq!(ppu.read_main(&mut mapper, 0x2007), 0x12);
+
+        // The palette comes back straight away with open bus in the top bits, and the
+        // nametable byte under it goes into the buffer
+        ppu.write_main(&mut mapper, 0x2006, 0x3F);
+        ppu.write_main(&mut mapper, 0x2006, 0x00);
+        ppu.write_main(&mut mapper, 0x2000, 0xC0);
+        assert_eq!(ppu.read_main(&mut mapper, 0x2007), 0xE1);
+        ppu.write_main(&mut mapper, 0x2006, 0x20);
+        ppu.write_main(&mut mapper, 0x2006, 0x00);
+        assert_eq!(ppu.read_main(&mut mapper, 0x2007), 0x12);
+
+        // Write only registers read back the last value on the bus until it fades
+        ppu.write_main(&mut mapper, 0x2003, 0x5A);
+        assert_eq!(ppu.read_main(&mut mapper, 0x2000), 0x5A);
+        ppu.frame += LATCH_DECAY_FRAMES;
+        assert_eq!(ppu.read_main(&mut mapper, 0x2000), 0);
+    }
+
+    #[test]
+    fn debug_views_draw_tiles_with_their_palettes() {
+        let mut chr = vec![0; 8*1024];
+        // Tile 1 has one pixel of colour 1 in its top left corner
+        chr[0x10] = 0x80;
+        let mut mapper = mapper_with_chr(chr, Mirroring::Horizontal);
+        let mut ppu = Ppu::new();
+        ppu.write(&mut mapper, 0x3F00, 0x0F);
+        ppu.write(&mut mapper, 0x3F01, 0x30);
+        ppu.write(&mut mapper, 0x3F11, 0x16);
+        let (backdrop, white, red) = (ppu.palette_rgba(0), ppu.palette_rgba(1), ppu.palette_rgba(0x11));
+
+        let patterns = ppu.draw_pattern_tables(&mut mapper, 0);
+        assert_eq!((*patterns.get_pixel(8, 0), *patterns.get_pixel(9, 0)), (white, backdrop));
+        assert_eq!(*ppu.draw_pattern_tables(&mut mapper, 4).get_pixel(8, 0), red);
+
+        // Flipped horizontally
+        ppu.oam[..4].copy_from_slice(&[0x20, 1, 0b01000000, 0x30]);
+        let sprites = ppu.draw_sprites(&mut mapper);
+        assert_eq!((*sprites.get_pixel(0, 0), *sprites.get_pixel(7, 0)), (backdrop, red));
+        assert!(ppu.describe_sprites().contains(" 0  30  20  01    0    false   H-"));
+
+        // The scroll window is outlined by inverting the colours under it
+        ppu.write_main(&mut mapper, 0x2005, 16);
+        ppu.write_main(&mut mapper, 0x2005, 8);
+        let nametables = ppu.draw_nametables(&mut mapper);
+        let inverted = image::Rgba([0xFF - backdrop.0[0], 0xFF - backdrop.0[1], 0xFF - backdrop.0[2], 0xFF]);
+        assert_eq!((*nametables.get_pixel(16, 8), *nametables.get_pixel(17, 9)), (inverted, backdrop));
+        assert_eq!(*nametables.get_pixel((16 + 255) % 512, 8 + 239), inverted);
+    }
+
+    #[test]
+    fn regions_change_the_length_of_a_frame() {
+        let mut mapper = test_mapper(Mirroring::Horizontal);
+        let mut cpu = Cpu::new(0);
+
+        // Cpu cycles in a number of whole frames, with rendering off so NTSC does not skip
+        // a dot
+        let mut cycles = |region: Region, frames: u64| {
+            let mut ppu = Ppu::new();
+            ppu.region = region;
+            while ppu.frame == 0 {
+                ppu.tick(&mut cpu, &mut mapper);
+            }
+            let mut count = 0;
+            while ppu.frame < 1 + frames {
+                ppu.tick(&mut cpu, &mut mapper);
+                count += 1;
+            }
+            (count, ppu.scanline)
+        };
+        assert_eq!(cycles(Region::Ntsc, 3), (3*262*341/3, 241));
+        assert_eq!(cycles(Region::Pal, 2), (2*312*341*5/16, 241));
+        assert_eq!(cycles(Region::Dendy, 3), (3*312*341/3, 291));
+    }
+
+    #[test]
+    fn frames_are_palette_indices_with_emphasis() {
+        let mut mapper = test_mapper(Mirroring::Horizontal);
+        let mut cpu = Cpu::new(0);
+        let mut ppu = Ppu::new();
+        ppu.write(&mut mapper, 0x3F00, 0x16);
+        // Red emphasis, with rendering off so every pixel is the backdrop
+        ppu.write_main(&mut mapper, 0x2001, 0b00100000);
+        while ppu.frame == 0 {
+            ppu.tick(&mut cpu, &mut mapper);
+        }
+        assert_eq!(ppu.indexed_output[100*256 + 100], 0b001_010110);
+
+        let mut canvas = make_canvas(256, 240);
+        ppu.palette.convert(&ppu.indexed_output, &mut canvas);
+        let rgb = ppu.palette.colour(0x16, 0b001);
+        assert_eq!(canvas.get_pixel(100, 100).0, [rgb[0], rgb[1], rgb[2], 0xFF]);
+
+        // PAL swaps the red and green bits
+        ppu.region = Region::Pal;
+        while ppu.frame == 1 {
+            ppu.tick(&mut cpu, &mut mapper);
+        }
+        assert_eq!(ppu.indexed_output[100*256 + 100], 0b010_010110);
+    }
+
+    #[test]
+    fn mid_frame_writes_change_the_rest_of_the_frame() {
+        // Tile 1 is solid colour 1 and tile 2 solid colour 2
+        let mut chr = vec![0; 8*1024];
+        for row in 0..8 {
+            chr[0x10 + row] = 0xFF;
+            chr[0x28 + row] = 0xFF;
+        }
+        let mut mapper = mapper_with_chr(chr, Mirroring::Vertical);
+        let mut cpu = Cpu::new(0);
+        let mut ppu = Ppu::new();
+        for addr in 0x2000..0x23C0 {
+            ppu.write(&mut mapper, addr, 1);
+            ppu.write(&mut mapper, addr + 0x400, 2);
+        }
+        ppu.write(&mut mapper, 0x3F01, 0x16);
+        ppu.write(&mut mapper, 0x3F02, 0x2A);
+        ppu.write_main(&mut mapper, 0x2001, 0b00001010);
+        while ppu.frame == 0 {
+            ppu.tick(&mut cpu, &mut mapper);
+        }
+
+        // A $2006 split jumps straight to the second nametable
+        run_to_scanline(&mut ppu, &mut cpu, &mut mapper, 100);
+        ppu.write_main(&mut mapper, 0x2006, 0x24);
+        ppu.write_main(&mut mapper, 0x2006, 0x00);
+
+        // $2005 only changes t, which is copied to v's horizontal position at the end of the
+        // line, so x wraps back into the first nametable from the next line
+        run_to_scanline(&mut ppu, &mut cpu, &mut mapper, 150);
+        ppu.write_main(&mut mapper, 0x2005, 128);
+
+        // Palette writes with rendering turned off for a moment, after resetting the toggle
+        // the lone $2005 write left set
+        run_to_scanline(&mut ppu, &mut cpu, &mut mapper, 200);
+        ppu.read_main(&mut mapper, 0x2002);
+        ppu.write_main(&mut mapper, 0x2001, 0);
+        ppu.write_main(&mut mapper, 0x2006, 0x3F);
+        ppu.write_main(&mut mapper, 0x2006, 0x02);
+        ppu.write_main(&mut mapper, 0x2007, 0x21);
+        ppu.write_main(&mut mapper, 0x2006, 0x24);
+        ppu.write_main(&mut mapper, 0x2006, 0x00);
+        ppu.write_main(&mut mapper, 0x2001, 0b00001010);
+        while ppu.frame == 1 {
+            ppu.tick(&mut cpu, &mut mapper);
+        }
+
+        let pixel = |x: usize, y: usize| ppu.indexed_output[y*256 + x];
+        assert_eq!((pixel(50, 50), pixel(200, 50)), (0x16, 0x16));
+        assert_eq!((pixel(50, 120), pixel(200, 120)), (0x2A, 0x2A));
+        assert_eq!((pixel(50, 149), pixel(200, 149)), (0x2A, 0x2A));
+        assert_eq!((pixel(50, 160), pixel(200, 160)), (0x2A, 0x16));
+        assert_eq!((pixel(50, 210), pixel(200, 210)), (0x21, 0x21));
+    }
+}
@@ -0,0 +1,333 @@
+use std::cmp::max;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::prelude::*;
+use cpu::*;
+use disasm::disassemble;
+
+// Counts how often each instruction runs and how many cycles it takes, grouped by the
+// subroutine or interrupt handler it ran in. Subroutines start at the target of a JSR, and
+// end when the stack pointer is back where it was before the call, so that RTS tricks like
+// jump tables don't confuse it.
+//
+// write_reports saves a text report sorted by cycles, and a callgrind file that can be opened
+// with kcachegrind or qcachegrind.
+
+fn peek16(mem: &mut Bus, addr: u16) -> u16 {
+    mem.peek(addr) as u16 + ((mem.peek(addr + 1) as u16)<<8)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Routine {
+    // Whatever was running when the profiler was started
+    Start(u16),
+    Subroutine(u16),
+    Nmi(u16),
+    Irq(u16),
+}
+
+impl Routine {
+    fn name(&self) -> String {
+        match *self {
+            Routine::Start(addr) => format!("start ${:04X}", addr),
+            Routine::Subroutine(addr) => format!("${:04X}", addr),
+            Routine::Nmi(addr) => format!("NMI ${:04X}", addr),
+            Routine::Irq(addr) => format!("IRQ ${:04X}", addr),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Cost {
+    instructions: u64,
+    cycles: u64,
+}
+
+impl Cost {
+    fn add(&mut self, other: Cost) {
+        self.instructions += other.instructions;
+        self.cycles += other.cycles;
+    }
+
+    fn since(&self, start: Cost) -> Cost {
+        Cost {
+            instructions: self.instructions - start.instructions,
+            cycles: self.cycles - start.cycles,
+        }
+    }
+}
+
+struct Frame {
+    routine: Routine,
+    // The instruction that made the call, or was interrupted
+    call_pc: u16,
+    // The stack pointer before the call. The frame ends when it is back at this level.
+    s: u16,
+    start: Cost,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Calls {
+    count: u64,
+    inclusive: Cost,
+}
+
+pub struct Profiler {
+    total: Cost,
+    // Self cost of each instruction, by the routine it ran in
+    costs: HashMap<(Routine, u16), Cost>,
+    // Caller, call site and callee
+    calls: HashMap<(Routine, u16, Routine), Calls>,
+    stack: Vec<Frame>,
+    // The instruction that is running: pc, opcode and total_count when it started
+    last: Option<(u16, u8, u64)>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            total: Cost::default(),
+            costs: HashMap::new(),
+            calls: HashMap::new(),
+            stack: vec![],
+            last: None,
+        }
+    }
+
+    // Called before every instruction. The cycles since the last call, including any DMA it
+    // caused, belong to the previous instruction.
+    pub fn log(&mut self, cpu: &Cpu, mem: &mut Bus) {
+        if self.stack.is_empty() {
+            self.stack.push(Frame {
+                routine: Routine::Start(cpu.pc),
+                call_pc: cpu.pc,
+                // Above anything the stack pointer can be, so it never returns
+                s: 0x200,
+                start: self.total,
+            });
+        }
+
+        if let Some((pc, op, start)) = self.last {
+            let cost = Cost {
+                instructions: 1,
+                cycles: cpu.total_count - start,
+            };
+            let routine = self.stack.last().unwrap().routine;
+            self.costs.entry((routine, pc)).or_insert(Cost::default()).add(cost);
+            self.total.add(cost);
+
+            // The interrupt sequence pushes three bytes, which are not part of the routine
+            // that was interrupted
+            let interrupt = if cpu.interrupted {
+                let nmi_vector = peek16(mem, 0xFFFA);
+                Some(if cpu.pc == nmi_vector {
+                    Routine::Nmi(cpu.pc)
+                } else {
+                    Routine::Irq(cpu.pc)
+                })
+            } else if op == 0x00 {
+                Some(Routine::Irq(cpu.pc))
+            } else {
+                None
+            };
+            let s = match interrupt {
+                Some(_) => cpu.s as u16 + 3,
+                None => cpu.s as u16,
+            };
+
+            while s >= self.stack.last().unwrap().s {
+                self.ret();
+            }
+
+            if op == 0x20 {
+                // If an interrupt came right after the JSR, the target is the pushed pc
+                let target = match interrupt {
+                    Some(_) => peek16(mem, 0x100 + s - 1),
+                    None => cpu.pc,
+                };
+                self.call(Routine::Subroutine(target), pc, s + 2);
+            }
+            if let Some(routine) = interrupt {
+                self.call(routine, pc, s);
+            }
+        }
+
+        self.last = Some((cpu.pc, mem.peek(cpu.pc), cpu.total_count));
+    }
+
+    fn call(&mut self, routine: Routine, call_pc: u16, s: u16) {
+        self.stack.push(Frame {
+            routine: routine,
+            call_pc: call_pc,
+            s: s,
+            start: self.total,
+        });
+    }
+
+    fn ret(&mut self) {
+        let frame = self.stack.pop().unwrap();
+        let caller = self.stack.last().unwrap().routine;
+        let calls = self.calls.entry((caller, frame.call_pc, frame.routine))
+            .or_insert(Calls::default());
+        calls.count += 1;
+        calls.inclusive.add(self.total.since(frame.start));
+    }
+
+    // The calls so far, counting the routines that are still running as if they had returned
+    fn all_calls(&self) -> HashMap<(Routine, u16, Routine), Calls> {
+        let mut all = self.calls.clone();
+        for i in 1..self.stack.len() {
+            let frame = &self.stack[i];
+            let caller = self.stack[i - 1].routine;
+            let calls = all.entry((caller, frame.call_pc, frame.routine))
+                .or_insert(Calls::default());
+            calls.count += 1;
+            calls.inclusive.add(self.total.since(frame.start));
+        }
+        all
+    }
+
+    pub fn report(&self, mem: &mut Bus) -> String {
+        let calls = self.all_calls();
+
+        // Routine: calls, self and inclusive cost
+        let mut routines: HashMap<Routine, (u64, Cost, Cost)> = HashMap::new();
+        for (&(routine, _), &cost) in self.costs.iter() {
+            routines.entry(routine).or_insert((0, Cost::default(), Cost::default())).1.add(cost);
+        }
+        for (&(_, _, callee), c) in calls.iter() {
+            let r = routines.entry(callee).or_insert((0, Cost::default(), Cost::default()));
+            r.0 += c.count;
+            r.2.add(c.inclusive);
+        }
+        for (&routine, r) in routines.iter_mut() {
+            if let Routine::Start(_) = routine {
+                r.0 = 1;
+                r.2 = self.total;
+            }
+        }
+
+        let mut routines: Vec<(Routine, (u64, Cost, Cost))> = routines.into_iter().collect();
+        routines.sort_by(|a, b| (b.1).1.cycles.cmp(&(a.1).1.cycles).then(a.0.cmp(&b.0)));
+
+        let percent = |cycles: u64| 100.0 * cycles as f64 / max(self.total.cycles, 1) as f64;
+        let mut out = format!("{} instructions, {} cycles\n\n", self.total.instructions,
+                              self.total.cycles);
+        out += &format!("{:<12} {:>8} {:>12} {:>6} {:>12} {:>6}\n", "Routine", "Calls",
+                        "Self cycles", "%", "Incl. cycles", "%");
+        for &(routine, (count, own, inclusive)) in routines.iter() {
+            out += &format!("{:<12} {:>8} {:>12} {:>6.2} {:>12} {:>6.2}\n", routine.name(), count,
+                            own.cycles, percent(own.cycles), inclusive.cycles,
+                            percent(inclusive.cycles));
+        }
+
+        let mut instructions: HashMap<u16, Cost> = HashMap::new();
+        for (&(_, pc), &cost) in self.costs.iter() {
+            instructions.entry(pc).or_insert(Cost::default()).add(cost);
+        }
+        let mut instructions: Vec<(u16, Cost)> = instructions.into_iter().collect();
+        instructions.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(&b.0)));
+
+        out += &format!("\n{:<4} {:<16} {:>10} {:>12} {:>6}\n", "PC", "Instruction", "Hits",
+                        "Cycles", "%");
+        for &(pc, cost) in instructions.iter().take(50) {
+            out += &format!("{:04X} {:<16} {:>10} {:>12} {:>6.2}\n", pc,
+                            disassemble(mem, pc).text(), cost.instructions, cost.cycles,
+                            percent(cost.cycles));
+        }
+        out
+    }
+
+    // See http://valgrind.org/docs/manual/cl-format.html
+    pub fn callgrind(&self) -> String {
+        let calls = self.all_calls();
+        let mut out = String::new();
+        out += "# callgrind format\nversion: 1\ncreator: nes_emulator\npositions: instr\n";
+        out += "events: Cycles Instructions\n";
+        out += &format!("summary: {} {}\n", self.total.cycles, self.total.instructions);
+
+        let mut costs: Vec<(&(Routine, u16), &Cost)> = self.costs.iter().collect();
+        costs.sort_by_key(|c| *c.0);
+        let mut calls: Vec<(&(Routine, u16, Routine), &Calls)> = calls.iter().collect();
+        calls.sort_by_key(|c| *c.0);
+
+        let mut routines: Vec<Routine> = costs.iter().map(|c| (c.0).0).collect();
+        routines.dedup();
+        for routine in routines {
+            out += &format!("\nfn={}\n", routine.name());
+            for &(&(_, pc), cost) in costs.iter().filter(|c| (c.0).0 == routine) {
+                out += &format!("0x{:04X} {} {}\n", pc, cost.cycles, cost.instructions);
+            }
+            for &(&(_, pc, callee), c) in calls.iter().filter(|c| (c.0).0 == routine) {
+                let target = match callee {
+                    Routine::Start(addr) | Routine::Subroutine(addr) | Routine::Nmi(addr)
+                        | Routine::Irq(addr) => addr
+                };
+                out += &format!("cfn={}\ncalls={} 0x{:04X}\n0x{:04X} {} {}\n", callee.name(),
+                                c.count, target, pc, c.inclusive.cycles, c.inclusive.instructions);
+            }
+        }
+        out
+    }
+
+    // Writes profile.txt and callgrind.out.nes
+    pub fn write_reports(&self, mem: &mut Bus) {
+        let files = [("profile.txt", self.report(mem)), ("callgrind.out.nes", self.callgrind())];
+        for &(filename, ref text) in files.iter() {
+            let file = File::create(filename).expect("Could not create profile");
+            BufWriter::new(file).write_all(text.as_bytes()).expect("Could not write profile");
+            println!("Wrote {}", filename);
+        }
+    }
+}
+
+// Starts the profiler, or stops it and writes its reports
+pub fn toggle(profiler: &mut Option<Profiler>, mem: &mut Bus) {
+    match profiler.take() {
+        Some(p) => p.write_reports(mem),
+        None => {
+            println!("Profiling");
+            *profiler = Some(Profiler::new());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ram_bus::RamBus;
+    use super::*;
+
+    #[test]
+    fn groups_cycles_by_subroutine() {
+        let mut bus = RamBus::new();
+        bus.load(0x0600, &[
+            0xA2, 0x03,       // LDX #$03
+            0x20, 0x0A, 0x06, // JSR $060A
+            0xCA,             // DEX
+            0xD0, 0xFA,       // BNE $0602
+            0xF0, 0xFE,       // BEQ $0608
+            0xEA,             // NOP
+            0x60,             // RTS
+        ]);
+
+        let mut cpu = Cpu::new(0x0600);
+        let mut profiler = Profiler::new();
+        while cpu.pc != 0x0608 {
+            profiler.log(&cpu, &mut bus);
+            cpu.tick(&mut bus);
+        }
+        profiler.log(&cpu, &mut bus);
+
+        let start = Routine::Start(0x0600);
+        let subroutine = Routine::Subroutine(0x060A);
+        let calls = profiler.calls[&(start, 0x0602, subroutine)];
+        assert_eq!(calls.count, 3);
+        assert_eq!(calls.inclusive.cycles, 3*(2 + 6));
+
+        let own: u64 = profiler.costs.iter().filter(|c| (c.0).0 == start)
+            .map(|c| c.1.cycles).sum();
+        assert_eq!(own, 2 + 3*6 + 3*2 + 2*3 + 2);
+        assert_eq!(profiler.total.cycles, cpu.total_count);
+    }
+}
@@ -0,0 +1,101 @@
+use cpu::*;
+
+// 64kB of plain memory, with nothing else attached. Enough to run the cpu on its own, for
+// tests and for 6502 test suites that are not NES roms.
+pub struct RamBus {
+    pub ram: Vec<u8>,
+}
+
+impl RamBus {
+    pub fn new() -> RamBus {
+        RamBus {
+            ram: vec![0; 0x10000]
+        }
+    }
+
+    pub fn load(&mut self, addr: u16, data: &[u8]) {
+        for (i, &val) in data.iter().enumerate() {
+            self.ram[addr as usize + i] = val;
+        }
+    }
+}
+
+impl Bus for RamBus {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        self.ram[addr as usize] = val;
+    }
+
+    fn peek(&mut self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_without_a_chipset() {
+        let mut bus = RamBus::new();
+        // Sum 1..=10 into $10
+        bus.load(0x0600, &[
+            0xA9, 0x00,       // LDA #$00
+            0xA2, 0x0A,       // LDX #$0A
+            0x86, 0x11,       // STX $11
+            0x18,             // CLC
+            0x65, 0x11,       // ADC $11
+            0xC6, 0x11,       // DEC $11
+            0xD0, 0xF9,       // BNE $0606
+            0x85, 0x10,       // STA $10
+        ]);
+
+        let mut cpu = Cpu::new(0x0600);
+        while cpu.pc != 0x060F {
+            cpu.tick(&mut bus);
+        }
+
+        assert_eq!(bus.ram[0x10], 55);
+        assert_eq!(cpu.total_count, 2 + 2 + 3 + 10*(2 + 3 + 5 + 3) - 1 + 3);
+    }
+
+    // Keeps every access, as address, value and whether it was a write
+    struct LoggingBus {
+        bus: RamBus,
+        log: Vec<(u16, u8, bool)>,
+    }
+
+    impl Bus for LoggingBus {
+        fn read(&mut self, addr: u16) -> u8 {
+            let val = self.bus.read(addr);
+            self.log.push((addr, val, false));
+            val
+        }
+
+        fn write(&mut self, addr: u16, val: u8) {
+            self.log.push((addr, val, true));
+            self.bus.write(addr, val);
+        }
+
+        fn peek(&mut self, addr: u16) -> u8 {
+            self.bus.peek(addr)
+        }
+    }
+
+    #[test]
+    fn jsr_pushes_before_reading_the_high_byte() {
+        let mut bus = LoggingBus { bus: RamBus::new(), log: vec![] };
+        // The operand ends at $FFFF, so pc wraps to 0 after it
+        bus.bus.load(0xFFFD, &[0x20, 0x34, 0x12]); // JSR $1234
+        let mut cpu = Cpu::new(0xFFFD);
+        cpu.s = 0xFD;
+        cpu.tick(&mut bus);
+
+        assert_eq!(cpu.pc, 0x1234);
+        assert_eq!(bus.log, vec![(0xFFFD, 0x20, false), (0xFFFE, 0x34, false), (0x01FD, 0, false),
+                                 (0x01FD, 0xFF, true), (0x01FC, 0xFF, true), (0xFFFF, 0x12, false)]);
+    }
+}
@@ -0,0 +1,75 @@
+// The kind of console a game was made for. PAL consoles and the Dendy famiclone both draw 312
+// lines a frame at 50Hz, but the PAL cpu runs at 1/16 of its master clock against the ppu's
+// 1/5, so the ppu runs 3.2 dots per cpu cycle instead of 3. The Dendy keeps the NTSC ratio
+// and starts vblank 50 lines later, so games written for NTSC timing mostly run on it.
+// See https://wiki.nesdev.com/w/index.php/Cycle_reference_chart
+use palette::{Ntsc, Palette};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl Region {
+    // From the low bits of byte 12 of an NES 2.0 header. Games that run on any console are
+    // run as NTSC.
+    pub fn from_nes_2(byte: u8) -> Region {
+        match byte & 0b11 {
+            1 => Region::Pal,
+            3 => Region::Dendy,
+            _ => Region::Ntsc
+        }
+    }
+
+    // Including vblank and the pre-render line, which is the last one
+    pub fn scanlines(&self) -> u32 {
+        match *self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    // The line vblank and the NMI start on
+    pub fn vblank_line(&self) -> u32 {
+        match *self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    // Ppu dots for a number of cpu cycles
+    pub fn dots_per_cycle(&self) -> (u32, u32) {
+        match *self {
+            Region::Ntsc | Region::Dendy => (3, 1),
+            Region::Pal => (16, 5),
+        }
+    }
+
+    // Only the NTSC ppu makes odd frames a dot shorter
+    pub fn skips_odd_dot(&self) -> bool {
+        *self == Region::Ntsc
+    }
+
+    // PAL and Dendy ppus have the red and green emphasis bits of PPUMASK the other way around
+    pub fn swaps_emphasis(&self) -> bool {
+        *self != Region::Ntsc
+    }
+
+    // The palette of PaletteSource::Default. PAL consoles and the Dendy make a PAL signal,
+    // so their colours are the ones a PAL tv decodes from it.
+    pub fn default_palette(&self) -> Palette {
+        match *self {
+            Region::Ntsc => Palette::default(),
+            Region::Pal | Region::Dendy => Palette::generate_pal(Ntsc::default()),
+        }
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        match *self {
+            Region::Ntsc => 60.0988,
+            Region::Pal | Region::Dendy => 50.0070,
+        }
+    }
+}
@@ -1,4 +1,48 @@
+use memory::RamFill;
+use palette::PaletteSource;
+use upscale::Upscaler;
+use region::Region;
+
 pub const USE_MOVIE: bool = false;
 pub const DEBUG: bool = false;
 pub const SPECIAL: bool = false;
-pub const USE_HACKS: bool = true;
\ No newline at end of file
+pub const USE_HACKS: bool = true;
+// Write a nestest style trace of every instruction to trace.log
+pub const TRACE: bool = false;
+// Run without a window, starting in the debugger
+pub const HEADLESS: bool = false;
+// Start with the profiler running. It can also be toggled with P when DEBUG is set, or with p
+// in the debugger, and writes profile.txt and callgrind.out.nes when it is stopped.
+pub const PROFILE: bool = false;
+// Log which bytes of the rom are code and data to assets/smb.cdl, in the format of FCEUX. The
+// log is added to what is already in the file, and is saved with C or the debugger.
+pub const CDL: bool = false;
+// What ram holds at power on: RamFill::Zeros, RamFill::Ones (0xFF) or RamFill::Random(seed)
+pub const RAM_FILL: RamFill = RamFill::Zeros;
+// How many instructions to keep for the crash_report.txt written on the first fault, with
+// the bus accesses they made. 0 turns it off.
+pub const HISTORY: usize = 0;
+// Only draw the first 8 sprites on each line, like the hardware. Turning it off removes the
+// flicker of games with more, and it can be toggled with F3.
+pub const SPRITE_LIMIT: bool = true;
+// Where the colours come from: PaletteSource::Default for the region's own, which is decoded
+// like a PAL tv for PAL and Dendy games, PaletteSource::File("name.pal") for a 64 or 512
+// colour .pal file like the ones FCEUX and Mesen save, or one decoded like an NTSC tv in any
+// region with PaletteSource::Ntsc(Ntsc { hue: 0.0, saturation: 1.2, contrast: 1.0,
+// brightness: 1.0, gamma: 1.8 })
+pub const PALETTE: PaletteSource = PaletteSource::Default;
+// Blur the picture through a simulated composite signal, with the colour fringing and dot
+// crawl of an NTSC tv, in any region. It uses the PALETTE's Ntsc settings if it has them, can
+// be toggled with F7, and is not used when SPECIAL is set.
+pub const NTSC_FILTER: bool = false;
+// Smooth the edges of the pixel art before it is stretched to the window, and in screenshots
+// taken with F8: Upscaler::Nearest, Upscaler::Scale2x, Upscaler::Scale3x,
+// Upscaler::Hqx(2 to 4) or Upscaler::Xbr(2 to 4). It is not used with NTSC_FILTER,
+// which has no sharp edges left.
+pub const UPSCALER: Upscaler = Upscaler::Nearest;
+// Save every frame as it is shown, after NTSC_FILTER or UPSCALER, to frame-<number>.png
+pub const RECORD: bool = false;
+// The console to emulate, which sets the frame rate and timing: None for the one in the rom's
+// NES 2.0 header, or NTSC when it has none, or Some(Region::Ntsc), Some(Region::Pal) or
+// Some(Region::Dendy)
+pub const REGION: Option<Region> = None;
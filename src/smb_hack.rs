@@ -1,6 +1,8 @@
 use nes::*;
+use cpu::Bus;
 use smb_level::*;
 use settings::*;
+use error::EmulationError;
 
 const GAME_ENGINE_SUBROUTINE: u16 = 0x0E;
 
@@ -22,43 +24,45 @@ impl SmbHack {
     }
 }
 
-pub fn initial_state(nes: &mut Nes) {
+pub fn initial_state(nes: &mut Nes) -> Result<(), EmulationError> {
     // Big 'ol hack to skip the title screen
     nes.smb_hack.skip = true;
     set_level(nes);
     prepare_level(nes);
 
     for _ in 0..60 {
-        nes.tick();
+        nes.tick()?;
         set_level(nes);
     }
 
     nes.chipset.controller1.start = true;
     for _ in 0..2 {
-        nes.tick();
+        nes.tick()?;
         set_level(nes);
     }
     nes.chipset.controller1.start = false;
 
-    skip_prelevel(nes);
+    skip_prelevel(nes)
 }
 
-fn skip_prelevel(nes: &mut Nes) {
+fn skip_prelevel(nes: &mut Nes) -> Result<(), EmulationError> {
     nes.smb_hack.skip = true;
     for _ in 0..10 {
         // Hack the prelevel timer to clear so the level starts right away
         nes.chipset.write(0x07A0, 0);
-        nes.tick();
+        nes.tick()?;
     }
     nes.smb_hack.skip = false;
+    Ok(())
 }
 
-fn skip_death(nes: &mut Nes) {
+fn skip_death(nes: &mut Nes) -> Result<(), EmulationError> {
     nes.smb_hack.skip = true;
     for _ in 0..30 {
-        nes.tick();
+        nes.tick()?;
     }
     nes.smb_hack.skip = false;
+    Ok(())
 }
 
 fn set_level(nes: &mut Nes) {
@@ -85,16 +89,16 @@ pub fn kill_yourself(nes: &mut Nes) {
     nes.chipset.write(GAME_ENGINE_SUBROUTINE, 0x06);
 }
 
-pub fn tick(nes: &mut Nes) {
+pub fn tick(nes: &mut Nes) -> Result<(), EmulationError> {
     if nes.smb_hack.skip {
-        return;
+        return Ok(());
     }
 
     set_level(nes);
     if nes.smb_hack.prelevel_skip {
         if nes.chipset.read(0x07A0) == 7 {
             nes.smb_hack.prelevel_skip = false;
-            skip_prelevel(nes);
+            skip_prelevel(nes)?;
         }
     }
 
@@ -113,11 +117,12 @@ pub fn tick(nes: &mut Nes) {
         // TODO rewind time
         // For now, we just advance through the pre-level
         if SPECIAL {
-            skip_death(nes);
-            skip_prelevel(nes);
+            skip_death(nes)?;
+            skip_prelevel(nes)?;
         }
         else {
             nes.smb_hack.prelevel_skip = true;
         }
     }
+    Ok(())
 }
\ No newline at end of file
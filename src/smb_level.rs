@@ -1,4 +1,5 @@
 use nes::*;
+use cpu::Bus;
 use ines::lines_from_file;
 use std::collections::HashMap;
 use phf::Map;
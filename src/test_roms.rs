@@ -0,0 +1,218 @@
+use std::fs;
+use std::panic;
+use std::path::{Path, PathBuf};
+use ines::*;
+use nes::*;
+use history::panic_message;
+use cpu::Bus;
+use memory::Mem;
+use region::Region;
+
+// Runs blargg's test roms without a window.
+// See https://wiki.nesdev.com/w/index.php/Emulator_tests and the readme.txt of each test
+
+// Long enough for every rom_singles test, and for cpu_timing_test6
+const MAX_FRAMES: u32 = 60*60;
+
+#[derive(Debug, PartialEq)]
+pub enum TestResult {
+    Passed(String),
+    Failed(u8, String),
+    Timeout(String),
+    Crashed(String),
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        match *self {
+            TestResult::Passed(_) => true,
+            _ => false
+        }
+    }
+}
+
+// Roms write their status to $6000 once $6001-$6003 hold the signature: $80 while running,
+// $81 when they need a reset, and the result code when they are done. The text they print
+// is a zero terminated string at $6004.
+fn status(nes: &mut Nes) -> Option<u8> {
+    let signature = [nes.chipset.peek(0x6001), nes.chipset.peek(0x6002), nes.chipset.peek(0x6003)];
+    if signature == [0xDE, 0xB0, 0x61] {
+        Some(nes.chipset.peek(0x6000))
+    } else {
+        None
+    }
+}
+
+fn status_text(nes: &mut Nes) -> String {
+    let mut text = String::new();
+    for addr in 0x6004..0x7000 {
+        match nes.chipset.peek(addr) {
+            0 => break,
+            c => text.push(c as char)
+        }
+    }
+    text.trim().to_string()
+}
+
+// Older tests like cpu_timing_test6 only print to the screen, with tiles numbered like ASCII
+fn screen_text(nes: &mut Nes) -> String {
+    let mut lines = vec![];
+    for row in 0..30 {
+        let mut line = String::new();
+        for col in 0..32 {
+            let c = nes.chipset.ppu.read(&mut nes.chipset.mapper, 0x2000 + row*32 + col);
+            line.push(if c >= 0x20 && c < 0x7F { c as char } else { ' ' });
+        }
+        if !line.trim().is_empty() {
+            lines.push(line.trim().to_string());
+        }
+    }
+    lines.join("\n")
+}
+
+// Roms that ask for a reset want it at least 100ms later
+const RESET_DELAY: u32 = 6;
+
+fn run(nes: &mut Nes) -> TestResult {
+    let mut reset_at = None;
+    // Roms that poke at unmapped addresses often pass anyway, so this is only reported if
+    // the rom never finishes
+    let mut error = None;
+    for frame in 0..MAX_FRAMES {
+        if let Err(e) = nes.tick() {
+            error = error.or(Some(e));
+        }
+
+        if reset_at == Some(frame) {
+            reset_at = None;
+            nes.reset();
+            // The status is still $81 until the rom gets going again
+            continue;
+        }
+
+        match status(nes) {
+            Some(0x80) | None => (),
+            Some(0x81) => if reset_at.is_none() {
+                reset_at = Some(frame + RESET_DELAY);
+            },
+            Some(0) => return TestResult::Passed(status_text(nes)),
+            Some(code) => return TestResult::Failed(code, status_text(nes)),
+        }
+
+        if frame % 60 == 0 {
+            let text = screen_text(nes);
+            if text.contains("PASSED") {
+                return TestResult::Passed(text);
+            }
+            if text.contains("FAILED") {
+                return TestResult::Failed(1, text);
+            }
+        }
+    }
+
+    if let Some(e) = error {
+        return TestResult::Crashed(e.to_string());
+    }
+    match status(nes) {
+        Some(_) => TestResult::Timeout(status_text(nes)),
+        None => TestResult::Timeout(screen_text(nes))
+    }
+}
+
+pub fn run_test_rom(path: &Path) -> TestResult {
+    let (flags, prg, chr) = match load_file(path.to_str().unwrap()) {
+        Ok(rom) => rom,
+        Err(e) => return TestResult::Crashed(format!("Could not load rom: {}", e))
+    };
+
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        match Nes::new(prg, chr, flags.mapper, flags.prg_ram_size, flags.mirroring) {
+            Ok(mut nes) => {
+                nes.set_region(flags.region.unwrap_or(Region::Ntsc));
+                run(&mut nes)
+            },
+            Err(e) => TestResult::Crashed(e.to_string())
+        }
+    }));
+
+    match result {
+        Ok(result) => result,
+        Err(e) => TestResult::Crashed(panic_message(&e))
+    }
+}
+
+// All .nes files below path, in a stable order
+pub fn find_test_roms(path: &Path) -> Vec<PathBuf> {
+    if !path.is_dir() {
+        return vec![path.to_path_buf()];
+    }
+
+    let mut entries: Vec<PathBuf> = fs::read_dir(path).expect("Could not read directory")
+        .map(|e| e.unwrap().path()).collect();
+    entries.sort();
+
+    let mut roms = vec![];
+    for entry in entries {
+        if entry.is_dir() {
+            roms.extend(find_test_roms(&entry));
+        } else if entry.extension().map_or(false, |e| e == "nes") {
+            roms.push(entry);
+        }
+    }
+    roms
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::*;
+
+    fn check_roms(dir: &str, roms: &[&str]) {
+        let mut failures = vec![];
+        for rom in roms {
+            let path = Path::new("tests/nes-test-roms").join(dir).join(rom);
+            let result = run_test_rom(&path);
+            if !result.passed() {
+                failures.push(format!("{}: {:?}", path.display(), result));
+            }
+        }
+        assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+    }
+
+    #[test]
+    fn instr_test_v5() {
+        check_roms("instr_test-v5/rom_singles", &[
+            "01-basics.nes", "02-implied.nes", "03-immediate.nes", "04-zero_page.nes",
+            "05-zp_xy.nes", "06-absolute.nes", "07-abs_xy.nes", "08-ind_x.nes", "09-ind_y.nes",
+            "10-branches.nes", "11-stack.nes", "12-jmp_jsr.nes", "13-rts.nes", "14-rti.nes",
+            "15-brk.nes", "16-special.nes"]);
+    }
+
+    #[test]
+    fn instr_misc() {
+        check_roms("instr_misc/rom_singles", &["01-abs_x_wrap.nes", "02-branch_wrap.nes"]);
+    }
+
+    #[test]
+    fn instr_misc_dummy_reads() {
+        check_roms("instr_misc/rom_singles", &["03-dummy_reads.nes", "04-dummy_reads_apu.nes"]);
+    }
+
+    #[test]
+    fn instr_timing() {
+        check_roms("instr_timing/rom_singles", &["1-instr_timing.nes", "2-branch_timing.nes"]);
+    }
+
+    #[test]
+    fn cpu_timing_test6() {
+        check_roms("cpu_timing_test6", &["cpu_timing_test.nes"]);
+    }
+
+    #[test]
+    fn ppu_sprite_hit() {
+        check_roms("ppu_sprite_hit/rom_singles", &["01-basics.nes", "02-alignment.nes",
+            "03-corners.nes", "04-flip.nes", "05-left_clip.nes", "06-right_edge.nes",
+            "07-screen_bottom.nes", "08-double_height.nes", "09-timing.nes",
+            "10-timing_order.nes"]);
+    }
+}
@@ -0,0 +1,35 @@
+extern crate nes_emulator;
+
+use std::env;
+use std::path::Path;
+use std::process;
+use nes_emulator::test_roms::*;
+
+// Usage: test_runner [rom or directory]...
+// Runs every test rom in tests/nes-test-roms by default
+fn main() {
+    let mut paths: Vec<String> = env::args().skip(1).collect();
+    if paths.is_empty() {
+        paths.push("tests/nes-test-roms".to_string());
+    }
+
+    let mut passed = 0;
+    let mut failed = 0;
+    for path in paths {
+        for rom in find_test_roms(Path::new(&path)) {
+            let result = run_test_rom(&rom);
+            if result.passed() {
+                passed += 1;
+                println!("PASS {}", rom.display());
+            } else {
+                failed += 1;
+                println!("FAIL {}: {:?}", rom.display(), result);
+            }
+        }
+    }
+
+    println!("{} passed, {} failed", passed, failed);
+    if failed > 0 {
+        process::exit(1);
+    }
+}
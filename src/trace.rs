@@ -0,0 +1,94 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::prelude::*;
+use cpu::*;
+use nes::*;
+use disasm::disassemble;
+
+// Writes one line per instruction in the format of Nintendulator and nestest.log:
+// C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+pub struct Trace {
+    out: Box<Write>,
+}
+
+impl Trace {
+    pub fn new(out: Box<Write>) -> Trace {
+        Trace {
+            out: out
+        }
+    }
+
+    pub fn to_file(filename: &str) -> Trace {
+        let file = File::create(filename).expect("Could not create trace file");
+        Trace::new(Box::new(BufWriter::new(file)))
+    }
+
+    pub fn log(&mut self, cpu: &Cpu, mem: &mut Chipset) {
+        writeln!(self.out, "{}", trace_line(cpu, mem)).expect("Could not write trace");
+    }
+}
+
+// Describes the instruction at pc, before it is executed
+pub fn trace_line(cpu: &Cpu, mem: &mut Bus) -> String {
+    let instruction = disassemble(mem, cpu.pc);
+    let bytes: Vec<String> = instruction.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    // Unofficial opcodes are marked like in nestest.log
+    let star = if instruction.official { " " } else { "*" };
+    let (scanline, dot) = mem.ppu_position();
+
+    format!("{:04X}  {:<9}{}{:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+            cpu.pc, bytes.join(" "), star, instruction.annotated(cpu, mem), cpu.a, cpu.x, cpu.y,
+            cpu.get_p() & !0b00010000, cpu.s, scanline, dot, cpu.total_count)
+}
+
+// nestest.nes runs all of its tests without a ppu when started at $C000 instead of the
+// reset vector. The reference log starts at cycle 7, on dot 21 of the first scanline.
+pub fn start_nestest(nes: &mut Nes) {
+    nes.cpu.pc = 0xC000;
+    nes.cpu.set_p(0x24);
+    nes.cpu.total_count = 7;
+    nes.chipset.ppu.scanline = 0;
+    nes.chipset.ppu.dot = 21;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use ines::*;
+    use nes::*;
+    use super::*;
+
+    fn nestest() -> Nes {
+        let (flags, prg, chr) = load_file("tests/nestest.nes").unwrap();
+        let mut nes = Nes::new(prg, chr, flags.mapper, flags.prg_ram_size, flags.mirroring).unwrap();
+        start_nestest(&mut nes);
+        nes
+    }
+
+    // nestest keeps the number of the first failed official and unofficial test in $02 and $03
+    #[test]
+    fn nestest_reports_no_failures() {
+        let mut nes = nestest();
+        // The last instruction of the tests, an RTS out of the $C000 entry point
+        while nes.cpu.pc != 0xC66E {
+            nes.step().unwrap();
+        }
+        assert_eq!((nes.chipset.peek(0x02), nes.chipset.peek(0x03)), (0, 0));
+    }
+
+    // The reference log is Nintendulator's, from https://www.qmtpro.com/~nes/misc/nestest.log.
+    // It is not in the repo, run this with --ignored after saving it as tests/nestest.log.
+    #[test]
+    #[ignore]
+    fn nestest_matches_reference_log() {
+        let mut nes = nestest();
+        assert!(Path::new("tests/nestest.log").exists(), "tests/nestest.log is missing");
+
+        for (i, expected) in lines_from_file("tests/nestest.log").iter().enumerate() {
+            let line = trace_line(&nes.cpu, &mut nes.chipset);
+            // The log has Windows line endings
+            assert_eq!(line.as_str(), expected.trim_right(), "Trace differs on line {}", i + 1);
+            nes.step().unwrap();
+        }
+    }
+}
@@ -0,0 +1,452 @@
+use image;
+use std::cmp;
+use ppu::{make_canvas, NesImageBuffer};
+
+// Pixel art upscalers, run on the ppu's output before it is stretched to the window.
+// Scale2x and Scale3x only copy neighbours into the corners of each pixel. Hqx looks up which
+// of the 8 neighbours differ from the pixel in a table of the 256 patterns, which says how to
+// blend each corner, and xBR blends corners on the side of the edge that is closer to a
+// straight line through the pixel.
+// See http://www.scale2x.it/algorithm, https://en.wikipedia.org/wiki/Hqx and
+// https://forums.libretro.com/t/xbr-algorithm-tutorial/123
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Upscaler {
+    // Leave the frame as it is
+    Nearest,
+    Scale2x,
+    Scale3x,
+    // hq2x, hq3x or hq4x
+    Hqx(u32),
+    // 2, 3 or 4 times
+    Xbr(u32),
+}
+
+type Colour = [u8; 4];
+
+// The frame with the YUV of each pixel, which is what the upscalers compare
+struct Source {
+    width: i32,
+    height: i32,
+    colours: Vec<Colour>,
+    yuv: Vec<[f32; 3]>,
+}
+
+impl Source {
+    fn new(image: &NesImageBuffer) -> Source {
+        let colours: Vec<Colour> = image.pixels().map(|p| p.0).collect();
+        let yuv = colours.iter().map(|c| to_yuv(*c)).collect();
+        Source {
+            width: image.width() as i32,
+            height: image.height() as i32,
+            colours: colours,
+            yuv: yuv,
+        }
+    }
+
+    // Pixels past the edges repeat the ones on the edge
+    fn index(&self, x: i32, y: i32) -> usize {
+        let x = cmp::max(0, cmp::min(x, self.width - 1));
+        let y = cmp::max(0, cmp::min(y, self.height - 1));
+        (y*self.width + x) as usize
+    }
+
+    fn at(&self, x: i32, y: i32) -> Colour {
+        self.colours[self.index(x, y)]
+    }
+
+    // How far apart two pixels look, weighted like the xBR filters
+    fn distance(&self, a: (i32, i32), b: (i32, i32)) -> f32 {
+        let (a, b) = (self.yuv[self.index(a.0, a.1)], self.yuv[self.index(b.0, b.1)]);
+        48.0*(a[0] - b[0]).abs() + 7.0*(a[1] - b[1]).abs() + 6.0*(a[2] - b[2]).abs()
+    }
+
+    // With the thresholds of hqx
+    fn differ(&self, a: (i32, i32), b: (i32, i32)) -> bool {
+        let (a, b) = (self.yuv[self.index(a.0, a.1)], self.yuv[self.index(b.0, b.1)]);
+        (a[0] - b[0]).abs() > 48.0 || (a[1] - b[1]).abs() > 7.0 || (a[2] - b[2]).abs() > 6.0
+    }
+}
+
+fn to_yuv(c: Colour) -> [f32; 3] {
+    let (r, g, b) = (c[0] as f32, c[1] as f32, c[2] as f32);
+    [0.299*r + 0.587*g + 0.114*b,
+     -0.169*r - 0.331*g + 0.5*b,
+     0.5*r - 0.419*g - 0.081*b]
+}
+
+fn blend(a: Colour, b: Colour, amount: f32) -> Colour {
+    let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32)*amount).round() as u8;
+    [mix(a[0], b[0]), mix(a[1], b[1]), mix(a[2], b[2]), 0xFF]
+}
+
+impl Upscaler {
+    pub fn factor(&self) -> u32 {
+        match *self {
+            Upscaler::Nearest => 1,
+            Upscaler::Scale2x => 2,
+            Upscaler::Scale3x => 3,
+            Upscaler::Hqx(n) | Upscaler::Xbr(n) => cmp::max(2, cmp::min(n, 4)),
+        }
+    }
+
+    // out is replaced with a new canvas when it is not the right size
+    pub fn apply(&self, image: &NesImageBuffer, out: &mut NesImageBuffer) {
+        let n = self.factor();
+        if out.width() != image.width()*n || out.height() != image.height()*n {
+            *out = make_canvas(image.width()*n, image.height()*n);
+        }
+
+        let src = Source::new(image);
+        let patterns: Vec<[Corner; 4]> = match *self {
+            Upscaler::Hqx(_) => (0..256).map(|p| hqx_pattern(p as u8)).collect(),
+            _ => vec![]
+        };
+        let mut block = vec![[0; 4]; (n*n) as usize];
+        for y in 0..src.height {
+            for x in 0..src.width {
+                match *self {
+                    Upscaler::Nearest => block[0] = src.at(x, y),
+                    Upscaler::Scale2x => scale2x(&src, x, y, &mut block),
+                    Upscaler::Scale3x => scale3x(&src, x, y, &mut block),
+                    Upscaler::Hqx(_) => hqx(&src, x, y, n, &patterns, &mut block),
+                    Upscaler::Xbr(_) => blend_corners(&src, x, y, n, &mut block, xbr_corner),
+                }
+
+                for (i, c) in block.iter().enumerate() {
+                    let (bx, by) = (i as u32 % n, i as u32 / n);
+                    out.put_pixel(x as u32*n + bx, y as u32*n + by, image::Rgba(*c));
+                }
+            }
+        }
+    }
+}
+
+// With the neighbours named
+//   A B C
+//   D E F
+//   G H I
+fn scale2x(src: &Source, x: i32, y: i32, block: &mut [Colour]) {
+    let (b, d, e, f, h) = (src.at(x, y - 1), src.at(x - 1, y), src.at(x, y),
+                           src.at(x + 1, y), src.at(x, y + 1));
+    if b != h && d != f {
+        block[0] = if d == b { d } else { e };
+        block[1] = if b == f { f } else { e };
+        block[2] = if d == h { d } else { e };
+        block[3] = if h == f { f } else { e };
+    } else {
+        for c in block.iter_mut() { *c = e }
+    }
+}
+
+fn scale3x(src: &Source, x: i32, y: i32, block: &mut [Colour]) {
+    let (a, b, c) = (src.at(x - 1, y - 1), src.at(x, y - 1), src.at(x + 1, y - 1));
+    let (d, e, f) = (src.at(x - 1, y), src.at(x, y), src.at(x + 1, y));
+    let (g, h, i) = (src.at(x - 1, y + 1), src.at(x, y + 1), src.at(x + 1, y + 1));
+    for p in block.iter_mut() { *p = e }
+    if b == h || d == f {
+        return;
+    }
+
+    if d == b { block[0] = d }
+    if (d == b && e != c) || (b == f && e != a) { block[1] = b }
+    if b == f { block[2] = f }
+    if (d == b && e != g) || (d == h && e != a) { block[3] = d }
+    if (b == f && e != i) || (h == f && e != c) { block[5] = f }
+    if d == h { block[6] = d }
+    if (d == h && e != i) || (h == f && e != g) { block[7] = h }
+    if h == f { block[8] = f }
+}
+
+// Asks rule about each corner of the pixel at x, y, which gives the colour to blend into the
+// corner and how strongly. Subpixels are blended less the further they are from the corner,
+// so that a blended corner is cut off by a diagonal line.
+fn blend_corners(src: &Source, x: i32, y: i32, n: u32, block: &mut [Colour],
+                 rule: fn(&Source, i32, i32, i32, i32) -> Option<(Colour, f32)>) {
+    let e = src.at(x, y);
+    let mut corners = [[None; 2]; 2];
+    for cy in 0..2 {
+        for cx in 0..2 {
+            corners[cy][cx] = rule(src, x, y, cx as i32*2 - 1, cy as i32*2 - 1);
+        }
+    }
+
+    for (i, p) in block.iter_mut().enumerate() {
+        // From -0.5 to 0.5 across the pixel
+        let u = ((i as u32 % n) as f32 + 0.5)/n as f32 - 0.5;
+        let v = ((i as u32 / n) as f32 + 0.5)/n as f32 - 0.5;
+        *p = e;
+        if u == 0.0 || v == 0.0 {
+            continue;
+        }
+
+        let corner = corners[(v > 0.0) as usize][(u > 0.0) as usize];
+        if let Some((colour, strength)) = corner {
+            let coverage = ((u.abs() + v.abs() - 0.25)*2.0).max(0.0).min(1.0);
+            *p = blend(e, colour, coverage*strength);
+        }
+    }
+}
+
+// Compares how much the colour changes along the two diagonals through the corner, using
+// the 4x4 pixels around it. With the corner at I:
+//      A1 B1 C1
+//   A0 A  B  C  C4
+//   D0 D  E  F  F4
+//   G0 G  H  I  I4
+//      G5 H5 I5
+fn xbr_corner(src: &Source, x: i32, y: i32, dx: i32, dy: i32) -> Option<(Colour, f32)> {
+    let p = |i: i32, j: i32| (x + i*dx, y + j*dy);
+    let d = |a: (i32, i32), b: (i32, i32)| src.distance(a, b);
+    let (b, c, dd, e, f, g, h, i) = (p(0, -1), p(1, -1), p(-1, 0), p(0, 0), p(1, 0),
+                                     p(-1, 1), p(0, 1), p(1, 1));
+    let (f4, i4, h5, i5) = (p(2, 0), p(2, 1), p(0, 2), p(1, 2));
+
+    if src.at(e.0, e.1) == src.at(f.0, f.1) || src.at(e.0, e.1) == src.at(h.0, h.1) {
+        return None;
+    }
+    // Along the edge the corner would cut, and across it
+    let along = d(e, c) + d(e, g) + d(i, f4) + d(i, h5) + 4.0*d(h, f);
+    let across = d(h, dd) + d(h, i5) + d(f, i4) + d(f, b) + 4.0*d(e, i);
+    if along < across {
+        let closer = if d(e, f) <= d(e, h) { f } else { h };
+        Some((src.at(closer.0, closer.1), 1.0))
+    } else {
+        None
+    }
+}
+
+// How hqx fills in one corner of a pixel, from which neighbours differ from it. Side a is
+// the neighbour beside the corner and side b the one above or below it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Corner {
+    // Neither side differs
+    Flat,
+    // Only one side differs, a unless swapped. Solid when the diagonal differs too. Line when
+    // an edge across the next corner along that side could carry on past this one, which is
+    // only drawn if the two sides of the next corner look alike.
+    Side { swapped: bool, solid: bool, line: bool },
+    // Both sides differ, and are blended in when they look alike. Steep and shallow are lines
+    // carrying on past the next corner above or below, or beside it.
+    Both { solid: bool, steep: bool, shallow: bool },
+}
+
+// Bit i of a pattern is set when neighbour i differs, in the order of hqx:
+//   0 1 2
+//   3 . 4
+//   5 6 7
+fn neighbour_bit(dx: i32, dy: i32) -> u8 {
+    let i = (dy + 1)*3 + dx + 1;
+    1 << (if i > 4 { i - 1 } else { i })
+}
+
+// The corners are top left, top right, bottom left and bottom right
+const CORNERS: [(i32, i32); 4] = [(-1, -1), (1, -1), (-1, 1), (1, 1)];
+
+fn hqx_pattern(pattern: u8) -> [Corner; 4] {
+    let differs = |dx: i32, dy: i32| pattern & neighbour_bit(dx, dy) != 0;
+    let mut corners = [Corner::Flat; 4];
+    for (corner, &(cx, cy)) in corners.iter_mut().zip(CORNERS.iter()) {
+        let (a, b, solid) = (differs(cx, 0), differs(0, cy), differs(cx, cy));
+        *corner = match (a, b) {
+            (false, false) => Corner::Flat,
+            (true, true) => Corner::Both {
+                solid: solid,
+                steep: differs(cx, -cy) && !differs(0, -cy),
+                shallow: differs(-cx, cy) && !differs(-cx, 0),
+            },
+            _ => Corner::Side {
+                swapped: b,
+                solid: solid,
+                line: solid && if a { differs(0, -cy) } else { differs(-cx, 0) },
+            },
+        };
+    }
+    corners
+}
+
+// Sixteenths of the pixel, the diagonal, side a and side b in each subpixel of a corner.
+// For 2x only the corner itself, for 3x also the ones below it and beside it, which 3x shares
+// with the next corners, and for 4x also the one diagonally inwards.
+type Weights = [[u8; 4]; 4];
+
+static HQ2X: [Weights; 8] = [
+    [[8, 0, 4, 4], [0; 4], [0; 4], [0; 4]],
+    [[12, 0, 0, 4], [0; 4], [0; 4], [0; 4]],
+    [[8, 4, 0, 4], [0; 4], [0; 4], [0; 4]],
+    [[10, 0, 4, 2], [0; 4], [0; 4], [0; 4]],
+    [[16, 0, 0, 0], [0; 4], [0; 4], [0; 4]],
+    [[12, 4, 0, 0], [0; 4], [0; 4], [0; 4]],
+    [[8, 0, 4, 4], [0; 4], [0; 4], [0; 4]],
+    [[4, 0, 6, 6], [0; 4], [0; 4], [0; 4]],
+];
+
+static HQ3X: [Weights; 8] = [
+    [[8, 0, 4, 4], [12, 0, 4, 0], [12, 0, 0, 4], [0; 4]],
+    [[12, 0, 0, 4], [16, 0, 0, 0], [12, 0, 0, 4], [0; 4]],
+    [[12, 4, 0, 0], [16, 0, 0, 0], [12, 0, 0, 4], [0; 4]],
+    [[12, 0, 4, 0], [4, 0, 12, 0], [16, 0, 0, 0], [0; 4]],
+    [[16, 0, 0, 0], [16, 0, 0, 0], [16, 0, 0, 0], [0; 4]],
+    [[12, 4, 0, 0], [16, 0, 0, 0], [16, 0, 0, 0], [0; 4]],
+    [[2, 0, 7, 7], [14, 0, 2, 0], [14, 0, 0, 2], [0; 4]],
+    [[0, 0, 8, 8], [4, 0, 12, 0], [14, 0, 0, 2], [0; 4]],
+];
+
+static HQ4X: [Weights; 8] = [
+    [[8, 0, 4, 4], [10, 0, 4, 2], [10, 0, 2, 4], [12, 0, 2, 2]],
+    [[12, 0, 0, 4], [16, 0, 0, 0], [12, 0, 0, 4], [16, 0, 0, 0]],
+    [[10, 6, 0, 0], [14, 2, 0, 0], [12, 0, 0, 4], [16, 0, 0, 0]],
+    [[12, 0, 4, 0], [4, 0, 12, 0], [16, 0, 0, 0], [16, 0, 0, 0]],
+    [[16, 0, 0, 0], [16, 0, 0, 0], [16, 0, 0, 0], [16, 0, 0, 0]],
+    [[10, 6, 0, 0], [14, 2, 0, 0], [14, 2, 0, 0], [16, 0, 0, 0]],
+    [[0, 0, 8, 8], [8, 0, 8, 0], [8, 0, 0, 8], [16, 0, 0, 0]],
+    [[0, 0, 8, 8], [4, 0, 12, 0], [12, 0, 0, 4], [14, 0, 2, 0]],
+];
+
+// Indices into the tables above
+const FLAT: usize = 0;
+const SIDE_SOLID: usize = 1;
+const SIDE_OPEN: usize = 2;
+const SIDE_LINE: usize = 3;
+const INNER_SOLID: usize = 4;
+const INNER_OPEN: usize = 5;
+const EDGE: usize = 6;
+const EDGE_LINE: usize = 7;
+
+// Side a and side b trade places, and so do the subpixels below and beside the corner
+fn swap(w: Weights) -> Weights {
+    let t = |w: [u8; 4]| [w[0], w[1], w[3], w[2]];
+    [t(w[0]), t(w[2]), t(w[1]), t(w[3])]
+}
+
+fn hqx(src: &Source, x: i32, y: i32, n: u32, patterns: &[[Corner; 4]], block: &mut [Colour]) {
+    let mut pattern = 0;
+    for dy in -1..2 {
+        for dx in -1..2 {
+            if (dx != 0 || dy != 0) && src.differ((x, y), (x + dx, y + dy)) {
+                pattern |= neighbour_bit(dx, dy);
+            }
+        }
+    }
+
+    let tables = match n { 2 => &HQ2X, 3 => &HQ3X, _ => &HQ4X };
+    let e = src.at(x, y);
+    // Sixteenths of the pixel in each subpixel. Where 3x corners share one, the more blended
+    // one is kept.
+    let mut kept = vec![16; (n*n) as usize];
+    for p in block.iter_mut() { *p = e }
+    for (corner, &(cx, cy)) in patterns[pattern as usize].iter().zip(CORNERS.iter()) {
+        let (a, b, diagonal) = ((x + cx, y), (x, y + cy), (x + cx, y + cy));
+        let weights = match *corner {
+            Corner::Flat => tables[FLAT],
+            Corner::Side { swapped, solid, line } => {
+                // The side that differs, and the far side of the next corner along it
+                let (side, far) = if swapped { (b, (x - cx, y)) } else { (a, (x, y - cy)) };
+                let w = if line && !src.differ(side, far) {
+                    tables[SIDE_LINE]
+                } else if solid {
+                    tables[SIDE_SOLID]
+                } else {
+                    tables[SIDE_OPEN]
+                };
+                if swapped { swap(w) } else { w }
+            },
+            Corner::Both { solid, steep, shallow } => {
+                if src.differ(a, b) {
+                    tables[if solid { INNER_SOLID } else { INNER_OPEN }]
+                } else {
+                    let w = tables[EDGE_LINE];
+                    match (steep, shallow) {
+                        (false, false) => tables[EDGE],
+                        (true, false) => w,
+                        (false, true) => swap(w),
+                        (true, true) => {
+                            // Each subpixel takes the more blended of the two lines
+                            let mut both = w;
+                            for (b, s) in both.iter_mut().zip(swap(w).iter()) {
+                                if s[0] < b[0] { *b = *s }
+                            }
+                            both
+                        }
+                    }
+                }
+            },
+        };
+
+        let colours = [e, src.at(diagonal.0, diagonal.1), src.at(a.0, a.1), src.at(b.0, b.1)];
+        let subpixels = match n { 2 => 1, 3 => 3, _ => 4 };
+        for (i, w) in weights.iter().enumerate().take(subpixels) {
+            // Down from the corner, then across it
+            let (sx, sy) = ((i as u32)/2, (i as u32)%2);
+            let bx = if cx < 0 { sx } else { n - 1 - sx };
+            let by = if cy < 0 { sy } else { n - 1 - sy };
+            let index = (by*n + bx) as usize;
+            if w[0] >= kept[index] {
+                continue;
+            }
+            kept[index] = w[0];
+            let mut c = [0, 0, 0, 0xFF];
+            for channel in 0..3 {
+                let sum: u32 = (0..4).map(|k| w[k] as u32*colours[k][channel] as u32).sum();
+                c[channel] = ((sum + 8)/16) as u8;
+            }
+            block[index] = c;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image;
+    use ppu::make_canvas;
+    use super::*;
+
+    #[test]
+    fn upscalers_smooth_diagonals_and_keep_flat_areas() {
+        // A white triangle below the diagonal, on black
+        let mut image = make_canvas(8, 8);
+        for (x, y, p) in image.enumerate_pixels_mut() {
+            *p = image::Rgba(if x <= y { [0xFF, 0xFF, 0xFF, 0xFF] } else { [0, 0, 0, 0xFF] });
+        }
+
+        let mut out = make_canvas(1, 1);
+        Upscaler::Scale2x.apply(&image, &mut out);
+        assert_eq!((out.width(), out.height()), (16, 16));
+        // The top right of the white pixel on the diagonal is filled in with black
+        assert_eq!(out.get_pixel(9, 8).0, [0, 0, 0, 0xFF]);
+        assert_eq!(out.get_pixel(8, 9).0, [0xFF, 0xFF, 0xFF, 0xFF]);
+
+        for &upscaler in [Upscaler::Scale3x, Upscaler::Hqx(2), Upscaler::Hqx(3), Upscaler::Hqx(4),
+                          Upscaler::Xbr(2), Upscaler::Xbr(3)].iter() {
+            upscaler.apply(&image, &mut out);
+            let n = upscaler.factor();
+            assert_eq!(out.width(), 8*n);
+            // Away from the edge nothing changes
+            assert_eq!(out.get_pixel(0, 7*n).0, [0xFF, 0xFF, 0xFF, 0xFF], "{:?}", upscaler);
+            assert_eq!(out.get_pixel(7*n, 0).0, [0, 0, 0, 0xFF], "{:?}", upscaler);
+            // The corner of a pixel on the diagonal that sticks out is cut off
+            let corner = out.get_pixel(4*n + n - 1, 4*n).0;
+            assert!(corner[0] < 0xFF, "{:?} left {:?}", upscaler, corner);
+        }
+    }
+
+    #[test]
+    fn hqx_draws_lines_through_corners() {
+        assert_eq!(hqx_pattern(0), [Corner::Flat; 4]);
+        // Above and to the right differ and look alike, and so does the top left, so the edge
+        // across the top right corner carries on over the top left one
+        let grey = [0x80, 0x80, 0x80, 0xFF];
+        let mut image = make_canvas(3, 3);
+        for (x, y, p) in image.enumerate_pixels_mut() {
+            let black = (x, y) == (0, 0) || (x, y) == (1, 0) || (x, y) == (2, 1);
+            *p = image::Rgba(if black { [0, 0, 0, 0xFF] } else { grey });
+        }
+
+        let mut out = make_canvas(1, 1);
+        Upscaler::Hqx(2).apply(&image, &mut out);
+        // What hq2x.c gives for this pattern, 19: (5*E + 2*B + D)/8 and (2*E + 3*B + 3*F)/8
+        assert_eq!(out.get_pixel(2, 2).0, [0x60, 0x60, 0x60, 0xFF]);
+        assert_eq!(out.get_pixel(3, 2).0, [0x20, 0x20, 0x20, 0xFF]);
+        assert_eq!((out.get_pixel(2, 3).0, out.get_pixel(3, 3).0), (grey, grey));
+    }
+}